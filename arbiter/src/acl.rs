@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use uuid::Uuid;
 
 #[derive(Default, Deserialize)]
@@ -7,12 +11,90 @@ pub struct AclDatabase {
     pub entries: Vec<AclEntry>,
 }
 
+impl AclDatabase {
+    // Everything the active entries covering both the controller and the device allow, taken
+    // together. None if no entry covers them.
+    pub fn grant(&self, controller: &Uuid, device: &Uuid, timestamp: u64) -> Option<AclGrant> {
+        let mut grant: Option<AclGrant> = None;
+        for (index, entry) in self.entries.iter().enumerate().filter(|(_, entry)| {
+            entry.is_active_at(timestamp)
+                && entry.controller_cids.contains(controller)
+                && entry.device_cids.contains(device)
+        }) {
+            let grant = grant.get_or_insert_with(AclGrant::default);
+            grant.entries.push(index);
+            grant.read.extend(entry.parameters.read.iter().cloned());
+            grant.write.extend(entry.parameters.write.iter().cloned());
+            // Earlier entries win if two set the same claim
+            for (name, value) in &entry.extra_claims {
+                grant
+                    .extra_claims
+                    .entry(name.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+        grant
+    }
+}
+
+#[derive(Default)]
+pub struct AclGrant {
+    // Indices of the entries it came from
+    pub entries: Vec<usize>,
+    pub read: BTreeSet<String>,
+    pub write: BTreeSet<String>,
+    pub extra_claims: Map<String, Value>,
+}
+
+// A token request the ACL doesn't allow, as opposed to a malformed one. Recorded in the audit
+// log as is.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AclDenied {
+    pub device: Uuid,
+    // Indices of the entries that covered the device; empty if none did
+    pub acl_entries: Vec<usize>,
+    pub denied_read: Vec<String>,
+    pub denied_write: Vec<String>,
+}
+
+impl Display for AclDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.acl_entries.is_empty() {
+            write!(f, "No ACL entry covers device {}", self.device)
+        } else {
+            write!(
+                f,
+                "Not allowed by the ACL on device {}: read {:?}, write {:?}",
+                self.device, self.denied_read, self.denied_write
+            )
+        }
+    }
+}
+
+impl std::error::Error for AclDenied {}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AclEntry {
     pub controller_cids: Vec<Uuid>,
     pub device_cids: Vec<Uuid>,
     pub parameters: AclParameters,
+    // Seconds since the Unix epoch
+    #[serde(default)]
+    pub valid_from: Option<u64>,
+    #[serde(default)]
+    pub valid_until: Option<u64>,
+    // Added to every token issued under this entry, e.g. a "role" for the device to act on
+    #[serde(default)]
+    pub extra_claims: Map<String, Value>,
+}
+
+impl AclEntry {
+    pub fn is_active_at(&self, timestamp: u64) -> bool {
+        self.valid_from.is_none_or(|from| timestamp >= from)
+            && self.valid_until.is_none_or(|until| timestamp < until)
+    }
 }
 
 #[derive(Deserialize)]
@@ -21,3 +103,97 @@ pub struct AclParameters {
     pub read: Vec<String>,
     pub write: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const CONTROLLER: Uuid = Uuid::from_u128(0xc1);
+    const DEVICE: Uuid = Uuid::from_u128(0xd1);
+
+    fn entry(valid_from: Option<u64>, valid_until: Option<u64>) -> AclEntry {
+        serde_json::from_value(json!({
+            "controllerCids": [CONTROLLER],
+            "deviceCids": [DEVICE],
+            "parameters": { "read": ["temp"], "write": [] },
+            "validFrom": valid_from,
+            "validUntil": valid_until,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn window_includes_its_start_and_excludes_its_end() {
+        let window = entry(Some(100), Some(200));
+        assert!(!window.is_active_at(99));
+        assert!(window.is_active_at(100));
+        assert!(window.is_active_at(199));
+        assert!(!window.is_active_at(200));
+    }
+
+    #[test]
+    fn missing_bounds_leave_the_window_open() {
+        assert!(entry(None, None).is_active_at(0));
+        assert!(entry(None, None).is_active_at(u64::MAX));
+        assert!(entry(Some(100), None).is_active_at(u64::MAX));
+        assert!(!entry(Some(100), None).is_active_at(99));
+        assert!(entry(None, Some(200)).is_active_at(0));
+        assert!(!entry(None, Some(200)).is_active_at(200));
+    }
+
+    #[test]
+    fn entries_outside_their_window_grant_nothing() {
+        let acl = AclDatabase {
+            entries: vec![entry(Some(100), Some(200))],
+        };
+        assert!(acl.grant(&CONTROLLER, &DEVICE, 150).is_some());
+        assert!(acl.grant(&CONTROLLER, &DEVICE, 50).is_none());
+        assert!(acl.grant(&CONTROLLER, &DEVICE, 250).is_none());
+    }
+
+    #[test]
+    fn grant_is_the_union_of_all_matching_entries() {
+        let acl: AclDatabase = serde_json::from_value(json!({
+            "entries": [
+                {
+                    "controllerCids": [CONTROLLER],
+                    "deviceCids": [DEVICE],
+                    "parameters": { "read": ["temp"], "write": [] },
+                    "extraClaims": { "role": "operator" },
+                },
+                {
+                    "controllerCids": [Uuid::from_u128(0xc2)],
+                    "deviceCids": [DEVICE],
+                    "parameters": { "read": ["secret"], "write": ["secret"] },
+                },
+                {
+                    "controllerCids": [CONTROLLER],
+                    "deviceCids": [DEVICE, Uuid::from_u128(0xd2)],
+                    "parameters": { "read": ["mode"], "write": ["mode"] },
+                    "extraClaims": { "role": "admin" },
+                },
+            ],
+        }))
+        .unwrap();
+
+        let grant = acl.grant(&CONTROLLER, &DEVICE, 0).unwrap();
+        assert_eq!(grant.entries, vec![0, 2]);
+        assert_eq!(
+            grant.read,
+            BTreeSet::from(["mode".to_string(), "temp".to_string()])
+        );
+        assert_eq!(grant.write, BTreeSet::from(["mode".to_string()]));
+        assert_eq!(grant.extra_claims["role"], "operator");
+    }
+
+    #[test]
+    fn no_grant_without_an_entry_covering_both() {
+        let acl = AclDatabase {
+            entries: vec![entry(None, None)],
+        };
+        assert!(acl.grant(&Uuid::from_u128(0xc2), &DEVICE, 0).is_none());
+        assert!(acl.grant(&CONTROLLER, &Uuid::from_u128(0xd2), 0).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time,
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{acl::AclDenied, config::AuditLogConfig};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum AuditEvent<'a> {
+    #[serde(rename_all = "camelCase")]
+    DeviceRegistered { cid: Uuid, port: u16 },
+    #[serde(rename_all = "camelCase")]
+    DeviceDeregistered { cid: Uuid },
+    #[serde(rename_all = "camelCase")]
+    TokenIssued {
+        controller: Uuid,
+        subject: &'a str,
+        device: Uuid,
+        jti: Uuid,
+        params_read: &'a [String],
+        params_write: &'a [String],
+        // The ACL entries that granted the token, and what they left out of it
+        acl_entries: &'a [usize],
+        denied_read: &'a [String],
+        denied_write: &'a [String],
+    },
+    #[serde(rename_all = "camelCase")]
+    TokenDenied {
+        controller: Uuid,
+        reason: String,
+        // Present if it was the ACL that refused
+        #[serde(flatten)]
+        acl_decision: Option<&'a AclDenied>,
+    },
+    #[serde(rename_all = "camelCase")]
+    TokenRevoked { jti: Uuid, by: Uuid },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+// Append-only JSON lines log of security-relevant events. The current file is rotated to
+// `<file>.1` (shifting older archives up) when it would exceed `maxBytes` or when the UTC day
+// changes, keeping at most `maxArchives` archives.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    daily: bool,
+    max_archives: usize,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditLogConfig) -> std::io::Result<Self> {
+        let path = PathBuf::from(&config.file);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_bytes: config.max_bytes,
+            daily: config.daily,
+            max_archives: config.max_archives,
+            file,
+            size,
+            day: now() / SECONDS_PER_DAY,
+        })
+    }
+
+    pub fn record(&mut self, event: AuditEvent) {
+        let timestamp = now();
+        let mut line = serde_json::to_vec(&AuditRecord { timestamp, event }).unwrap();
+        line.push(b'\n');
+
+        if let Err(e) = self.write_line(&line, timestamp) {
+            println!("Error writing audit log: {e}");
+        }
+    }
+
+    fn write_line(&mut self, line: &[u8], timestamp: u64) -> std::io::Result<()> {
+        let day = timestamp / SECONDS_PER_DAY;
+        let size_exceeded = self
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        let day_changed = self.daily && day != self.day;
+
+        if size_exceeded || day_changed {
+            self.rotate()?;
+            self.day = day;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_archives == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.archive_path(self.max_archives));
+            for index in (1..self.max_archives).rev() {
+                let archive = self.archive_path(index);
+                if archive.exists() {
+                    std::fs::rename(&archive, self.archive_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.archive_path(1))?;
+            self.file = open_append(&self.path)?;
+        }
+
+        self.size = 0;
+        Ok(())
+    }
+
+    fn archive_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+fn open_append(path: &PathBuf) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &std::path::Path, max_bytes: Option<u64>, daily: bool) -> AuditLog {
+        AuditLog::open(&AuditLogConfig {
+            file: dir.join("audit.log").to_str().unwrap().to_string(),
+            max_bytes,
+            daily,
+            max_archives: 2,
+        })
+        .unwrap()
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arbiter-audit-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn log_is_rotated_when_it_would_exceed_max_bytes() {
+        let dir = temp_dir();
+        let mut log = open(&dir, Some(10), false);
+        let now = now();
+        log.write_line(b"first\n", now).unwrap();
+        log.write_line(b"second\n", now).unwrap();
+        log.write_line(b"third\n", now).unwrap();
+
+        assert_eq!(read(dir.join("audit.log")), "third\n");
+        assert_eq!(read(dir.join("audit.log.1")), "second\n");
+        assert_eq!(read(dir.join("audit.log.2")), "first\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn daily_log_is_rotated_when_the_day_changes() {
+        let dir = temp_dir();
+        let mut log = open(&dir, None, true);
+        let now = now();
+        log.write_line(b"today\n", now).unwrap();
+        log.write_line(b"still today\n", now).unwrap();
+        log.write_line(b"tomorrow\n", now + SECONDS_PER_DAY)
+            .unwrap();
+
+        assert_eq!(read(dir.join("audit.log")), "tomorrow\n");
+        assert_eq!(read(dir.join("audit.log.1")), "today\nstill today\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use std::io::Write;
+use std::net::SocketAddr;
+
+use coap_lite::{CoapOption, CoapRequest};
+use flate2::{write::DeflateEncoder, Compression};
+
+// There is no registered Content-Encoding option for CoAP, so we use one from the experimental
+// range. It is elective, so peers that don't understand it will just get uncompressed payloads.
+pub const CONTENT_ENCODING_OPTION: CoapOption = CoapOption::Unknown(65000);
+pub const DEFLATE: &[u8] = b"deflate";
+
+// Compresses the response payload if the client advertised deflate support and the payload is
+// at least `threshold` bytes.
+pub fn compress_response_if_accepted(request: &mut CoapRequest<SocketAddr>, threshold: usize) {
+    let accepts_deflate = request
+        .message
+        .get_option(CONTENT_ENCODING_OPTION)
+        .is_some_and(|values| values.iter().any(|value| value == DEFLATE));
+    if !accepts_deflate {
+        return;
+    }
+
+    let Some(ref mut response) = request.response else {
+        return;
+    };
+    if response.message.payload.len() < threshold {
+        return;
+    }
+
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(&response.message.payload).unwrap();
+    response.message.payload = encoder.finish().unwrap();
+    response
+        .message
+        .add_option(CONTENT_ENCODING_OPTION, DEFLATE.to_vec());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use coap_lite::CoapResponse;
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    fn request(accepts_deflate: bool, payload: &[u8]) -> CoapRequest<SocketAddr> {
+        let mut request = CoapRequest::new();
+        if accepts_deflate {
+            request
+                .message
+                .add_option(CONTENT_ENCODING_OPTION, DEFLATE.to_vec());
+        }
+        request.response = CoapResponse::new(&request.message);
+        request.response.as_mut().unwrap().message.payload = payload.to_vec();
+        request
+    }
+
+    #[test]
+    fn compressed_payload_inflates_to_the_original() {
+        let payload = br#"{"devices":[]}"#.repeat(20);
+        let mut request = request(true, &payload);
+        compress_response_if_accepted(&mut request, 64);
+
+        let message = &request.response.unwrap().message;
+        assert!(message
+            .get_option(CONTENT_ENCODING_OPTION)
+            .is_some_and(|values| values.iter().any(|value| value == DEFLATE)));
+        assert!(message.payload.len() < payload.len());
+        let mut inflated = vec![];
+        DeflateDecoder::new(message.payload.as_slice())
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, payload);
+    }
+
+    #[test]
+    fn payload_is_left_alone_if_small_or_not_accepted() {
+        let payload = br#"{"devices":[]}"#.repeat(20);
+        for (accepts_deflate, threshold) in [(true, payload.len() + 1), (false, 0)] {
+            let mut request = request(accepts_deflate, &payload);
+            compress_response_if_accepted(&mut request, threshold);
+            let message = &request.response.unwrap().message;
+            assert_eq!(message.payload, payload);
+            assert!(message.get_option(CONTENT_ENCODING_OPTION).is_none());
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use log::LevelFilter;
 use serde::Deserialize;
 use uuid::Uuid;
@@ -8,6 +10,11 @@ use crate::acl::AclDatabase;
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub cid: Uuid,
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: String,
+    // Further addresses to serve on alongside bindAddr, e.g. "[::1]:5683" to also accept IPv6
+    #[serde(default)]
+    pub extra_bind_addrs: Vec<String>,
     #[serde(default = "default_root_ca")]
     pub root_ca_file: String,
     #[serde(default = "default_cert_file")]
@@ -18,6 +25,125 @@ pub struct Config {
     pub log_level: LevelFilter,
     #[serde(default)]
     pub acl: AclDatabase,
+    // Size of each of the high and low priority request queues
+    #[serde(default = "default_request_queue_size")]
+    pub request_queue_size: usize,
+    #[serde(default)]
+    pub request_queue_policy: QueuePolicy,
+    // How often expired devices and stale per-controller state are cleaned up
+    #[serde(default = "default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: usize,
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+    #[serde(default)]
+    pub device_ca: Option<DeviceCaConfig>,
+    // Subject to put in tokens issued to a controller instead of its CID, e.g. an email address
+    #[serde(default)]
+    pub controller_subjects: HashMap<Uuid, String>,
+    // JSON array of devices to pre-register at startup, in the same format as GET /devices
+    #[serde(default)]
+    pub seed_devices_file: Option<String>,
+    // If set, only devices with these CIDs may register
+    #[serde(default)]
+    pub provisioned_devices: Option<Vec<Uuid>>,
+    // If set, devices may re-register without the key from their last registration, and after a
+    // port change the old port is still reported for this long
+    #[serde(default)]
+    pub port_change_grace_secs: Option<u64>,
+    // Serve GET /devices/{cid}/params/{param} by reading the parameter from the device on the
+    // controller's behalf
+    #[serde(default)]
+    pub proxy_reads: bool,
+    // Sign GET /devices payloads with the token signing key, so that controllers can check the
+    // integrity of device lists they cached
+    #[serde(default)]
+    pub sign_device_lists: bool,
+    // Print a JSON line to stdout whenever a device registers, deregisters or expires, alongside
+    // the usual log output
+    #[serde(default)]
+    pub emit_fleet_events: bool,
+    // Requires the `webhooks` feature
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    // Limits control token requests per controller; unlimited if unset
+    #[serde(default)]
+    pub token_rate_limit: Option<RateLimitConfig>,
+    // Issue tokens for just the parameters the ACL allows, instead of refusing requests that ask
+    // for more
+    #[serde(default)]
+    pub narrow_token_scopes: bool,
+    // Most devices a single token request may ask for; unlimited if unset
+    #[serde(default)]
+    pub max_devices_per_token_request: Option<usize>,
+    // Lifetime of issued tokens. Controllers may ask for shorter-lived tokens, but not longer.
+    #[serde(default = "default_max_token_lifetime_secs")]
+    pub max_token_lifetime_secs: u64,
+    // Address tokens to the device's registered label instead of its CID. Devices must be
+    // configured to match, and labels should be unique, since a token is accepted by any device
+    // with the label in its audience.
+    #[serde(default)]
+    pub label_audience: bool,
+    // Limits registrations per source IP address; unlimited if unset. Devices all connect from
+    // the same host in this setup, so the limit has to leave room for all of them.
+    #[serde(default)]
+    pub registration_rate_limit: Option<RateLimitConfig>,
+    // Token requests (including proxied reads) a controller may have awaiting a response at once;
+    // more are refused with 5.03
+    #[serde(default)]
+    pub max_in_flight_token_requests: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default = "default_webhook_max_retries")]
+    pub max_retries: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    pub max_requests: u32,
+    pub window_secs: u64,
+}
+
+// When set, devices must register on a separate listener that only accepts client certificates
+// chaining to this CA, and registrations on the main listener are refused.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCaConfig {
+    pub ca_file: String,
+    pub listen_addr: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLogConfig {
+    pub file: String,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub daily: bool,
+    #[serde(default = "default_audit_log_max_archives")]
+    pub max_archives: usize,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QueuePolicy {
+    // Wait for room in the queue
+    #[default]
+    Block,
+    // Reject the request with 5.03 Service Unavailable if the queue is full
+    Shed,
+}
+
+fn default_bind_addr() -> String {
+    "127.0.0.1:5683".to_string()
 }
 
 fn default_root_ca() -> String {
@@ -32,6 +158,30 @@ fn default_key_file() -> String {
     "../certs/arbiter-key.pem".to_string()
 }
 
+fn default_request_queue_size() -> usize {
+    1000
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    30
+}
+
+fn default_compression_threshold() -> usize {
+    512
+}
+
+fn default_audit_log_max_archives() -> usize {
+    5
+}
+
+fn default_webhook_max_retries() -> u32 {
+    3
+}
+
+fn default_max_token_lifetime_secs() -> u64 {
+    6000
+}
+
 fn default_log_filter() -> LevelFilter {
     LevelFilter::Off
 }
@@ -0,0 +1,54 @@
+use std::fmt::Display;
+
+// Startup failures, each mapped to a distinct process exit code so that supervisors can tell
+// them apart.
+#[derive(Debug)]
+pub enum StartupError {
+    Config(anyhow::Error),
+    Certificate(anyhow::Error),
+    Bind(anyhow::Error),
+    Server(anyhow::Error),
+    AuditLog(anyhow::Error),
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Certificate(_) => 3,
+            Self::Bind(_) => 4,
+            Self::Server(_) => 5,
+            Self::AuditLog(_) => 6,
+        }
+    }
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "Configuration error: {e:#}"),
+            Self::Certificate(e) => write!(f, "Certificate error: {e:#}"),
+            Self::Bind(e) => write!(f, "Couldn't bind listener: {e:#}"),
+            Self::Server(e) => write!(f, "Server error: {e:#}"),
+            Self::AuditLog(e) => write!(f, "Couldn't open audit log: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_startup_error_has_its_own_exit_code() {
+        let errors = [
+            StartupError::Config(anyhow::anyhow!("")),
+            StartupError::Certificate(anyhow::anyhow!("")),
+            StartupError::Bind(anyhow::anyhow!("")),
+            StartupError::Server(anyhow::anyhow!("")),
+            StartupError::AuditLog(anyhow::anyhow!("")),
+        ];
+        let codes: Vec<_> = errors.iter().map(StartupError::exit_code).collect();
+        assert_eq!(codes, vec![2, 3, 4, 5, 6]);
+    }
+}
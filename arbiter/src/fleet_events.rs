@@ -0,0 +1,49 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+// Written to stdout one JSON object per line, so that scripts can follow devices joining and
+// leaving by picking out the lines that start with `{`
+#[derive(Serialize)]
+#[serde(tag = "fleetEvent", rename_all = "camelCase")]
+pub enum FleetEvent<'a> {
+    #[serde(rename_all = "camelCase")]
+    Registered {
+        cid: Uuid,
+        label: &'a str,
+        port: u16,
+        tags: &'a [String],
+    },
+    #[serde(rename_all = "camelCase")]
+    Deregistered { cid: Uuid, label: &'a str },
+    #[serde(rename_all = "camelCase")]
+    Expired { cid: Uuid, label: &'a str },
+}
+
+pub fn emit(event: &FleetEvent) {
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{line}"),
+        Err(e) => println!("Couldn't serialize fleet event: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registration_is_one_json_line() {
+        let tags = ["hvac".to_string()];
+        // What emit prints
+        let line = serde_json::to_string(&FleetEvent::Registered {
+            cid: Uuid::from_u128(1),
+            label: "thermostat",
+            port: 47111,
+            tags: &tags,
+        })
+        .unwrap();
+        assert_eq!(
+            line,
+            r#"{"fleetEvent":"registered","cid":"00000000-0000-0000-0000-000000000001","label":"thermostat","port":47111,"tags":["hvac"]}"#
+        );
+    }
+}
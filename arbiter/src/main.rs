@@ -1,84 +1,349 @@
-use std::{fs::File, io::BufReader};
+use std::{fs::File, io::BufReader, net::SocketAddr, process::ExitCode};
 
+use anyhow::Context;
+use coap::server::Listener;
 use coap::Server;
 use rcgen::KeyPair;
 use rustls::{Certificate as RustlsCertificate, RootCertStore};
-use tokio::sync::mpsc::channel;
+use tokio::task::JoinSet;
 use webrtc_dtls::{
     config::{ClientAuthType, Config as DtlsConfig},
     crypto::{Certificate, CryptoPrivateKey},
     listener::listen,
 };
+use webrtc_util::conn::Listener as _;
 
-use self::{config::Config, request_handler::RequestHandler, state::run_state_loop};
+use self::{
+    audit::AuditLog,
+    config::Config,
+    error::StartupError,
+    queue::request_queue,
+    request_handler::{Endpoint, RequestHandler},
+    state::run_state_loop,
+    webhook::Notifier,
+};
 
 mod acl;
+mod audit;
+mod compression;
 mod config;
+mod error;
+mod fleet_events;
+mod queue;
+mod rate_limit;
 mod request;
 mod request_handler;
 mod state;
+mod webhook;
 
 #[tokio::main]
-async fn main() {
-    let config = std::fs::read_to_string("config.json").expect("No config file provided");
-    let config: Config = serde_json::from_str(&config).expect("Invalid config");
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn run() -> Result<(), StartupError> {
+    let config = std::fs::read_to_string("config.json")
+        .context("No config file provided")
+        .map_err(StartupError::Config)?;
+    let mut config: serde_json::Value = serde_json::from_str(&config)
+        .context("Invalid config")
+        .map_err(StartupError::Config)?;
+    env_overrides::apply_env_overrides(&mut config, "ARBITER_");
+    let config: Config = serde_json::from_value(config)
+        .context("Invalid config")
+        .map_err(StartupError::Config)?;
 
     env_logger::Builder::new()
         .filter_level(config.log_level)
         .init();
 
-    let addr = "127.0.0.1:5683";
+    let addrs = std::iter::once(&config.bind_addr)
+        .chain(&config.extra_bind_addrs)
+        .map(|addr| parse_listen_addr(addr))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(StartupError::Config)?;
 
-    let client_cas = get_root_cert_store(&config.root_ca_file);
-    let (certificates, priv_key) = get_my_certs(&config.cert_file, &config.key_file);
+    let root_cas = get_root_cert_store(&config.root_ca_file).map_err(StartupError::Certificate)?;
+    let (certificates, priv_key) =
+        get_my_certs(&config.cert_file, &config.key_file).map_err(StartupError::Certificate)?;
 
-    let dtls_config = DtlsConfig {
-        certificates,
-        client_auth: ClientAuthType::RequireAndVerifyClientCert,
-        client_cas,
-        server_name: "arbiter.local".into(),
+    let proxy_dtls_config = config.proxy_reads.then(|| DtlsConfig {
+        certificates: certificates.clone(),
+        roots_cas: root_cas.clone(),
         ..Default::default()
-    };
+    });
+
+    let mut endpoints = vec![];
+    match config.device_ca {
+        None => endpoints.push((addrs, root_cas, Endpoint::Combined)),
+        Some(ref device_ca) => {
+            let device_cas =
+                get_root_cert_store(&device_ca.ca_file).map_err(StartupError::Certificate)?;
+            endpoints.push((addrs, root_cas, Endpoint::Controller));
+            let device_addr =
+                parse_listen_addr(&device_ca.listen_addr).map_err(StartupError::Config)?;
+            endpoints.push((vec![device_addr], device_cas, Endpoint::Device));
+        }
+    }
+
+    let audit_log = config
+        .audit_log
+        .as_ref()
+        .map(|audit_config| {
+            AuditLog::open(audit_config)
+                .with_context(|| format!("Couldn't open {}", audit_config.file))
+        })
+        .transpose()
+        .map_err(StartupError::AuditLog)?;
+
+    let notifier = Notifier::new(config.webhook.as_ref())
+        .context("Invalid webhook")
+        .map_err(StartupError::Config)?;
+
+    let (tx, rx) = request_queue(config.request_queue_size);
 
-    let (tx, rx) = channel(1000);
+    let mut servers = JoinSet::new();
+    for (addrs, client_cas, endpoint) in endpoints {
+        let dtls_config = endpoint_dtls_config(certificates.clone(), client_cas);
 
-    let listener = listen(addr, dtls_config).await.unwrap();
-    let listener = Box::new(listener);
-    let server = Server::from_listeners(vec![listener]);
-    println!("Server up on {addr}");
+        // One server per endpoint, listening on each of its addresses
+        let mut listeners = vec![];
+        for (listener, addr) in bind_listeners(&addrs, &dtls_config).await? {
+            listeners.push(listener);
+            println!("Server up on {addr} ({endpoint:?} endpoint)");
+        }
+        let server = Server::from_listeners(listeners);
+
+        let handler = RequestHandler::new(tx.clone(), endpoint, &config, proxy_dtls_config.clone());
+        servers.spawn(async move { server.run(handler).await });
+    }
+    drop(tx);
 
     let state_handle =
-        tokio::spawn(async move { run_state_loop(rx, config.acl, priv_key, config.cid).await });
+        tokio::spawn(
+            async move { run_state_loop(rx, config, priv_key, audit_log, notifier).await },
+        );
+
+    while let Some(result) = servers.join_next().await {
+        result
+            .context("CoAP server panicked")
+            .map_err(StartupError::Server)?
+            .context("CoAP server failed")
+            .map_err(StartupError::Server)?;
+    }
 
-    server.run(RequestHandler::new(tx)).await.unwrap();
+    state_handle
+        .await
+        .context("State loop panicked")
+        .map_err(StartupError::Server)
+}
+
+// Peers have to present a certificate that chains to one of client_cas
+fn endpoint_dtls_config(certificates: Vec<Certificate>, client_cas: RootCertStore) -> DtlsConfig {
+    DtlsConfig {
+        certificates,
+        client_auth: ClientAuthType::RequireAndVerifyClientCert,
+        client_cas,
+        server_name: "arbiter.local".into(),
+        ..Default::default()
+    }
+}
 
-    state_handle.await.unwrap();
+// Each listener along with the address it is bound to, which differs from the one asked for if
+// that had port 0
+async fn bind_listeners(
+    addrs: &[SocketAddr],
+    dtls_config: &DtlsConfig,
+) -> Result<Vec<(Box<dyn Listener>, SocketAddr)>, StartupError> {
+    let mut listeners: Vec<(Box<dyn Listener>, SocketAddr)> = vec![];
+    for addr in addrs {
+        let listener = listen(*addr, dtls_config.clone())
+            .await
+            .with_context(|| format!("Couldn't listen on {addr}"))
+            .map_err(StartupError::Bind)?;
+        let local_addr = listener.addr().await.unwrap_or(*addr);
+        listeners.push((Box::new(listener), local_addr));
+    }
+    Ok(listeners)
 }
 
-fn get_root_cert_store(cert_file: &str) -> RootCertStore {
+fn parse_listen_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    addr.parse()
+        .with_context(|| format!("Invalid listen address {addr:?}, expected e.g. 127.0.0.1:5683"))
+}
+
+fn get_root_cert_store(cert_file: &str) -> anyhow::Result<RootCertStore> {
     let mut store = RootCertStore::empty();
-    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file).unwrap())) {
-        store
-            .add(&RustlsCertificate(cert.unwrap().to_vec()))
-            .unwrap();
+    let file = File::open(cert_file).with_context(|| format!("Couldn't open {cert_file}"))?;
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        store.add(&RustlsCertificate(cert?.to_vec()))?;
     }
-    store
+    Ok(store)
 }
 
-fn get_my_certs(cert_file: &str, key_file: &str) -> (Vec<Certificate>, KeyPair) {
-    let private_key = std::fs::read_to_string(key_file).unwrap();
-    let private_key = KeyPair::from_pem(&private_key).unwrap();
-    let cert_private_key = CryptoPrivateKey::from_key_pair(&private_key).unwrap();
+fn get_my_certs(cert_file: &str, key_file: &str) -> anyhow::Result<(Vec<Certificate>, KeyPair)> {
+    let private_key =
+        std::fs::read_to_string(key_file).with_context(|| format!("Couldn't read {key_file}"))?;
+    let private_key = KeyPair::from_pem(&private_key)?;
+    let cert_private_key = CryptoPrivateKey::from_key_pair(&private_key)?;
 
-    let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file).unwrap()))
-        .map(|cert_result| RustlsCertificate(cert_result.unwrap().to_vec()))
-        .collect();
+    let file = File::open(cert_file).with_context(|| format!("Couldn't open {cert_file}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map(|cert_result| Ok(RustlsCertificate(cert_result?.to_vec())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    (
+    Ok((
         vec![Certificate {
             certificate: certs,
             private_key: cert_private_key,
         }],
         private_key,
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+    };
+
+    use coap_lite::{CoapRequest, Packet};
+    use tokio::net::UdpSocket;
+    use webrtc_dtls::conn::DTLSConn;
+    use webrtc_util::Conn as _;
+
+    use super::*;
+
+    fn ca(name: &str) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn issue(signer: &rcgen::Certificate, name: &str) -> Certificate {
+        let cert =
+            rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![name.to_string()]))
+                .unwrap();
+        Certificate {
+            certificate: vec![RustlsCertificate(
+                cert.serialize_der_with_signer(signer).unwrap(),
+            )],
+            private_key: CryptoPrivateKey::from_key_pair(cert.get_key_pair()).unwrap(),
+        }
+    }
+
+    // Whether the endpoint accepts a handshake from a device with a cert issued by device_ca
+    async fn handshake(client_cas: RootCertStore, device_ca: &rcgen::Certificate) -> bool {
+        let root = ca("root");
+        let dtls_config = endpoint_dtls_config(vec![issue(&root, "arbiter.local")], client_cas);
+        let listener = listen("127.0.0.1:0", dtls_config).await.unwrap();
+        let addr = listener.addr().await.unwrap();
+        let accepted = tokio::spawn(async move { listener.accept().await.is_ok() });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let client_config = DtlsConfig {
+            certificates: vec![issue(device_ca, "device.local")],
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let _ = DTLSConn::new(Arc::new(socket), client_config, true, None).await;
+        accepted.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn device_endpoint_only_accepts_certs_from_the_device_ca() {
+        let device_ca = ca("devices");
+        let mut device_cas = RootCertStore::empty();
+        device_cas
+            .add(&RustlsCertificate(device_ca.serialize_der().unwrap()))
+            .unwrap();
+
+        assert!(handshake(device_cas.clone(), &device_ca).await);
+        assert!(!handshake(device_cas, &ca("controllers")).await);
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_over_ipv4_and_ipv6() {
+        let root = ca("root");
+        let mut client_cas = RootCertStore::empty();
+        client_cas
+            .add(&RustlsCertificate(root.serialize_der().unwrap()))
+            .unwrap();
+        let dtls_config = endpoint_dtls_config(vec![issue(&root, "arbiter.local")], client_cas);
+        let addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let (listeners, bound): (Vec<_>, Vec<_>) = bind_listeners(&addrs, &dtls_config)
+            .await
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let server = Server::from_listeners(listeners);
+        tokio::spawn(
+            server.run(|mut request: Box<CoapRequest<SocketAddr>>| async {
+                request.response.as_mut().unwrap().message.payload = b"pong".to_vec();
+                request
+            }),
+        );
+
+        for addr in bound {
+            let socket = UdpSocket::bind(SocketAddr::new(
+                if addr.is_ipv4() {
+                    Ipv4Addr::LOCALHOST.into()
+                } else {
+                    Ipv6Addr::LOCALHOST.into()
+                },
+                0,
+            ))
+            .await
+            .unwrap();
+            socket.connect(addr).await.unwrap();
+            let client_config = DtlsConfig {
+                certificates: vec![issue(&root, "controller.local")],
+                insecure_skip_verify: true,
+                ..Default::default()
+            };
+            let conn = DTLSConn::new(Arc::new(socket), client_config, true, None)
+                .await
+                .unwrap();
+
+            let mut request = CoapRequest::<SocketAddr>::new();
+            request.set_path("ping");
+            request.message.header.message_id = 1;
+            conn.send(&request.message.to_bytes().unwrap())
+                .await
+                .unwrap();
+            let mut buf = [0; 1500];
+            let len = conn.recv(&mut buf).await.unwrap();
+            let response = Packet::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(response.payload, b"pong", "No response over {addr}");
+        }
+    }
+
+    #[test]
+    fn malformed_listen_address_is_a_clear_error() {
+        assert_eq!(
+            parse_listen_addr("0.0.0.0:5683").unwrap(),
+            SocketAddr::from(([0, 0, 0, 0], 5683))
+        );
+        assert_eq!(
+            parse_listen_addr("[::1]:0").unwrap(),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 0))
+        );
+        for addr in ["localhost:5683", "127.0.0.1", "127.0.0.1:99999"] {
+            assert_eq!(
+                parse_listen_addr(addr).unwrap_err().to_string(),
+                format!("Invalid listen address {addr:?}, expected e.g. 127.0.0.1:5683")
+            );
+        }
+    }
 }
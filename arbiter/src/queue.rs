@@ -0,0 +1,159 @@
+use coap_lite::{CoapOption, CoapRequest};
+use tokio::sync::mpsc::{
+    channel,
+    error::{SendError, TrySendError},
+    Receiver, Sender,
+};
+
+use crate::request::{Request, RequestType};
+
+// Lets a client override the default priority of its request with "high" or "low". Elective, from
+// the experimental range like the other custom options.
+pub const PRIORITY_OPTION: CoapOption = CoapOption::Unknown(65008);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+impl Priority {
+    // Token requests and revocations are served ahead of discovery and registration when the
+    // state loop is backed up
+    pub fn of<Endpoint>(request: &RequestType, coap_request: &CoapRequest<Endpoint>) -> Self {
+        let requested = coap_request
+            .message
+            .get_first_option(PRIORITY_OPTION)
+            .and_then(|value| match value.as_slice() {
+                b"high" => Some(Priority::High),
+                b"low" => Some(Priority::Low),
+                _ => None,
+            });
+        requested.unwrap_or(match request {
+            RequestType::ControlToken(_) | RequestType::ProxyRead(_) | RequestType::Revoke(_) => {
+                Priority::High
+            }
+            RequestType::Register(..)
+            | RequestType::Deregister(_)
+            | RequestType::List(_)
+            | RequestType::Revocations
+            | RequestType::Shutdown => Priority::Low,
+        })
+    }
+}
+
+// Each priority has its own bounded queue, so a flood of low-priority requests can't fill up the
+// room high-priority ones need
+#[derive(Clone)]
+pub struct RequestSender {
+    high: Sender<Request>,
+    low: Sender<Request>,
+}
+
+impl RequestSender {
+    pub async fn send(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<(), SendError<Request>> {
+        self.queue(priority).send(request).await
+    }
+
+    // Like mpsc::Sender::try_send, hands the request back on failure
+    #[allow(clippy::result_large_err)]
+    pub fn try_send(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<(), TrySendError<Request>> {
+        self.queue(priority).try_send(request)
+    }
+
+    fn queue(&self, priority: Priority) -> &Sender<Request> {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        }
+    }
+}
+
+pub struct RequestReceiver {
+    high: Receiver<Request>,
+    low: Receiver<Request>,
+}
+
+impl RequestReceiver {
+    // Returns None once all senders are gone and both queues are drained
+    pub async fn recv(&mut self) -> Option<Request> {
+        tokio::select! {
+            biased;
+            Some(request) = self.high.recv() => Some(request),
+            Some(request) = self.low.recv() => Some(request),
+            else => None,
+        }
+    }
+}
+
+pub fn request_queue(size: usize) -> (RequestSender, RequestReceiver) {
+    let (high_tx, high_rx) = channel(size);
+    let (low_tx, low_rx) = channel(size);
+    (
+        RequestSender {
+            high: high_tx,
+            low: low_tx,
+        },
+        RequestReceiver {
+            high: high_rx,
+            low: low_rx,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn high_priority_request_overtakes_earlier_low_priority_ones() {
+        let (tx, mut rx) = request_queue(4);
+        for _ in 0..3 {
+            tx.send(
+                Request::asynchronous(RequestType::Revocations),
+                Priority::Low,
+            )
+            .await
+            .unwrap();
+        }
+        tx.send(Request::asynchronous(RequestType::Shutdown), Priority::High)
+            .await
+            .unwrap();
+        drop(tx);
+
+        let mut served = vec![];
+        while let Some(request) = rx.recv().await {
+            served.push(request.get_type().name());
+        }
+        assert_eq!(
+            served,
+            ["Shutdown", "Revocations", "Revocations", "Revocations"]
+        );
+    }
+
+    #[test]
+    fn priority_option_overrides_the_default() {
+        let mut coap_request = CoapRequest::<SocketAddr>::new();
+        assert_eq!(
+            Priority::of(&RequestType::Revocations, &coap_request),
+            Priority::Low
+        );
+        coap_request
+            .message
+            .add_option(PRIORITY_OPTION, b"high".to_vec());
+        assert_eq!(
+            Priority::of(&RequestType::Revocations, &coap_request),
+            Priority::High
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::RateLimitConfig;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitBudget {
+    pub remaining: u32,
+    pub reset_secs: u64,
+}
+
+struct Window {
+    started: Instant,
+    used: u32,
+}
+
+// Fixed-window limit on how many requests each controller (or other requester, such as a source
+// address) may make
+pub struct RateLimiter<K = Uuid> {
+    max_requests: u32,
+    window: Duration,
+    windows: HashMap<K, Window>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            max_requests: config.max_requests,
+            window: Duration::from_secs(config.window_secs),
+            windows: HashMap::new(),
+        }
+    }
+
+    // Counts a request from the controller against its budget. Returns the budget left after
+    // this request, or Err with the (empty) budget if the limit has already been reached.
+    pub fn check(&mut self, key: K, now: Instant) -> Result<RateLimitBudget, RateLimitBudget> {
+        let window = self.windows.entry(key).or_insert(Window {
+            started: now,
+            used: 0,
+        });
+        if now.duration_since(window.started) >= self.window {
+            window.started = now;
+            window.used = 0;
+        }
+
+        let reset_secs = (window.started + self.window)
+            .saturating_duration_since(now)
+            .as_secs();
+        if window.used >= self.max_requests {
+            return Err(RateLimitBudget {
+                remaining: 0,
+                reset_secs,
+            });
+        }
+
+        window.used += 1;
+        Ok(RateLimitBudget {
+            remaining: self.max_requests - window.used,
+            reset_secs,
+        })
+    }
+
+    // Forgets controllers whose window has run out; they get a fresh one on their next request
+    // anyway
+    pub fn evict_expired(&mut self, now: Instant) {
+        self.windows
+            .retain(|_, window| now.duration_since(window.started) < self.window);
+    }
+}
+
+// Caps how many requests each controller may have waiting for a response at once
+pub struct InFlightLimiter {
+    max_in_flight: usize,
+    in_flight: Arc<Mutex<HashMap<Uuid, usize>>>,
+}
+
+// Counts as in flight until dropped
+pub struct InFlightGuard {
+    cid: Uuid,
+    in_flight: Arc<Mutex<HashMap<Uuid, usize>>>,
+}
+
+impl InFlightLimiter {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight,
+            in_flight: Default::default(),
+        }
+    }
+
+    // Returns None if the controller already has the maximum number of requests in flight
+    pub fn try_acquire(&self, cid: Uuid) -> Option<InFlightGuard> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(cid).or_insert(0);
+        if *count >= self.max_in_flight {
+            return None;
+        }
+        *count += 1;
+        Some(InFlightGuard {
+            cid,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.cid) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.cid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_windows_are_evicted() {
+        let mut rate_limiter = RateLimiter::new(&RateLimitConfig {
+            max_requests: 1,
+            window_secs: 60,
+        });
+        let start = Instant::now();
+        rate_limiter.check(1, start).unwrap();
+        rate_limiter
+            .check(2, start + Duration::from_secs(30))
+            .unwrap();
+
+        rate_limiter.evict_expired(start + Duration::from_secs(59));
+        assert_eq!(rate_limiter.windows.len(), 2);
+        rate_limiter.evict_expired(start + Duration::from_secs(60));
+        assert_eq!(rate_limiter.windows.len(), 1);
+        assert!(rate_limiter.windows.contains_key(&2));
+        rate_limiter.evict_expired(start + Duration::from_secs(90));
+        assert!(rate_limiter.windows.is_empty());
+    }
+}
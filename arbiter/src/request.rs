@@ -1,10 +1,19 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
-use coap_lite::{error::HandlingError, CoapRequest};
+use coap_lite::{error::HandlingError, CoapOption, CoapRequest, MessageClass};
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot::Sender as OneshotSender;
 use uuid::Uuid;
 
+use crate::rate_limit::RateLimitBudget;
+
+// Detached ES256 signature (base64url) over the uncompressed GET /devices payload. An elective
+// option from the experimental range, like the content encoding one.
+pub const SIGNATURE_OPTION: CoapOption = CoapOption::Unknown(65004);
+
 pub struct Request {
     ty: RequestType,
     notify: Option<OneshotSender<Response>>,
@@ -26,55 +35,174 @@ impl Request {
         &self.ty
     }
 
+    // Fails only if a synchronous requester stopped waiting before the response was ready;
+    // asynchronous requests have no one to respond to.
     pub fn respond(self, response: Response) -> Result<(), Response> {
-        if let Some(notify) = self.notify {
-            notify.send(response)
-        } else {
-            Err(response)
+        match self.notify {
+            Some(notify) => notify.send(response),
+            None => Ok(()),
         }
     }
 }
 
 pub enum RequestType {
-    Register(ApiDevice),
-    List,
+    // Along with the address the registration came from, and the key from the device's last
+    // registration if it is renewing it
+    Register(ApiDevice, Option<IpAddr>, Option<String>),
+    Deregister(DeregisterRequest),
+    List(ListFilter),
     ControlToken(ControlTokenRequest),
+    ProxyRead(ProxyReadRequest),
+    Revoke(RevokeRequest),
+    Revocations,
     Shutdown,
 }
 
-#[derive(Debug, Serialize)]
+impl RequestType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestType::Register(..) => "Register",
+            RequestType::Deregister(_) => "Deregister",
+            RequestType::List(_) => "List",
+            RequestType::ControlToken(_) => "ControlToken",
+            RequestType::ProxyRead(_) => "ProxyRead",
+            RequestType::Revoke(_) => "Revoke",
+            RequestType::Revocations => "Revocations",
+            RequestType::Shutdown => "Shutdown",
+        }
+    }
+}
+
+// Deserialize is only used for seeding the registry from a file
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ApiDevice {
     pub cid: Uuid,
     pub label: String,
     pub manufacturer: String,
     pub model: String,
     pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_port: Option<u16>,
+    #[serde(default = "default_seed_ttl")]
     pub ttl: u64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // The parameters the device has, if it told us. Tokens are only issued for these.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Vec<String>>,
+    // Public key controllers can encrypt PUT values to, so that only the device can read them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn default_seed_ttl() -> u64 {
+    // A day, so that seeded devices don't vanish during a development session
+    24 * 60 * 60
+}
+
+#[derive(Debug)]
+pub struct DeregisterRequest {
+    pub cid: Uuid,
+    // From the device's registration response, to show that the request comes from the device
+    pub registration_key: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationResponse {
+    pub registration_key: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    pub tag: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ControlTokenRequest {
     pub cid: Uuid,
     pub devices: Vec<Uuid>,
     pub params_read: Vec<String>,
     pub params_write: Vec<String>,
+    // Retrying with the same key returns the tokens issued the first time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    // Requested token lifetime, clamped to the arbiter's maximum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
+pub struct RevokeRequest {
+    // The controller asking, which must be the one the token was issued to unless it is an admin
+    pub controller: Uuid,
+    pub jti: Uuid,
+}
+
+#[derive(Debug)]
+pub struct ProxyReadRequest {
+    pub controller: Uuid,
+    pub device: Uuid,
+    pub parameter: String,
+}
+
+// Where and with which token the request handler should read a parameter for a controller
+#[derive(Debug)]
+pub struct ProxyTarget {
+    pub port: u16,
+    pub parameter: String,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ControlTokenResponse {
     pub tokens: HashMap<Uuid, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitBudget>,
+    // What each token grants, which is less than was asked for if the ACL narrowed it
+    #[serde(skip)]
+    pub scopes: HashMap<Uuid, TokenScope>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenScope {
+    pub jti: Uuid,
+    pub params_read: Vec<String>,
+    pub params_write: Vec<String>,
+    // How the ACL decided on the above, for the audit log
+    pub acl_entries: Vec<usize>,
+    pub denied_read: Vec<String>,
+    pub denied_write: Vec<String>,
 }
 
 pub enum Response {
     Ok,
+    Registered(RegistrationResponse),
     ListResponse(ListResponse),
     ControlTokenResponse(ControlTokenResponse),
+    ProxyTarget(ProxyTarget),
+    // jtis of revoked tokens that haven't expired yet
+    Revocations(Vec<Uuid>),
+    // A device's response, passed through to the controller as-is
+    Proxied {
+        code: MessageClass,
+        payload: Vec<u8>,
+    },
     Error(HandlingError),
 }
 
 pub struct ListResponse {
     pub devices: Vec<ApiDevice>,
+    pub signature: Option<String>,
+}
+
+impl ListResponse {
+    pub fn payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.devices).unwrap()
+    }
 }
 
 impl Response {
@@ -86,12 +214,29 @@ impl Response {
 
         match self {
             Response::Ok => {}
+            Response::Registered(registration) => {
+                resp.message.payload = serde_json::to_vec(&registration).unwrap();
+            }
             Response::ListResponse(list) => {
-                resp.message.payload = serde_json::to_vec(&list.devices).unwrap();
+                resp.message.payload = list.payload();
+                if let Some(signature) = list.signature {
+                    resp.message
+                        .add_option(SIGNATURE_OPTION, signature.into_bytes());
+                }
             }
             Response::ControlTokenResponse(payload) => {
                 resp.message.payload = serde_json::to_vec(&payload).unwrap();
             }
+            Response::Revocations(jtis) => {
+                resp.message.payload = serde_json::to_vec(&jtis).unwrap();
+            }
+            Response::ProxyTarget(_) => {
+                unreachable!("Proxy targets are resolved by the request handler")
+            }
+            Response::Proxied { code, payload } => {
+                resp.message.header.code = code;
+                resp.message.payload = payload;
+            }
             Response::Error(e) => {
                 message.apply_from_error(e);
             }
@@ -1,20 +1,212 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use coap::request::{CoapRequest, Method};
+use coap::client::CoAPClient;
+use coap::dtls::UdpDtlsConfig;
+use coap::request::{CoapRequest, Method, RequestBuilder};
 use coap_lite::error::HandlingError;
-use serde::Deserialize;
-use tokio::sync::mpsc::Sender;
+use coap_lite::{CoapOption, MessageClass, ResponseType};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::oneshot::channel as oneshot_channel;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+use webrtc_dtls::config::Config as DtlsConfig;
 
-use crate::request::{ApiDevice, ControlTokenRequest, Request, RequestType};
+use crate::{
+    compression::compress_response_if_accepted,
+    config::{Config, QueuePolicy},
+    queue::{Priority, RequestSender},
+    rate_limit::InFlightLimiter,
+    request::{
+        ApiDevice, ControlTokenRequest, DeregisterRequest, ListFilter, ProxyReadRequest,
+        ProxyTarget, Request, RequestType, Response, RevokeRequest,
+    },
+};
+
+// Which kinds of peers a listener serves. Device and Controller are used when devices have to
+// present certificates from a separate device CA, Combined otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    Combined,
+    Controller,
+    Device,
+}
+
+impl Endpoint {
+    fn allows(&self, request: &RequestType) -> bool {
+        let is_device_request = matches!(
+            request,
+            RequestType::Register(..) | RequestType::Deregister(_)
+        );
+        // Devices fetch the revocation list too
+        let is_shared = matches!(request, RequestType::Revocations);
+        match self {
+            Endpoint::Combined => true,
+            Endpoint::Controller => !is_device_request,
+            Endpoint::Device => is_device_request || is_shared,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    #[serde(skip)]
+    proxy_only: bool,
+}
+
+const ROUTES: &[Route] = &[
+    Route {
+        method: "GET",
+        path: "/health",
+        proxy_only: false,
+    },
+    Route {
+        method: "GET",
+        path: "/info",
+        proxy_only: false,
+    },
+    Route {
+        method: "GET",
+        path: "/devices?tag={tag}",
+        proxy_only: false,
+    },
+    Route {
+        method: "PUT",
+        path: "/devices/{cid}",
+        proxy_only: false,
+    },
+    Route {
+        method: "DELETE",
+        path: "/devices/{cid}",
+        proxy_only: false,
+    },
+    Route {
+        method: "GET",
+        path: "/devices/{cid}/params/{parameter}",
+        proxy_only: true,
+    },
+    Route {
+        method: "GET",
+        path: "/controlToken",
+        proxy_only: false,
+    },
+    Route {
+        method: "POST",
+        path: "/revoke",
+        proxy_only: false,
+    },
+    Route {
+        method: "GET",
+        path: "/revocations",
+        proxy_only: false,
+    },
+];
+
+#[derive(Serialize)]
+struct InfoPayload {
+    // Unix seconds, so that peers can check their clocks against ours
+    time: u64,
+}
+
+#[derive(Serialize)]
+struct NotFoundPayload {
+    error: &'static str,
+    routes: Vec<&'static Route>,
+}
+
+// Upper bound on connecting to a device and reading a parameter for a proxied request
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct RequestHandler {
-    tx: Sender<Request>,
+    tx: RequestSender,
+    endpoint: Endpoint,
+    // Client config for connecting to devices, if proxied reads are enabled
+    proxy_dtls_config: Option<DtlsConfig>,
+    queue_policy: QueuePolicy,
+    compression_threshold: usize,
+    in_flight_limiter: Option<InFlightLimiter>,
 }
 
 impl RequestHandler {
-    pub fn new(tx: Sender<Request>) -> Self {
-        RequestHandler { tx }
+    pub fn new(
+        tx: RequestSender,
+        endpoint: Endpoint,
+        config: &Config,
+        proxy_dtls_config: Option<DtlsConfig>,
+    ) -> Self {
+        RequestHandler {
+            tx,
+            endpoint,
+            proxy_dtls_config,
+            queue_policy: config.request_queue_policy,
+            compression_threshold: config.compression_threshold,
+            in_flight_limiter: config
+                .max_in_flight_token_requests
+                .map(InFlightLimiter::new),
+        }
+    }
+
+    // A 4.04 whose body lists the routes this handler serves, to point clients in the right
+    // direction
+    fn apply_not_found(&self, request: &mut CoapRequest<SocketAddr>) {
+        let routes: Vec<_> = ROUTES
+            .iter()
+            .filter(|route| !route.proxy_only || self.proxy_dtls_config.is_some())
+            .collect();
+        let resp = request.response.as_mut().unwrap();
+        resp.message.header.code = MessageClass::Response(ResponseType::NotFound);
+        resp.message.payload = serde_json::to_vec(&NotFoundPayload {
+            error: "Unknown route",
+            routes,
+        })
+        .unwrap();
+    }
+
+    async fn proxy_read(&self, target: ProxyTarget) -> Response {
+        let mut dtls_config = self.proxy_dtls_config.clone().unwrap();
+        dtls_config.server_name = "device.local".to_string();
+        let dest_addr = SocketAddr::from(([127, 0, 0, 1], target.port));
+
+        let read = async {
+            let client = CoAPClient::from_udp_dtls_config(UdpDtlsConfig {
+                config: dtls_config,
+                dest_addr,
+            })
+            .await?;
+            let request = RequestBuilder::new(&format!("/{}", target.parameter), Method::Get)
+                .domain(dest_addr.to_string())
+                .data(Some(
+                    serde_json::to_vec(&ProxyGetParamPayload {
+                        token: target.token,
+                    })
+                    .unwrap(),
+                ))
+                .build();
+            client.send(request).await
+        };
+
+        match tokio::time::timeout(PROXY_TIMEOUT, read).await {
+            Ok(Ok(response)) => Response::Proxied {
+                code: response.message.header.code,
+                payload: response.message.payload,
+            },
+            Ok(Err(e)) => {
+                println!("Proxied read from device failed: {e}");
+                Response::Error(HandlingError::with_code(
+                    ResponseType::BadGateway,
+                    "Couldn't read parameter from device",
+                ))
+            }
+            Err(_) => Response::Error(HandlingError::with_code(
+                ResponseType::GatewayTimeout,
+                "Device didn't respond in time",
+            )),
+        }
     }
 }
 
@@ -33,21 +225,45 @@ impl coap::server::RequestHandler for RequestHandler {
         'life0: 'async_trait,
         Self: 'async_trait,
     {
-        Box::pin(async {
+        // Groups the traces of handling this request, so they can be told apart from other requests
+        let span = info_span!(
+            "request",
+            method = ?request.get_method(),
+            path = %request.get_path(),
+            peer = request.source.map(display),
+        );
+        let handling = async {
             // We are not handling any Acknowledgment or Reset messages
-            if !request.response.is_some() {
+            if request.response.is_none() {
                 return request;
             };
 
-            match request.get_method() {
-                &Method::Get => println!("handling: GET /{}", request.get_path()),
-                &Method::Post => println!("handling: POST /{}", request.get_path(),),
-                &Method::Put => println!("handling: PUT /{}", request.get_path()),
+            match *request.get_method() {
+                Method::Get => println!("handling: GET /{}", request.get_path()),
+                Method::Post => println!("handling: POST /{}", request.get_path(),),
+                Method::Put => println!("handling: PUT /{}", request.get_path()),
+                Method::Delete => println!("handling: DELETE /{}", request.get_path()),
                 _ => println!("Ignoring request with unknown method"),
             };
 
             let path = request.get_path_as_vec().unwrap();
 
+            // Answered by any endpoint, without going through the state loop, so that peers can
+            // tell when the arbiter is up
+            if request.get_method() == &Method::Get && path == ["health"] {
+                request.response.as_mut().unwrap().message.payload = b"ok".to_vec();
+                return request;
+            }
+            if request.get_method() == &Method::Get && path == ["info"] {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                request.response.as_mut().unwrap().message.payload =
+                    serde_json::to_vec(&InfoPayload { time }).unwrap();
+                return request;
+            }
+
             let req = match (
                 request.get_method(),
                 path.iter()
@@ -55,7 +271,9 @@ impl coap::server::RequestHandler for RequestHandler {
                     .collect::<Vec<_>>()
                     .as_slice(),
             ) {
-                (&Method::Get, &["devices"]) => RequestType::List,
+                (&Method::Get, &["devices"]) => RequestType::List(ListFilter {
+                    tag: get_query_param(&request, "tag"),
+                }),
                 (&Method::Put, &["devices", id]) => {
                     let payload = match serde_json::from_slice::<PutDevicePayload>(
                         &request.message.payload,
@@ -69,13 +287,80 @@ impl coap::server::RequestHandler for RequestHandler {
                         }
                     };
 
-                    RequestType::Register(ApiDevice {
-                        cid: id.parse().unwrap(),
-                        label: payload.label,
-                        manufacturer: payload.manufacturer,
-                        model: payload.model,
-                        port: payload.port,
-                        ttl: payload.ttl,
+                    let cid = match id.parse() {
+                        Ok(cid) => cid,
+                        Err(e) => {
+                            request.apply_from_error(HandlingError::bad_request(format!(
+                                "Invalid device CID {id}: {e}"
+                            )));
+                            return request;
+                        }
+                    };
+
+                    RequestType::Register(
+                        ApiDevice {
+                            cid,
+                            label: payload.label,
+                            manufacturer: payload.manufacturer,
+                            model: payload.model,
+                            port: payload.port,
+                            previous_port: None,
+                            ttl: payload.ttl,
+                            tags: payload.tags,
+                            parameters: payload.parameters,
+                            encryption_key: payload.encryption_key,
+                        },
+                        request.source.map(|source| source.ip()),
+                        payload.registration_key,
+                    )
+                }
+                (&Method::Delete, &["devices", id]) => {
+                    let cid = match id.parse() {
+                        Ok(cid) => cid,
+                        Err(e) => {
+                            request.apply_from_error(HandlingError::bad_request(format!(
+                                "Invalid device CID {id}: {e}"
+                            )));
+                            return request;
+                        }
+                    };
+                    // Devices that never got a registration key have nothing to send
+                    let payload = if request.message.payload.is_empty() {
+                        DeleteDevicePayload::default()
+                    } else {
+                        match serde_json::from_slice(&request.message.payload) {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                request.apply_from_error(HandlingError::bad_request(format!(
+                                    "Couldn't parse payload of DELETE /devices/{id}: {e}"
+                                )));
+                                return request;
+                            }
+                        }
+                    };
+
+                    RequestType::Deregister(DeregisterRequest {
+                        cid,
+                        registration_key: payload.registration_key,
+                    })
+                }
+                (&Method::Get, &["devices", id, "params", parameter])
+                    if self.proxy_dtls_config.is_some() =>
+                {
+                    let (Ok(device), Ok(payload)) = (
+                        id.parse(),
+                        serde_json::from_slice::<ProxyReadPayload>(&request.message.payload),
+                    ) else {
+                        request.apply_from_error(HandlingError::bad_request(format!(
+                            "Couldn't parse GET /devices/{id}/params/{parameter}"
+                        )));
+                        return request;
+                    };
+
+                    RequestType::ProxyRead(ProxyReadRequest {
+                        controller: payload.cid,
+                        device,
+                        parameter: parameter.to_string(),
                     })
                 }
                 (&Method::Get, &["controlToken"]) => {
@@ -93,37 +378,390 @@ impl coap::server::RequestHandler for RequestHandler {
 
                     RequestType::ControlToken(payload)
                 }
+                (&Method::Post, &["revoke"]) => {
+                    match serde_json::from_slice::<RevokePayload>(&request.message.payload) {
+                        Ok(payload) => RequestType::Revoke(RevokeRequest {
+                            controller: payload.cid,
+                            jti: payload.jti,
+                        }),
+                        Err(e) => {
+                            request.apply_from_error(HandlingError::bad_request(format!(
+                                "Couldn't parse payload of POST /revoke: {e}"
+                            )));
+                            return request;
+                        }
+                    }
+                }
+                (&Method::Get, &["revocations"]) => RequestType::Revocations,
                 (_, _) => {
-                    request.apply_from_error(HandlingError::not_found());
+                    self.apply_not_found(&mut request);
                     return request;
                 }
             };
 
+            if !self.endpoint.allows(&req) {
+                request.apply_from_error(HandlingError::with_code(
+                    ResponseType::Forbidden,
+                    match self.endpoint {
+                        Endpoint::Device => "Only device registration is served on this endpoint",
+                        _ => "Devices must register on the device endpoint",
+                    },
+                ));
+                return request;
+            }
+
+            let token_requester = match req {
+                RequestType::ControlToken(ref request) => Some(request.cid),
+                RequestType::ProxyRead(ref request) => Some(request.controller),
+                _ => None,
+            };
+            // Held until the response has been sent back
+            let _in_flight = match (&self.in_flight_limiter, token_requester) {
+                (Some(limiter), Some(cid)) => match limiter.try_acquire(cid) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        println!("Controller {cid} has too many token requests in flight");
+                        request.apply_from_error(HandlingError::with_code(
+                            ResponseType::ServiceUnavailable,
+                            "Too many token requests in flight, try again later",
+                        ));
+                        return request;
+                    }
+                },
+                _ => None,
+            };
+
+            let priority = Priority::of(&req, &request);
             let (resp_tx, resp_rx) = oneshot_channel();
-            self.tx
-                .send(Request::synchronous(req, resp_tx))
-                .await
-                .unwrap();
-            let resp = resp_rx.await.unwrap();
+            let req = Request::synchronous(req, resp_tx);
+            let sent = match self.queue_policy {
+                QueuePolicy::Block => self
+                    .tx
+                    .send(req, priority)
+                    .await
+                    .map_err(|e| TrySendError::Closed(e.0)),
+                QueuePolicy::Shed => self.tx.try_send(req, priority),
+            };
+            match sent {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    println!("Request queue is full, shedding request");
+                    request.apply_from_error(HandlingError::with_code(
+                        ResponseType::ServiceUnavailable,
+                        "Arbiter is overloaded, try again later",
+                    ));
+                    return request;
+                }
+                Err(TrySendError::Closed(_)) => panic!("Arbiter state loop has shut down"),
+            }
+            let resp = match resp_rx.await.unwrap() {
+                Response::ProxyTarget(target) => self.proxy_read(target).await,
+                resp => resp,
+            };
 
             resp.into_coap_response(&mut request);
+            compress_response_if_accepted(&mut request, self.compression_threshold);
 
             request
-        })
+        };
+        Box::pin(handling.instrument(span))
     }
 }
 
 impl Drop for RequestHandler {
     fn drop(&mut self) {
-        let _ = self.tx.send(Request::asynchronous(RequestType::Shutdown));
+        let _ = self
+            .tx
+            .try_send(Request::asynchronous(RequestType::Shutdown), Priority::Low);
     }
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PutDevicePayload {
     label: String,
     manufacturer: String,
     model: String,
     port: u16,
     ttl: u64,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    parameters: Option<Vec<String>>,
+    #[serde(default)]
+    encryption_key: Option<String>,
+    #[serde(default)]
+    registration_key: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteDevicePayload {
+    #[serde(default)]
+    registration_key: Option<String>,
+}
+
+// The cid is taken on trust and only recorded in the audit log, see State::revoke_token
+#[derive(Deserialize)]
+struct RevokePayload {
+    cid: Uuid,
+    jti: Uuid,
+}
+
+#[derive(Deserialize)]
+struct ProxyReadPayload {
+    cid: Uuid,
+}
+
+#[derive(Serialize)]
+struct ProxyGetParamPayload {
+    token: String,
+}
+
+fn get_query_param(request: &CoapRequest<SocketAddr>, name: &str) -> Option<String> {
+    request
+        .message
+        .get_option(CoapOption::UriQuery)?
+        .iter()
+        .filter_map(|query| std::str::from_utf8(query).ok())
+        .find_map(|query| {
+            query
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(str::to_string)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use coap::server::RequestHandler as _;
+    use coap_lite::CoapResponse;
+    use serde_json::json;
+    use webrtc_dtls::{
+        crypto::{Certificate, CryptoPrivateKey},
+        listener::listen,
+    };
+    use webrtc_util::conn::Listener as _;
+
+    use super::*;
+    use crate::queue::{request_queue, RequestReceiver};
+
+    const CONTROLLER: Uuid = Uuid::from_u128(0xc1);
+
+    fn handler(config: serde_json::Value, queue_size: usize) -> (RequestHandler, RequestReceiver) {
+        let mut fields = json!({ "cid": Uuid::from_u128(0xa1) });
+        fields
+            .as_object_mut()
+            .unwrap()
+            .extend(config.as_object().unwrap().clone());
+        let config: Config = serde_json::from_value(fields).unwrap();
+        let (tx, rx) = request_queue(queue_size);
+        (
+            RequestHandler::new(tx, Endpoint::Combined, &config, None),
+            rx,
+        )
+    }
+
+    async fn send(
+        handler: &RequestHandler,
+        method: Method,
+        path: &str,
+        payload: serde_json::Value,
+    ) -> ResponseType {
+        send_for_body(handler, method, path, payload).await.0
+    }
+
+    async fn send_for_body(
+        handler: &RequestHandler,
+        method: Method,
+        path: &str,
+        payload: serde_json::Value,
+    ) -> (ResponseType, Vec<u8>) {
+        let mut request = CoapRequest::new();
+        request.set_method(method);
+        request.set_path(path);
+        request.message.payload = serde_json::to_vec(&payload).unwrap();
+        request.response = CoapResponse::new(&request.message);
+        request.source = Some(SocketAddr::from(([127, 0, 0, 1], 5683)));
+
+        let response = handler
+            .handle_request(Box::new(request))
+            .await
+            .response
+            .unwrap();
+        let MessageClass::Response(code) = response.message.header.code else {
+            panic!("Not a response: {}", response.message.header.code);
+        };
+        (code, response.message.payload)
+    }
+
+    #[tokio::test]
+    async fn request_is_shed_when_the_queue_is_full() {
+        let (handler, _rx) = handler(json!({ "requestQueuePolicy": "shed" }), 1);
+        handler
+            .tx
+            .try_send(
+                Request::asynchronous(RequestType::Revocations),
+                Priority::Low,
+            )
+            .unwrap();
+
+        let code = send(&handler, Method::Get, "revocations", json!({})).await;
+        assert_eq!(code, ResponseType::ServiceUnavailable);
+    }
+
+    #[tokio::test]
+    async fn token_requests_beyond_the_in_flight_cap_are_refused() {
+        let (handler, mut rx) = handler(json!({ "maxInFlightTokenRequests": 1 }), 8);
+        let handler = Arc::new(handler);
+        let token_request = json!({
+            "cid": CONTROLLER,
+            "devices": [],
+            "paramsRead": [],
+            "paramsWrite": [],
+        });
+
+        // Stays in flight until the state loop, played here, responds
+        let first = tokio::spawn({
+            let handler = handler.clone();
+            let token_request = token_request.clone();
+            async move { send(&handler, Method::Get, "controlToken", token_request).await }
+        });
+        let queued = rx.recv().await.unwrap();
+        let code = send(&handler, Method::Get, "controlToken", token_request.clone()).await;
+        assert_eq!(code, ResponseType::ServiceUnavailable);
+
+        assert!(queued.respond(Response::Ok).is_ok());
+        first.await.unwrap();
+        let second = tokio::spawn({
+            let handler = handler.clone();
+            async move { send(&handler, Method::Get, "controlToken", token_request).await }
+        });
+        assert!(rx.recv().await.unwrap().respond(Response::Ok).is_ok());
+        assert_eq!(second.await.unwrap(), ResponseType::Content);
+    }
+
+    #[tokio::test]
+    async fn proxied_read_returns_the_device_value() {
+        // Stands in for the device, answering with the token it was sent
+        let mut params = rcgen::CertificateParams::new(vec!["device.local".to_string()]);
+        params.key_pair = Some(rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let device_config = DtlsConfig {
+            certificates: vec![Certificate {
+                certificate: vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                private_key: CryptoPrivateKey::from_key_pair(cert.get_key_pair()).unwrap(),
+            }],
+            ..Default::default()
+        };
+        let listener = listen("127.0.0.1:0", device_config).await.unwrap();
+        let port = listener.addr().await.unwrap().port();
+        let server = coap::Server::from_listeners(vec![Box::new(listener)]);
+        tokio::spawn(
+            server.run(|mut request: Box<CoapRequest<SocketAddr>>| async move {
+                let payload: serde_json::Value =
+                    serde_json::from_slice(&request.message.payload).unwrap();
+                let value = format!("{} for {}", payload["token"], request.get_path());
+                request.response.as_mut().unwrap().message.payload = value.into_bytes();
+                request
+            }),
+        );
+
+        let (tx, _rx) = request_queue(1);
+        let config = serde_json::from_value(json!({ "cid": Uuid::from_u128(0xa1) })).unwrap();
+        let proxy_dtls_config = DtlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let handler = RequestHandler::new(tx, Endpoint::Combined, &config, Some(proxy_dtls_config));
+        let response = handler
+            .proxy_read(ProxyTarget {
+                port,
+                parameter: "temp".to_string(),
+                token: "minted".to_string(),
+            })
+            .await;
+        match response {
+            Response::Proxied { code, payload } => {
+                assert_eq!(code, MessageClass::Response(ResponseType::Content));
+                assert_eq!(payload, b"\"minted\" for temp");
+            }
+            _ => panic!("Read wasn't proxied"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_route_lists_the_known_routes() {
+        let (handler, _rx) = handler(json!({}), 1);
+        let (code, body) = send_for_body(&handler, Method::Get, "nonexistent", json!({})).await;
+        assert_eq!(code, ResponseType::NotFound);
+
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Unknown route");
+        let routes = body["routes"].as_array().unwrap();
+        assert!(routes.contains(&json!({ "method": "GET", "path": "/controlToken" })));
+        assert!(routes.contains(&json!({ "method": "DELETE", "path": "/devices/{cid}" })));
+        // Proxied reads aren't enabled on this handler
+        assert!(!routes
+            .iter()
+            .any(|route| route["path"] == "/devices/{cid}/params/{parameter}"));
+    }
+
+    // Records the name and fields of each span created while it is the default subscriber
+    #[derive(Default)]
+    struct SpanRecorder(Mutex<Vec<(&'static str, HashMap<&'static str, String>)>>);
+
+    struct SpanFields<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl tracing::field::Visit for SpanFields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            span.record(&mut SpanFields(&mut fields));
+            let mut spans = self.0.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn request_is_handled_in_a_span_with_its_method_path_and_peer() {
+        let recorder = Arc::new(SpanRecorder::default());
+        let _subscriber = tracing::subscriber::set_default(recorder.clone());
+        let (handler, _rx) = handler(json!({}), 1);
+
+        let code = send(&handler, Method::Get, "health", json!({})).await;
+        assert_eq!(code, ResponseType::Content);
+        let spans = recorder.0.lock().unwrap();
+        let expected = HashMap::from([
+            ("method", "Get".to_string()),
+            ("path", "health".to_string()),
+            ("peer", "127.0.0.1:5683".to_string()),
+        ]);
+        assert_eq!(*spans, [("request", expected)]);
+    }
 }
@@ -1,21 +1,30 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    time::{self, Instant},
+    net::IpAddr,
+    time::{self, Duration, Instant},
 };
 
-use coap_lite::error::HandlingError;
+use coap_lite::{error::HandlingError, ResponseType};
 use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::warn;
 use rcgen::KeyPair;
 use serde::Serialize;
-use tokio::sync::mpsc::Receiver;
+use tracing::info_span;
 use uuid::Uuid;
 
 use crate::{
-    acl::AclDatabase,
+    acl::{AclDatabase, AclDenied},
+    audit::{AuditEvent, AuditLog},
+    config::Config,
+    fleet_events::{self, FleetEvent},
+    queue::RequestReceiver,
+    rate_limit::RateLimiter,
     request::{
-        ApiDevice, ControlTokenRequest, ControlTokenResponse, ListResponse, Request, RequestType,
-        Response,
+        ApiDevice, ControlTokenRequest, ControlTokenResponse, DeregisterRequest, ListFilter,
+        ListResponse, ProxyReadRequest, ProxyTarget, RegistrationResponse, Request, RequestType,
+        Response, RevokeRequest, TokenScope,
     },
+    webhook::{Notifier, WebhookEvent},
 };
 
 struct Device {
@@ -23,88 +32,605 @@ struct Device {
     manufacturer: String,
     model: String,
     port: u16,
+    // Port from before the last re-registration, kept until the grace period runs out
+    previous_port: Option<(u16, Instant)>,
+    tags: Vec<String>,
+    parameters: Option<Vec<String>>,
+    encryption_key: Option<String>,
+    // Handed to the device when it registers, and needed to deregister it
+    registration_key: String,
     valid_until: Instant,
 }
 
 struct State {
     devices: HashMap<Uuid, Device>,
+    audit_log: Option<AuditLog>,
+    notifier: Notifier,
+    emit_fleet_events: bool,
+    // When each token we issued expires, keyed by jti
+    issued_tokens: HashMap<Uuid, Instant>,
+    // jtis of revoked tokens, kept until any token with that jti would have expired anyway
+    revoked_tokens: HashMap<Uuid, Instant>,
+    // Responses that couldn't be delivered because the requester had gone away
+    dead_letters: u64,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(audit_log: Option<AuditLog>, notifier: Notifier, emit_fleet_events: bool) -> Self {
         State {
             devices: HashMap::new(),
+            audit_log,
+            notifier,
+            emit_fleet_events,
+            issued_tokens: HashMap::new(),
+            revoked_tokens: HashMap::new(),
+            dead_letters: 0,
         }
     }
+
+    fn audit(&mut self, event: AuditEvent) {
+        if let Some(ref mut audit_log) = self.audit_log {
+            audit_log.record(event);
+        }
+    }
+
+    fn fleet_event(&self, event: FleetEvent) {
+        if self.emit_fleet_events {
+            fleet_events::emit(&event);
+        }
+    }
+
+    // Only the controller a token was issued to may revoke it, unless the requester is an admin.
+    // Admins may also revoke tokens we don't know of, e.g. ones issued before a restart. Devices
+    // only learn of the revocation when they next fetch the list, and until then the token keeps
+    // working on them.
+    // Revocation isn't authenticated: all controllers share a certificate, so nothing proves
+    // which one is asking, and any controller can revoke any token. The CID it gives is only
+    // recorded in the audit log.
+    fn revoke_token(&mut self, request: &RevokeRequest, max_token_lifetime: Duration) {
+        println!("Revoking token {}", request.jti);
+        let until = self
+            .issued_tokens
+            .get(&request.jti)
+            .copied()
+            .unwrap_or_else(|| Instant::now() + max_token_lifetime);
+        self.revoked_tokens.insert(request.jti, until);
+        self.audit(AuditEvent::TokenRevoked {
+            jti: request.jti,
+            by: request.controller,
+        });
+    }
+
+    fn revocations(&self, now: Instant) -> Vec<Uuid> {
+        self.revoked_tokens
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(jti, _)| *jti)
+            .collect()
+    }
+
+    fn forget_expired_tokens(&mut self, now: Instant) {
+        self.issued_tokens.retain(|_, expires| *expires > now);
+        self.revoked_tokens.retain(|_, until| *until > now);
+    }
+
+    fn reap_expired_devices(&mut self, now: Instant) {
+        let emit_fleet_events = self.emit_fleet_events;
+        self.devices.retain(|cid, device| {
+            let alive = device.valid_until > now;
+            if !alive {
+                println!("Device {cid} ({}) expired", device.label);
+                if emit_fleet_events {
+                    fleet_events::emit(&FleetEvent::Expired {
+                        cid: *cid,
+                        label: &device.label,
+                    });
+                }
+            }
+            alive
+        });
+    }
 }
 
-pub async fn run_state_loop(
-    mut channel: Receiver<Request>,
+// Responses to token requests that carried an idempotency key are kept this long, so that a
+// retry after a lost response gets the same tokens instead of new ones
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(60);
+
+struct RecentResponse {
+    request: ControlTokenRequest,
+    response: ControlTokenResponse,
+    issued_at: Instant,
+}
+
+struct TokenIssuer {
+    jwt_key: EncodingKey,
     acl: AclDatabase,
-    private_key: KeyPair,
     my_cid: Uuid,
+    controller_subjects: HashMap<Uuid, String>,
+    rate_limiter: Option<RateLimiter>,
+    max_devices_per_request: Option<usize>,
+    max_token_lifetime_secs: u64,
+    label_audience: bool,
+    narrow_scopes: bool,
+    // Keyed by controller CID and idempotency key
+    recent_responses: HashMap<(Uuid, String), RecentResponse>,
+}
+
+impl TokenIssuer {
+    fn handle_request(&mut self, state: &mut State, request: &ControlTokenRequest) -> Response {
+        let _span = info_span!("issue_token", controller = %request.cid).entered();
+        println!("Control token request received from {}", request.cid);
+
+        let now = Instant::now();
+        self.evict_stale_responses(now);
+        let cache_key = request
+            .idempotency_key
+            .as_ref()
+            .map(|key| (request.cid, key.clone()));
+        if let Some(recent) = cache_key
+            .as_ref()
+            .and_then(|key| self.recent_responses.get(key))
+        {
+            if recent.request != *request {
+                return Response::Error(HandlingError::bad_request(
+                    "Idempotency key was already used for a different request",
+                ));
+            }
+            println!("Returning previously issued tokens for repeated request");
+            return Response::ControlTokenResponse(recent.response.clone());
+        }
+
+        let budget = match self
+            .rate_limiter
+            .as_mut()
+            .map(|limiter| limiter.check(request.cid, now))
+            .transpose()
+        {
+            Ok(budget) => budget,
+            Err(budget) => {
+                println!("Controller {} exceeded its token rate limit", request.cid);
+                return Response::Error(HandlingError::with_code(
+                    ResponseType::TooManyRequests,
+                    format!(
+                        "Token rate limit exceeded, resets in {}s",
+                        budget.reset_secs
+                    ),
+                ));
+            }
+        };
+
+        let subject = self
+            .controller_subjects
+            .get(&request.cid)
+            .cloned()
+            .unwrap_or_else(|| request.cid.to_string());
+        match check_advertised_parameters(request, &state.devices)
+            .and_then(|()| token_audiences(request, &state.devices, self.label_audience))
+            .and_then(|audiences| {
+                get_control_token(
+                    request,
+                    &subject,
+                    &self.acl,
+                    &audiences,
+                    self.narrow_scopes,
+                    self.max_devices_per_request,
+                    self.max_token_lifetime_secs,
+                    &self.jwt_key,
+                    &self.my_cid,
+                )
+            }) {
+            Ok(mut token) => {
+                token.rate_limit = budget;
+                for (device, scope) in &token.scopes {
+                    state.issued_tokens.insert(
+                        scope.jti,
+                        now + Duration::from_secs(self.max_token_lifetime_secs),
+                    );
+                    state.audit(AuditEvent::TokenIssued {
+                        controller: request.cid,
+                        subject: &subject,
+                        device: *device,
+                        jti: scope.jti,
+                        params_read: &scope.params_read,
+                        params_write: &scope.params_write,
+                        acl_entries: &scope.acl_entries,
+                        denied_read: &scope.denied_read,
+                        denied_write: &scope.denied_write,
+                    });
+                }
+                if let Some(cache_key) = cache_key {
+                    self.recent_responses.insert(
+                        cache_key,
+                        RecentResponse {
+                            request: request.clone(),
+                            response: token.clone(),
+                            issued_at: now,
+                        },
+                    );
+                }
+                Response::ControlTokenResponse(token)
+            }
+            Err(e) => {
+                println!("Error generating control token: {e}");
+                state.audit(AuditEvent::TokenDenied {
+                    controller: request.cid,
+                    reason: e.to_string(),
+                    acl_decision: e.downcast_ref::<AclDenied>(),
+                });
+                let code = if e.is::<AclDenied>() {
+                    ResponseType::Forbidden
+                } else {
+                    ResponseType::BadRequest
+                };
+                Response::Error(HandlingError::with_code(code, e))
+            }
+        }
+    }
+
+    fn evict_stale_responses(&mut self, now: Instant) {
+        self.recent_responses
+            .retain(|_, recent| now.duration_since(recent.issued_at) < IDEMPOTENCY_WINDOW);
+    }
+
+    // So that a retried request can't get back a token that has since been revoked
+    fn forget_revoked_token(&mut self, jti: Uuid) {
+        self.recent_responses.retain(|_, recent| {
+            !recent
+                .response
+                .scopes
+                .values()
+                .any(|scope| scope.jti == jti)
+        });
+    }
+
+    // Drops per-controller state that no longer affects any response
+    fn sweep(&mut self, now: Instant) {
+        self.evict_stale_responses(now);
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.evict_expired(now);
+        }
+    }
+}
+
+pub async fn run_state_loop(
+    mut channel: RequestReceiver,
+    config: Config,
+    private_key: KeyPair,
+    audit_log: Option<AuditLog>,
+    notifier: Notifier,
 ) {
-    let mut state = State::new();
-    let jwt_key = EncodingKey::from_ec_der(&private_key.serialize_der());
+    let mut state = State::new(audit_log, notifier, config.emit_fleet_events);
+    let port_change_grace = config.port_change_grace_secs.map(Duration::from_secs);
+    let provisioned_devices = config.provisioned_devices;
+    let sign_device_lists = config.sign_device_lists;
+    let mut registration_limiter = config
+        .registration_rate_limit
+        .as_ref()
+        .map(RateLimiter::<IpAddr>::new);
+    if let Some(ref seed_file) = config.seed_devices_file {
+        seed_devices(&mut state, seed_file);
+    }
+    let mut token_issuer = TokenIssuer {
+        jwt_key: EncodingKey::from_ec_der(&private_key.serialize_der()),
+        rate_limiter: config.token_rate_limit.as_ref().map(RateLimiter::new),
+        acl: config.acl,
+        my_cid: config.cid,
+        controller_subjects: config.controller_subjects,
+        max_devices_per_request: config.max_devices_per_token_request,
+        max_token_lifetime_secs: config.max_token_lifetime_secs,
+        label_audience: config.label_audience,
+        narrow_scopes: config.narrow_token_scopes,
+        recent_responses: HashMap::new(),
+    };
+
+    let mut sweep = tokio::time::interval(Duration::from_secs(config.sweep_interval_secs.max(1)));
+    loop {
+        let request = tokio::select! {
+            request = channel.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            _ = sweep.tick() => {
+                let now = Instant::now();
+                state.reap_expired_devices(now);
+                state.forget_expired_tokens(now);
+                token_issuer.sweep(now);
+                if let Some(ref mut limiter) = registration_limiter {
+                    limiter.evict_expired(now);
+                }
+                continue;
+            }
+        };
 
-    while let Some(request) = channel.recv().await {
         let response = match request.get_type() {
-            RequestType::Register(request) => {
+            RequestType::Register(request, Some(source), _)
+                if registration_limiter
+                    .as_mut()
+                    .is_some_and(|limiter| limiter.check(*source, Instant::now()).is_err()) =>
+            {
+                println!(
+                    "Rejecting registration of {} from {source}, too many registrations from \
+                     that address",
+                    request.cid
+                );
+                Response::Error(HandlingError::with_code(
+                    ResponseType::TooManyRequests,
+                    "Too many registrations from this address, try again later",
+                ))
+            }
+            RequestType::Register(request, _, _)
+                if provisioned_devices
+                    .as_ref()
+                    .is_some_and(|provisioned| !provisioned.contains(&request.cid)) =>
+            {
+                println!(
+                    "Rejecting registration from unprovisioned device {}",
+                    request.cid
+                );
+                Response::Error(HandlingError::with_code(
+                    ResponseType::Forbidden,
+                    "Device is not provisioned",
+                ))
+            }
+            RequestType::Register(request, _, renewal_key) => {
                 println!("Register request received: {:?}", request);
 
-                match register_device(&mut state, request) {
-                    Ok(()) => Response::Ok,
+                match register_device(
+                    &mut state,
+                    request,
+                    renewal_key.as_deref(),
+                    port_change_grace,
+                ) {
+                    Ok(registration_key) => {
+                        state.audit(AuditEvent::DeviceRegistered {
+                            cid: request.cid,
+                            port: request.port,
+                        });
+                        state.fleet_event(FleetEvent::Registered {
+                            cid: request.cid,
+                            label: &request.label,
+                            port: request.port,
+                            tags: &request.tags,
+                        });
+                        state.notifier.notify(WebhookEvent::DeviceRegistered {
+                            cid: request.cid,
+                            label: request.label.clone(),
+                            port: request.port,
+                        });
+                        Response::Registered(RegistrationResponse { registration_key })
+                    }
                     Err(e) => Response::Error(HandlingError::bad_request(e)),
                 }
             }
-            RequestType::List => Response::ListResponse(list_devices(&state)),
-            RequestType::ControlToken(request) => {
-                println!("Control token request received from {}", request.cid);
-                match get_control_token(request, &acl, &jwt_key, &my_cid) {
-                    Ok(token) => Response::ControlTokenResponse(token),
-                    Err(e) => {
-                        println!("Error generating control token: {e}");
-                        Response::Error(HandlingError::bad_request(e))
-                    }
+            RequestType::Deregister(request) => deregister_device(&mut state, request),
+            RequestType::List(filter) => {
+                let mut list = list_devices(&state, filter);
+                if sign_device_lists {
+                    list.signature = Some(
+                        jsonwebtoken::crypto::sign(
+                            &list.payload(),
+                            &token_issuer.jwt_key,
+                            Algorithm::ES256,
+                        )
+                        .unwrap(),
+                    );
                 }
+                Response::ListResponse(list)
+            }
+            RequestType::ControlToken(request) => token_issuer.handle_request(&mut state, request),
+            RequestType::ProxyRead(request) => {
+                proxy_read_target(&mut state, &mut token_issuer, request)
+            }
+            RequestType::Revoke(request) => {
+                state.revoke_token(
+                    request,
+                    Duration::from_secs(token_issuer.max_token_lifetime_secs),
+                );
+                token_issuer.forget_revoked_token(request.jti);
+                Response::Ok
             }
+            RequestType::Revocations => Response::Revocations(state.revocations(Instant::now())),
             RequestType::Shutdown => Response::Ok,
         };
 
-        let _ = request.respond(response);
+        deliver(&mut state, request, response);
+    }
+}
+
+// Counts and logs responses whose requester has gone away
+fn deliver(state: &mut State, request: Request, response: Response) {
+    let request_name = request.get_type().name();
+    if request.respond(response).is_err() {
+        state.dead_letters += 1;
+        warn!(
+            "Dropped response to {request_name} request, requester is gone ({} so far)",
+            state.dead_letters
+        );
+    }
+}
+
+fn proxy_read_target(
+    state: &mut State,
+    token_issuer: &mut TokenIssuer,
+    request: &ProxyReadRequest,
+) -> Response {
+    println!(
+        "Proxy read of {} on {} requested by {}",
+        request.parameter, request.device, request.controller
+    );
+    let Some(port) = state.devices.get(&request.device).map(|device| device.port) else {
+        return Response::Error(HandlingError::not_found());
+    };
+
+    let token_request = ControlTokenRequest {
+        cid: request.controller,
+        devices: vec![request.device],
+        params_read: vec![request.parameter.clone()],
+        params_write: vec![],
+        idempotency_key: None,
+        expires_in_secs: None,
+    };
+    match token_issuer.handle_request(state, &token_request) {
+        Response::ControlTokenResponse(mut response) => Response::ProxyTarget(ProxyTarget {
+            port,
+            parameter: request.parameter.clone(),
+            token: response.tokens.remove(&request.device).unwrap(),
+        }),
+        other => other,
+    }
+}
+
+fn seed_devices(state: &mut State, seed_file: &str) {
+    let devices = std::fs::read(seed_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(serde_json::from_slice::<Vec<ApiDevice>>(&contents)?));
+    let devices = match devices {
+        Ok(devices) => devices,
+        Err(e) => {
+            println!("Couldn't load seed devices from {seed_file}: {e}");
+            return;
+        }
+    };
+
+    for device in &devices {
+        if let Err(e) = register_device(state, device, None, None) {
+            println!("Couldn't seed device {}: {e}", device.cid);
+        }
     }
+    println!("Seeded {} devices from {seed_file}", state.devices.len());
 }
 
-fn register_device(state: &mut State, device: &ApiDevice) -> anyhow::Result<()> {
+// Returns the registration's key, which stays the same when a device renews its registration
+// with it
+fn register_device(
+    state: &mut State,
+    device: &ApiDevice,
+    renewal_key: Option<&str>,
+    port_change_grace: Option<Duration>,
+) -> anyhow::Result<String> {
+    let now = Instant::now();
+    // All devices are reached on the same host, so a port shared with another device means one of
+    // them is misconfigured and controllers would end up talking to the wrong one
+    if let Some((other, _)) = state.devices.iter().find(|(cid, other)| {
+        **cid != device.cid && other.port == device.port && other.valid_until > now
+    }) {
+        anyhow::bail!(
+            "Port {} is already registered by device {other}",
+            device.port
+        );
+    }
+
+    let new_device = Device {
+        label: device.label.clone(),
+        manufacturer: device.manufacturer.clone(),
+        model: device.model.clone(),
+        port: device.port,
+        previous_port: None,
+        tags: device.tags.clone(),
+        parameters: device.parameters.clone(),
+        encryption_key: device.encryption_key.clone(),
+        registration_key: Uuid::new_v4().simple().to_string(),
+        valid_until: now + Duration::from_secs(device.ttl),
+    };
+
     match state.devices.entry(device.cid) {
-        Entry::Occupied(_) => Err(anyhow::anyhow!("A device with this CID already exists")),
-        std::collections::hash_map::Entry::Vacant(entry) => {
+        // A registration that expired but hasn't been swept yet is as good as gone, so that a
+        // device restarting after letting it lapse can register again straight away
+        Entry::Occupied(mut entry) if entry.get().valid_until <= now => {
+            let registration_key = new_device.registration_key.clone();
+            entry.insert(new_device);
+            Ok(registration_key)
+        }
+        Entry::Occupied(mut entry) => {
+            let old = entry.get();
+            // Other re-registrations are only accepted when a grace period is configured
+            let renewing = renewal_key.is_some_and(|key| key == old.registration_key);
+            if !renewing && port_change_grace.is_none() {
+                anyhow::bail!("A device with this CID already exists");
+            }
+            let previous_port = match port_change_grace {
+                Some(grace) if old.port != device.port => Some((old.port, now + grace)),
+                _ => old.previous_port,
+            };
+            let registration_key = if renewing {
+                old.registration_key.clone()
+            } else {
+                new_device.registration_key.clone()
+            };
             entry.insert(Device {
-                label: device.label.clone(),
-                manufacturer: device.manufacturer.clone(),
-                model: device.model.clone(),
-                port: device.port,
-                valid_until: Instant::now() + std::time::Duration::from_secs(device.ttl),
+                previous_port,
+                registration_key: registration_key.clone(),
+                ..new_device
             });
-            Ok(())
+            Ok(registration_key)
+        }
+        Entry::Vacant(entry) => {
+            let registration_key = new_device.registration_key.clone();
+            entry.insert(new_device);
+            Ok(registration_key)
         }
     }
 }
 
-fn list_devices(state: &State) -> ListResponse {
+// Only the device itself may deregister, which it shows with the key it got when registering
+fn deregister_device(state: &mut State, request: &DeregisterRequest) -> Response {
+    let cid = request.cid;
+    let Some(device) = state.devices.get(&cid) else {
+        return Response::Error(HandlingError::not_found());
+    };
+    if request.registration_key.as_ref() != Some(&device.registration_key) {
+        println!("Refusing to deregister device {cid}, the registration key doesn't match");
+        return Response::Error(HandlingError::with_code(
+            ResponseType::Forbidden,
+            "Registration key doesn't match the device's",
+        ));
+    }
+
+    let device = state.devices.remove(&cid).unwrap();
+    println!("Device {cid} ({}) deregistered", device.label);
+    state.audit(AuditEvent::DeviceDeregistered { cid });
+    state.fleet_event(FleetEvent::Deregistered {
+        cid,
+        label: &device.label,
+    });
+    state.notifier.notify(WebhookEvent::DeviceDeregistered {
+        cid,
+        label: device.label,
+    });
+    Response::Ok
+}
+
+fn list_devices(state: &State, filter: &ListFilter) -> ListResponse {
+    let now = Instant::now();
     ListResponse {
         devices: state
             .devices
             .iter()
+            // Devices that expired since the last sweep are left out without waiting for it
+            .filter(|(_, device)| device.valid_until > now)
+            .filter(|(_, device)| {
+                filter
+                    .tag
+                    .as_ref()
+                    .is_none_or(|tag| device.tags.contains(tag))
+            })
             .map(|(cid, device)| ApiDevice {
                 cid: *cid,
                 label: device.label.clone(),
                 manufacturer: device.manufacturer.clone(),
                 model: device.model.clone(),
                 port: device.port,
-                ttl: device.valid_until.duration_since(Instant::now()).as_secs(),
+                previous_port: device
+                    .previous_port
+                    .filter(|(_, until)| now < *until)
+                    .map(|(port, _)| port),
+                ttl: device.valid_until.duration_since(now).as_secs(),
+                tags: device.tags.clone(),
+                parameters: device.parameters.clone(),
+                encryption_key: device.encryption_key.clone(),
             })
             .collect(),
+        signature: None,
     }
 }
 
@@ -114,40 +640,177 @@ struct JwtClaims {
     sub: String,
     aud: String,
     exp: u64,
+    iat: u64,
+    // Unique per token, for revoking it
+    jti: Uuid,
     params_read: Vec<String>,
     params_write: Vec<String>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+// Extra claims from the ACL can't override these
+const RESERVED_CLAIMS: &[&str] = &[
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "iat",
+    "jti",
+    "params_read",
+    "params_write",
+];
+
+// The aud claim for each requested device's token
+fn token_audiences(
+    request: &ControlTokenRequest,
+    devices: &HashMap<Uuid, Device>,
+    label_audience: bool,
+) -> anyhow::Result<HashMap<Uuid, String>> {
+    request
+        .devices
+        .iter()
+        .map(|cid| {
+            if !label_audience {
+                return Ok((*cid, cid.to_string()));
+            }
+            match devices.get(cid) {
+                Some(device) => Ok((*cid, device.label.clone())),
+                None => anyhow::bail!("Device {cid} isn't registered, so its label is unknown"),
+            }
+        })
+        .collect()
 }
 
+#[allow(clippy::too_many_arguments)]
 fn get_control_token(
     request: &ControlTokenRequest,
+    subject: &str,
     acl: &AclDatabase,
+    audiences: &HashMap<Uuid, String>,
+    narrow_scopes: bool,
+    max_devices: Option<usize>,
+    max_lifetime_secs: u64,
     jwt_key: &EncodingKey,
     arb_cid: &Uuid,
 ) -> anyhow::Result<ControlTokenResponse> {
-    if !validate_request_with_acl(request, acl) {
-        anyhow::bail!("Request not valid with ACL");
+    if let Some(max_devices) = max_devices {
+        if request.devices.len() > max_devices {
+            anyhow::bail!(
+                "Request covers {} devices, at most {max_devices} are allowed per request",
+                request.devices.len()
+            );
+        }
+    }
+
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // Every device is checked before any token is minted
+    let mut grants = HashMap::new();
+    for device in &request.devices {
+        let Some(grant) = acl.grant(&request.cid, device, now) else {
+            return Err(AclDenied {
+                device: *device,
+                acl_entries: vec![],
+                denied_read: request.params_read.clone(),
+                denied_write: request.params_write.clone(),
+            }
+            .into());
+        };
+        let (params_read, denied_read): (Vec<_>, Vec<_>) = request
+            .params_read
+            .iter()
+            .cloned()
+            .partition(|param| grant.read.contains(param));
+        let (params_write, denied_write): (Vec<_>, Vec<_>) = request
+            .params_write
+            .iter()
+            .cloned()
+            .partition(|param| grant.write.contains(param));
+
+        if !denied_read.is_empty() || !denied_write.is_empty() {
+            let requested_any = !request.params_read.is_empty() || !request.params_write.is_empty();
+            if !narrow_scopes
+                || (requested_any && params_read.is_empty() && params_write.is_empty())
+            {
+                return Err(AclDenied {
+                    device: *device,
+                    acl_entries: grant.entries,
+                    denied_read,
+                    denied_write,
+                }
+                .into());
+            }
+            println!(
+                "Leaving denied parameters out of the token for {device}: read {denied_read:?}, \
+                 write {denied_write:?}"
+            );
+        }
+
+        let extra_claims: serde_json::Map<_, _> = grant
+            .extra_claims
+            .into_iter()
+            .filter(|(name, _)| {
+                let reserved = RESERVED_CLAIMS.contains(&name.as_str());
+                if reserved {
+                    println!("Ignoring extra claim {name} from the ACL, it is a reserved claim");
+                }
+                !reserved
+            })
+            .collect();
+        grants.insert(
+            *device,
+            (
+                TokenScope {
+                    jti: Uuid::new_v4(),
+                    params_read,
+                    params_write,
+                    acl_entries: grant.entries,
+                    denied_read,
+                    denied_write,
+                },
+                extra_claims,
+            ),
+        );
     }
 
+    let lifetime_secs = match request.expires_in_secs {
+        Some(requested) if requested > max_lifetime_secs => {
+            println!(
+                "Requested token lifetime of {requested}s exceeds the maximum, using {max_lifetime_secs}s"
+            );
+            max_lifetime_secs
+        }
+        Some(requested) => requested,
+        None => max_lifetime_secs,
+    };
+
     let header = Header::new(Algorithm::ES256);
     let mut response = ControlTokenResponse {
         tokens: Default::default(),
+        rate_limit: None,
+        scopes: Default::default(),
     };
 
-    for device in &request.devices {
+    for (device, (scope, extra_claims)) in grants {
         let claims = JwtClaims {
             iss: arb_cid.to_string(),
-            sub: request.cid.to_string(),
-            aud: device.to_string(),
-            exp: (time::SystemTime::now() + time::Duration::from_secs(6000))
-                .duration_since(time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            params_read: request.params_read.clone(),
-            params_write: request.params_write.clone(),
+            sub: subject.to_string(),
+            aud: audiences[&device].clone(),
+            exp: now + lifetime_secs,
+            iat: now,
+            jti: scope.jti,
+            params_read: scope.params_read.clone(),
+            params_write: scope.params_write.clone(),
+            extra: extra_claims,
         };
 
         let token = jsonwebtoken::encode(&header, &claims, jwt_key)?;
-        response.tokens.insert(device.clone(), token);
+        response.tokens.insert(device, token);
+        response.scopes.insert(device, scope);
         println!(
             "Generating token: {}",
             serde_json::to_string_pretty(&claims).unwrap()
@@ -157,24 +820,797 @@ fn get_control_token(
     Ok(response)
 }
 
-fn validate_request_with_acl(request: &ControlTokenRequest, acl: &AclDatabase) -> bool {
-    for entry in &acl.entries {
-        if entry.controller_cids.contains(&request.cid)
-            && request
-                .devices
-                .iter()
-                .all(|dev| entry.device_cids.contains(dev))
-            && request
-                .params_read
-                .iter()
-                .all(|param| entry.parameters.read.contains(param))
-            && request
-                .params_write
-                .iter()
-                .all(|param| entry.parameters.write.contains(param))
+// Catches requests for parameters a device doesn't have before a token is minted for them.
+// Devices that didn't say which parameters they have are taken on trust.
+fn check_advertised_parameters(
+    request: &ControlTokenRequest,
+    devices: &HashMap<Uuid, Device>,
+) -> anyhow::Result<()> {
+    for cid in &request.devices {
+        let Some(advertised) = devices
+            .get(cid)
+            .and_then(|device| device.parameters.as_ref())
+        else {
+            continue;
+        };
+        if let Some(unknown) = request
+            .params_read
+            .iter()
+            .chain(&request.params_write)
+            // Device pseudo-parameters such as _params aren't advertised
+            .filter(|param| !param.starts_with('_'))
+            .find(|param| !advertised.contains(param))
         {
-            return true;
+            anyhow::bail!("Device {cid} has no parameter {unknown}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use jsonwebtoken::{DecodingKey, Validation};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::{AuditLogConfig, RateLimitConfig},
+        queue::{request_queue, Priority},
+    };
+
+    const CONTROLLER: Uuid = Uuid::from_u128(0xc1);
+    const OTHER_CONTROLLER: Uuid = Uuid::from_u128(0xc2);
+    const DEVICE: Uuid = Uuid::from_u128(0xd1);
+
+    fn state() -> State {
+        State::new(None, Notifier::new(None).unwrap(), false)
+    }
+
+    fn token_issuer() -> TokenIssuer {
+        let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        TokenIssuer {
+            jwt_key: EncodingKey::from_ec_der(&key_pair.serialize_der()),
+            acl: serde_json::from_value(json!({
+                "entries": [{
+                    "controllerCids": [CONTROLLER, OTHER_CONTROLLER],
+                    "deviceCids": [DEVICE],
+                    "parameters": { "read": ["temp"], "write": ["temp"] },
+                }],
+            }))
+            .unwrap(),
+            my_cid: Uuid::from_u128(0xa1),
+            controller_subjects: HashMap::new(),
+            rate_limiter: None,
+            max_devices_per_request: None,
+            max_token_lifetime_secs: 3600,
+            label_audience: false,
+            narrow_scopes: false,
+            recent_responses: HashMap::new(),
+        }
+    }
+
+    fn token_request(controller: Uuid) -> ControlTokenRequest {
+        ControlTokenRequest {
+            cid: controller,
+            devices: vec![DEVICE],
+            params_read: vec!["temp".to_string()],
+            params_write: vec![],
+            idempotency_key: None,
+            expires_in_secs: None,
+        }
+    }
+
+    fn issue(
+        token_issuer: &mut TokenIssuer,
+        state: &mut State,
+        request: &ControlTokenRequest,
+    ) -> ControlTokenResponse {
+        match token_issuer.handle_request(state, request) {
+            Response::ControlTokenResponse(response) => response,
+            Response::Error(e) => panic!("No token issued: {e:?}"),
+            _ => panic!("No token issued"),
+        }
+    }
+
+    #[test]
+    fn revoked_token_is_listed_and_not_handed_out_again() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = ControlTokenRequest {
+            idempotency_key: Some("retry-1".to_string()),
+            ..token_request(CONTROLLER)
+        };
+        let jti = issue(&mut token_issuer, &mut state, &request).scopes[&DEVICE].jti;
+
+        // Any controller can revoke it, see revoke_token
+        let revoke = RevokeRequest {
+            controller: OTHER_CONTROLLER,
+            jti,
+        };
+        state.revoke_token(&revoke, Duration::from_secs(3600));
+        token_issuer.forget_revoked_token(jti);
+        assert_eq!(state.revocations(Instant::now()), vec![jti]);
+
+        let retried = issue(&mut token_issuer, &mut state, &request);
+        assert_ne!(retried.scopes[&DEVICE].jti, jti);
+    }
+
+    #[test]
+    fn revocations_are_forgotten_once_the_token_expires() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let jti =
+            issue(&mut token_issuer, &mut state, &token_request(CONTROLLER)).scopes[&DEVICE].jti;
+        let request = RevokeRequest {
+            controller: CONTROLLER,
+            jti,
+        };
+        state.revoke_token(&request, Duration::from_secs(3600));
+
+        let later = Instant::now() + Duration::from_secs(3601);
+        assert!(state.revocations(later).is_empty());
+        state.forget_expired_tokens(later);
+        assert!(state.issued_tokens.is_empty());
+        assert!(state.revoked_tokens.is_empty());
+    }
+
+    fn api_device(port: u16) -> ApiDevice {
+        ApiDevice {
+            cid: DEVICE,
+            label: "test".to_string(),
+            manufacturer: "ACME".to_string(),
+            model: "Test".to_string(),
+            port,
+            previous_port: None,
+            ttl: 3600,
+            tags: vec![],
+            parameters: None,
+            encryption_key: None,
+        }
+    }
+
+    #[test]
+    fn deregistering_needs_the_registration_key() {
+        let mut state = state();
+        let registration_key = register_device(&mut state, &api_device(47111), None, None).unwrap();
+
+        for wrong_key in [None, Some("wrong".to_string())] {
+            let request = DeregisterRequest {
+                cid: DEVICE,
+                registration_key: wrong_key,
+            };
+            match deregister_device(&mut state, &request) {
+                Response::Error(e) => assert_eq!(e.code, Some(ResponseType::Forbidden)),
+                _ => panic!("Deregistered without the registration key"),
+            }
+            assert!(state.devices.contains_key(&DEVICE));
+        }
+
+        let request = DeregisterRequest {
+            cid: DEVICE,
+            registration_key: Some(registration_key),
+        };
+        assert!(matches!(
+            deregister_device(&mut state, &request),
+            Response::Ok
+        ));
+        assert!(state.devices.is_empty());
+    }
+
+    #[test]
+    fn registration_is_renewed_with_its_key() {
+        let mut state = state();
+        let registration_key = register_device(&mut state, &api_device(47111), None, None).unwrap();
+
+        assert!(register_device(&mut state, &api_device(47111), None, None).is_err());
+        assert!(register_device(&mut state, &api_device(47111), Some("wrong"), None).is_err());
+
+        let renewed_key = register_device(
+            &mut state,
+            &api_device(47112),
+            Some(&registration_key),
+            None,
+        )
+        .unwrap();
+        assert_eq!(renewed_key, registration_key);
+        let device = &state.devices[&DEVICE];
+        assert_eq!(device.port, 47112);
+        // Without a grace period the old port isn't reported
+        assert_eq!(device.previous_port, None);
+    }
+
+    #[test]
+    fn repeated_request_with_an_idempotency_key_gets_the_same_tokens() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = ControlTokenRequest {
+            idempotency_key: Some("retry-1".to_string()),
+            ..token_request(CONTROLLER)
+        };
+
+        let first = issue(&mut token_issuer, &mut state, &request);
+        let second = issue(&mut token_issuer, &mut state, &request);
+        assert_eq!(first.tokens, second.tokens);
+        assert_eq!(state.issued_tokens.len(), 1);
+
+        // A different key, or no key at all, mints a new token
+        let other_key = ControlTokenRequest {
+            idempotency_key: Some("retry-2".to_string()),
+            ..request.clone()
+        };
+        assert_ne!(
+            issue(&mut token_issuer, &mut state, &other_key).tokens,
+            first.tokens
+        );
+        assert_ne!(
+            issue(&mut token_issuer, &mut state, &token_request(CONTROLLER)).tokens,
+            first.tokens
+        );
+    }
+
+    #[test]
+    fn idempotency_key_cant_be_reused_for_a_different_request() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = ControlTokenRequest {
+            idempotency_key: Some("retry-1".to_string()),
+            ..token_request(CONTROLLER)
+        };
+        issue(&mut token_issuer, &mut state, &request);
+
+        let different = ControlTokenRequest {
+            params_write: vec!["temp".to_string()],
+            ..request
+        };
+        match token_issuer.handle_request(&mut state, &different) {
+            Response::Error(e) => assert_eq!(e.code, Some(ResponseType::BadRequest)),
+            _ => panic!("Reused idempotency key was accepted"),
+        }
+    }
+
+    fn token_error(
+        token_issuer: &mut TokenIssuer,
+        state: &mut State,
+        request: &ControlTokenRequest,
+    ) -> HandlingError {
+        match token_issuer.handle_request(state, request) {
+            Response::Error(e) => e,
+            _ => panic!("Token was issued"),
+        }
+    }
+
+    #[test]
+    fn partially_allowed_request_is_refused_unless_scopes_are_narrowed() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = ControlTokenRequest {
+            params_read: vec!["temp".to_string(), "secret".to_string()],
+            ..token_request(CONTROLLER)
+        };
+        let e = token_error(&mut token_issuer, &mut state, &request);
+        assert_eq!(e.code, Some(ResponseType::Forbidden));
+
+        token_issuer.narrow_scopes = true;
+        let scope = &issue(&mut token_issuer, &mut state, &request).scopes[&DEVICE];
+        assert_eq!(scope.params_read, vec!["temp".to_string()]);
+        assert_eq!(scope.denied_read, vec!["secret".to_string()]);
+
+        // Narrowing never hands out a token that grants none of what was asked for
+        let nothing_allowed = ControlTokenRequest {
+            params_read: vec!["secret".to_string()],
+            ..token_request(CONTROLLER)
+        };
+        let e = token_error(&mut token_issuer, &mut state, &nothing_allowed);
+        assert_eq!(e.code, Some(ResponseType::Forbidden));
+    }
+
+    #[test]
+    fn request_no_entry_covers_is_forbidden() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = token_request(Uuid::from_u128(0xc3));
+        let e = token_error(&mut token_issuer, &mut state, &request);
+        assert_eq!(e.code, Some(ResponseType::Forbidden));
+        assert!(state.issued_tokens.is_empty());
+    }
+
+    #[test]
+    fn expired_registration_leaves_the_listing_and_frees_the_cid() {
+        let mut state = state();
+        let device = ApiDevice {
+            ttl: 1,
+            ..api_device(47111)
+        };
+        let first_key = register_device(&mut state, &device, None, None).unwrap();
+        assert_eq!(
+            list_devices(&state, &ListFilter { tag: None })
+                .devices
+                .len(),
+            1
+        );
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(list_devices(&state, &ListFilter { tag: None })
+            .devices
+            .is_empty());
+
+        // Not swept yet, but the device may register again without its old key
+        let second_key = register_device(&mut state, &api_device(47111), None, None).unwrap();
+        assert_ne!(first_key, second_key);
+        assert_eq!(
+            list_devices(&state, &ListFilter { tag: None })
+                .devices
+                .len(),
+            1
+        );
+    }
+
+    // A state that audits to a file of its own
+    fn audited_state() -> (State, PathBuf) {
+        let file = std::env::temp_dir().join(format!("arbiter-audit-{}.log", Uuid::new_v4()));
+        let audit_log = AuditLog::open(&AuditLogConfig {
+            file: file.to_str().unwrap().to_string(),
+            max_bytes: None,
+            daily: false,
+            max_archives: 0,
+        })
+        .unwrap();
+        let state = State::new(Some(audit_log), Notifier::new(None).unwrap(), false);
+        (state, file)
+    }
+
+    // The records written since `file` was opened, after which it is removed
+    fn audit_records(file: PathBuf) -> Vec<serde_json::Value> {
+        let records = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&file).unwrap();
+        records
+    }
+
+    #[test]
+    fn deregistration_is_audited() {
+        let (mut state, file) = audited_state();
+        let registration_key = register_device(&mut state, &api_device(47111), None, None).unwrap();
+
+        let request = DeregisterRequest {
+            cid: DEVICE,
+            registration_key: Some(registration_key),
+        };
+        deregister_device(&mut state, &request);
+        let records = audit_records(file);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["event"], "deviceDeregistered");
+        assert_eq!(records[0]["cid"], DEVICE.to_string());
+    }
+
+    // The claims of an issued token, without checking its signature
+    fn token_claims(token: &str) -> serde_json::Value {
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.insecure_disable_signature_validation();
+        validation.validate_aud = false;
+        jsonwebtoken::decode(token, &DecodingKey::from_secret(&[]), &validation)
+            .unwrap()
+            .claims
+    }
+
+    #[test]
+    fn label_audience_addresses_tokens_to_the_device_label() {
+        let mut state = state();
+        let mut token_issuer = TokenIssuer {
+            label_audience: true,
+            ..token_issuer()
+        };
+
+        // The label is only known once the device has registered
+        token_error(&mut token_issuer, &mut state, &token_request(CONTROLLER));
+
+        register_device(&mut state, &api_device(47111), None, None).unwrap();
+        let token = issue(&mut token_issuer, &mut state, &token_request(CONTROLLER)).tokens
+            [&DEVICE]
+            .clone();
+        assert_eq!(token_claims(&token)["aud"], "test");
+    }
+
+    // Registers the device through the state loop, so that the checks made there apply
+    async fn register_through_state_loop(config: serde_json::Value, device: ApiDevice) -> Response {
+        registrations_through_state_loop(config, vec![(device, None)])
+            .await
+            .remove(0)
+    }
+
+    // Sends each registration, along with its source address, to one state loop in turn
+    async fn registrations_through_state_loop(
+        config: serde_json::Value,
+        registrations: Vec<(ApiDevice, Option<IpAddr>)>,
+    ) -> Vec<Response> {
+        let mut fields = json!({ "cid": Uuid::from_u128(0xa1) });
+        fields
+            .as_object_mut()
+            .unwrap()
+            .extend(config.as_object().unwrap().clone());
+        let config: Config = serde_json::from_value(fields).unwrap();
+        let (tx, rx) = request_queue(1);
+        let state_loop = tokio::spawn(run_state_loop(
+            rx,
+            config,
+            KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap(),
+            None,
+            Notifier::new(None).unwrap(),
+        ));
+
+        let mut responses = vec![];
+        for (device, source) in registrations {
+            let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+            let request =
+                Request::synchronous(RequestType::Register(device, source, None), resp_tx);
+            tx.send(request, Priority::Low).await.unwrap();
+            responses.push(resp_rx.await.unwrap());
+        }
+        drop(tx);
+        state_loop.await.unwrap();
+        responses
+    }
+
+    #[tokio::test]
+    async fn only_provisioned_devices_may_register() {
+        let config = json!({ "provisionedDevices": [DEVICE] });
+        let response = register_through_state_loop(config.clone(), api_device(47111)).await;
+        assert!(matches!(response, Response::Registered(_)));
+
+        let unknown = ApiDevice {
+            cid: Uuid::from_u128(0xd2),
+            ..api_device(47112)
+        };
+        match register_through_state_loop(config, unknown).await {
+            Response::Error(e) => assert_eq!(e.code, Some(ResponseType::Forbidden)),
+            _ => panic!("Unprovisioned device was registered"),
+        }
+    }
+
+    #[test]
+    fn port_of_another_registered_device_is_refused() {
+        let mut state = state();
+        let registration_key = register_device(&mut state, &api_device(47111), None, None).unwrap();
+
+        let other = ApiDevice {
+            cid: Uuid::from_u128(0xd2),
+            ..api_device(47111)
+        };
+        let e = register_device(&mut state, &other, None, None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("Port 47111 is already registered by device {DEVICE}")
+        );
+        assert!(!state.devices.contains_key(&other.cid));
+
+        // The device holding the port may keep it
+        register_device(
+            &mut state,
+            &api_device(47111),
+            Some(&registration_key),
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn request_for_more_devices_than_the_cap_is_refused() {
+        let mut state = state();
+        let other_device = Uuid::from_u128(0xd2);
+        let mut token_issuer = TokenIssuer {
+            acl: serde_json::from_value(json!({
+                "entries": [{
+                    "controllerCids": [CONTROLLER],
+                    "deviceCids": [DEVICE, other_device],
+                    "parameters": { "read": ["temp"], "write": [] },
+                }],
+            }))
+            .unwrap(),
+            max_devices_per_request: Some(1),
+            ..token_issuer()
+        };
+
+        issue(&mut token_issuer, &mut state, &token_request(CONTROLLER));
+
+        let request = ControlTokenRequest {
+            devices: vec![DEVICE, other_device],
+            ..token_request(CONTROLLER)
+        };
+        let e = token_error(&mut token_issuer, &mut state, &request);
+        assert_eq!(e.code, Some(ResponseType::BadRequest));
+        assert_eq!(
+            e.message,
+            "Request covers 2 devices, at most 1 are allowed per request"
+        );
+    }
+
+    #[test]
+    fn requested_lifetime_is_honored_up_to_the_maximum() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let lifetime = |token_issuer: &mut TokenIssuer, state: &mut State, requested| {
+            let request = ControlTokenRequest {
+                expires_in_secs: Some(requested),
+                ..token_request(CONTROLLER)
+            };
+            let claims = token_claims(&issue(token_issuer, state, &request).tokens[&DEVICE]);
+            claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap()
+        };
+
+        assert_eq!(lifetime(&mut token_issuer, &mut state, 60), 60);
+        assert_eq!(lifetime(&mut token_issuer, &mut state, 86400), 3600);
+    }
+
+    #[test]
+    fn listing_is_filtered_by_tag() {
+        let mut state = state();
+        let hvac = ApiDevice {
+            tags: vec!["floor-2".to_string(), "hvac".to_string()],
+            ..api_device(47111)
+        };
+        let lighting = ApiDevice {
+            cid: Uuid::from_u128(0xd2),
+            tags: vec!["floor-2".to_string()],
+            ..api_device(47112)
+        };
+        register_device(&mut state, &hvac, None, None).unwrap();
+        register_device(&mut state, &lighting, None, None).unwrap();
+
+        let listed = |tag: Option<&str>| {
+            let mut cids: Vec<_> = list_devices(
+                &state,
+                &ListFilter {
+                    tag: tag.map(str::to_string),
+                },
+            )
+            .devices
+            .iter()
+            .map(|device| device.cid)
+            .collect();
+            cids.sort();
+            cids
+        };
+        assert_eq!(listed(Some("hvac")), vec![DEVICE]);
+        assert_eq!(listed(Some("floor-2")), vec![DEVICE, lighting.cid]);
+        assert_eq!(listed(None), vec![DEVICE, lighting.cid]);
+        assert!(listed(Some("roof")).is_empty());
+    }
+
+    #[test]
+    fn response_to_a_requester_that_is_gone_is_counted() {
+        let mut state = state();
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        deliver(
+            &mut state,
+            Request::synchronous(RequestType::Revocations, resp_tx),
+            Response::Ok,
+        );
+        assert!(resp_rx.blocking_recv().is_ok());
+        assert_eq!(state.dead_letters, 0);
+
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        drop(resp_rx);
+        deliver(
+            &mut state,
+            Request::synchronous(RequestType::Revocations, resp_tx),
+            Response::Ok,
+        );
+        assert_eq!(state.dead_letters, 1);
+    }
+
+    #[test]
+    fn reported_budget_shrinks_with_each_token_request() {
+        let mut state = state();
+        let mut token_issuer = TokenIssuer {
+            rate_limiter: Some(RateLimiter::new(&RateLimitConfig {
+                max_requests: 2,
+                window_secs: 60,
+            })),
+            ..token_issuer()
+        };
+        let mut remaining = || {
+            issue(&mut token_issuer, &mut state, &token_request(CONTROLLER))
+                .rate_limit
+                .unwrap()
+                .remaining
+        };
+        assert_eq!(remaining(), 1);
+        assert_eq!(remaining(), 0);
+
+        let e = token_error(&mut token_issuer, &mut state, &token_request(CONTROLLER));
+        assert_eq!(e.code, Some(ResponseType::TooManyRequests));
+        // Each controller has a budget of its own
+        let other = issue(
+            &mut token_issuer,
+            &mut state,
+            &token_request(OTHER_CONTROLLER),
+        );
+        assert_eq!(other.rate_limit.unwrap().remaining, 1);
+    }
+
+    #[test]
+    fn token_subject_is_mapped_from_the_controller_cid() {
+        let mut state = state();
+        let mut token_issuer = TokenIssuer {
+            controller_subjects: HashMap::from([(CONTROLLER, "ops@example.com".to_string())]),
+            ..token_issuer()
+        };
+        let mut subject = |controller| {
+            let response = issue(&mut token_issuer, &mut state, &token_request(controller));
+            token_claims(&response.tokens[&DEVICE])["sub"].clone()
+        };
+
+        assert_eq!(subject(CONTROLLER), "ops@example.com");
+        // Unmapped controllers keep their CID
+        assert_eq!(subject(OTHER_CONTROLLER), OTHER_CONTROLLER.to_string());
+    }
+
+    #[test]
+    fn old_port_is_reported_until_the_grace_period_runs_out() {
+        let mut state = state();
+        let grace = Some(Duration::from_millis(300));
+        let registration_key =
+            register_device(&mut state, &api_device(47111), None, grace).unwrap();
+        register_device(
+            &mut state,
+            &api_device(47112),
+            Some(&registration_key),
+            grace,
+        )
+        .unwrap();
+
+        let listed = |state: &State| {
+            let device = list_devices(state, &ListFilter { tag: None })
+                .devices
+                .remove(0);
+            (device.port, device.previous_port)
+        };
+        assert_eq!(listed(&state), (47112, Some(47111)));
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(listed(&state), (47112, None));
+    }
+
+    #[test]
+    fn seeded_devices_are_listed_straight_away() {
+        let file = std::env::temp_dir().join(format!("arbiter-seed-{}.json", Uuid::new_v4()));
+        std::fs::write(
+            &file,
+            serde_json::to_vec(&json!([{
+                "cid": DEVICE,
+                "label": "lobby",
+                "manufacturer": "ACME",
+                "model": "Test",
+                "port": 47111,
+                "ttl": 3600,
+            }]))
+            .unwrap(),
+        )
+        .unwrap();
+        let mut state = state();
+        seed_devices(&mut state, file.to_str().unwrap());
+        std::fs::remove_file(&file).unwrap();
+
+        let devices = list_devices(&state, &ListFilter { tag: None }).devices;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].cid, DEVICE);
+        assert_eq!(devices[0].label, "lobby");
+        assert_eq!(devices[0].port, 47111);
+    }
+
+    #[test]
+    fn sweep_evicts_idempotency_entries_after_their_window() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let request = ControlTokenRequest {
+            idempotency_key: Some("retry-1".to_string()),
+            ..token_request(CONTROLLER)
+        };
+        issue(&mut token_issuer, &mut state, &request);
+
+        let now = Instant::now();
+        token_issuer.sweep(now);
+        assert_eq!(token_issuer.recent_responses.len(), 1);
+        token_issuer.sweep(now + IDEMPOTENCY_WINDOW);
+        assert!(token_issuer.recent_responses.is_empty());
+    }
+
+    #[test]
+    fn extra_claims_from_the_acl_are_added_to_the_token() {
+        let mut state = state();
+        let mut token_issuer = TokenIssuer {
+            acl: serde_json::from_value(json!({
+                "entries": [{
+                    "controllerCids": [CONTROLLER],
+                    "deviceCids": [DEVICE],
+                    "parameters": { "read": ["temp"], "write": [] },
+                    "extraClaims": { "role": "operator", "priority": 2, "iss": "someone-else" },
+                }],
+            }))
+            .unwrap(),
+            ..token_issuer()
+        };
+
+        let response = issue(&mut token_issuer, &mut state, &token_request(CONTROLLER));
+        let claims = token_claims(&response.tokens[&DEVICE]);
+        assert_eq!(claims["role"], "operator");
+        assert_eq!(claims["priority"], 2);
+        // Reserved claims can't be overridden
+        assert_eq!(claims["iss"], token_issuer.my_cid.to_string());
+    }
+
+    #[test]
+    fn token_for_a_parameter_the_device_didnt_advertise_is_refused() {
+        let mut state = state();
+        let mut token_issuer = token_issuer();
+        let device = ApiDevice {
+            parameters: Some(vec!["temp".to_string()]),
+            ..api_device(47111)
+        };
+        register_device(&mut state, &device, None, None).unwrap();
+
+        issue(&mut token_issuer, &mut state, &token_request(CONTROLLER));
+        let typo = ControlTokenRequest {
+            params_read: vec!["tmep".to_string()],
+            ..token_request(CONTROLLER)
+        };
+        let e = token_error(&mut token_issuer, &mut state, &typo);
+        assert_eq!(e.code, Some(ResponseType::BadRequest));
+        assert_eq!(e.message, format!("Device {DEVICE} has no parameter tmep"));
+    }
+
+    #[test]
+    fn partially_denied_request_is_audited_with_what_was_denied() {
+        let (mut state, file) = audited_state();
+        let request = ControlTokenRequest {
+            params_read: vec!["temp".to_string(), "humidity".to_string()],
+            ..token_request(CONTROLLER)
+        };
+        token_error(&mut token_issuer(), &mut state, &request);
+        let mut narrowing = TokenIssuer {
+            narrow_scopes: true,
+            ..token_issuer()
+        };
+        issue(&mut narrowing, &mut state, &request);
+
+        let records = audit_records(file);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["event"], "tokenDenied");
+        assert_eq!(records[0]["aclEntries"], json!([0]));
+        assert_eq!(records[0]["deniedRead"], json!(["humidity"]));
+        assert_eq!(records[0]["deniedWrite"], json!([]));
+        assert_eq!(records[1]["event"], "tokenIssued");
+        assert_eq!(records[1]["aclEntries"], json!([0]));
+        assert_eq!(records[1]["paramsRead"], json!(["temp"]));
+        assert_eq!(records[1]["deniedRead"], json!(["humidity"]));
+    }
+
+    #[tokio::test]
+    async fn rapid_registrations_from_one_address_are_throttled() {
+        let config = json!({ "registrationRateLimit": { "maxRequests": 2, "windowSecs": 60 } });
+        let host = Some(IpAddr::from([10, 0, 0, 1]));
+        let other_host = Some(IpAddr::from([10, 0, 0, 2]));
+        let registrations = (0..4)
+            .map(|i| {
+                let device = ApiDevice {
+                    cid: Uuid::from_u128(0xd0 + i),
+                    ..api_device(47111 + i as u16)
+                };
+                (device, if i < 3 { host } else { other_host })
+            })
+            .collect();
+
+        let responses = registrations_through_state_loop(config, registrations).await;
+        assert!(matches!(responses[0], Response::Registered(_)));
+        assert!(matches!(responses[1], Response::Registered(_)));
+        match &responses[2] {
+            Response::Error(e) => assert_eq!(e.code, Some(ResponseType::TooManyRequests)),
+            _ => panic!("Third registration from the address wasn't throttled"),
         }
+        assert!(matches!(responses[3], Response::Registered(_)));
     }
-    false
 }
@@ -0,0 +1,217 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum WebhookEvent {
+    #[serde(rename_all = "camelCase")]
+    DeviceRegistered { cid: Uuid, label: String, port: u16 },
+    #[serde(rename_all = "camelCase")]
+    DeviceDeregistered { cid: Uuid, label: String },
+}
+
+// Posts events to the configured webhook. Without the `webhooks` feature this does nothing, and
+// configuring a webhook is a startup error.
+pub struct Notifier {
+    #[cfg(feature = "webhooks")]
+    webhook: Option<http::Webhook>,
+}
+
+impl Notifier {
+    pub fn new(config: Option<&WebhookConfig>) -> anyhow::Result<Self> {
+        #[cfg(feature = "webhooks")]
+        {
+            Ok(Self {
+                webhook: config.map(http::Webhook::new).transpose()?,
+            })
+        }
+
+        #[cfg(not(feature = "webhooks"))]
+        {
+            if config.is_some() {
+                anyhow::bail!("A webhook is configured but the arbiter was built without webhooks");
+            }
+            Ok(Self {})
+        }
+    }
+
+    pub fn notify(&self, event: WebhookEvent) {
+        #[cfg(feature = "webhooks")]
+        if let Some(ref webhook) = self.webhook {
+            webhook.post(&event);
+        }
+
+        #[cfg(not(feature = "webhooks"))]
+        let _ = event;
+    }
+}
+
+#[cfg(feature = "webhooks")]
+mod http {
+    use std::time::Duration;
+
+    use anyhow::Context;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::WebhookEvent;
+    use crate::config::WebhookConfig;
+
+    const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+    // Minimal HTTP/1.1 client, plain http:// URLs only
+    pub struct Webhook {
+        host: String,
+        port: u16,
+        path: String,
+        max_retries: u32,
+    }
+
+    impl Webhook {
+        pub fn new(config: &WebhookConfig) -> anyhow::Result<Self> {
+            let rest = config
+                .url
+                .strip_prefix("http://")
+                .context("Webhook URL must start with http://")?;
+            let (authority, path) = match rest.find('/') {
+                Some(index) => rest.split_at(index),
+                None => (rest, "/"),
+            };
+            let (host, port) = match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().context("Invalid webhook port")?),
+                None => (authority, 80),
+            };
+
+            Ok(Self {
+                host: host.to_string(),
+                port,
+                path: path.to_string(),
+                max_retries: config.max_retries,
+            })
+        }
+
+        // Sends in the background, retrying with exponential backoff, so that the state loop
+        // never waits on the webhook
+        pub fn post(&self, event: &WebhookEvent) {
+            let body = serde_json::to_vec(event).unwrap();
+            let host = self.host.clone();
+            let port = self.port;
+            let path = self.path.clone();
+            let max_retries = self.max_retries;
+
+            tokio::spawn(async move {
+                let mut backoff = INITIAL_BACKOFF;
+                for attempt in 0..=max_retries {
+                    let result =
+                        tokio::time::timeout(ATTEMPT_TIMEOUT, post(&host, port, &path, &body))
+                            .await
+                            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+                    match result {
+                        Ok(()) => return,
+                        Err(e) => println!("Webhook delivery attempt {} failed: {e}", attempt + 1),
+                    }
+                    if attempt < max_retries {
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+                println!("Giving up on webhook delivery");
+            });
+        }
+    }
+
+    async fn post(host: &str, port: u16, path: &str, body: &[u8]) -> anyhow::Result<()> {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        let header = format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body).await?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("Malformed HTTP response")?;
+        if !(200..300).contains(&status) {
+            anyhow::bail!("HTTP status {status}");
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use tokio::{net::TcpListener, time::Instant};
+        use uuid::Uuid;
+
+        use super::*;
+
+        // Answers one request with the given status and returns the request's body
+        async fn respond(listener: &TcpListener, status: &str) -> serde_json::Value {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            let body = loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                let Some((header, body)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let length: usize = header
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if body.len() == length {
+                    break serde_json::from_str(body).unwrap();
+                }
+            };
+            stream
+                .write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            body
+        }
+
+        #[tokio::test]
+        async fn failed_delivery_is_retried_after_a_backoff() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let webhook = Webhook::new(&WebhookConfig {
+                url: format!("http://{}/events", listener.local_addr().unwrap()),
+                max_retries: 1,
+            })
+            .unwrap();
+            let cid = Uuid::from_u128(0xd1);
+
+            webhook.post(&WebhookEvent::DeviceDeregistered {
+                cid,
+                label: "test".to_string(),
+            });
+            let first = respond(&listener, "500 Internal Server Error").await;
+            let failed_at = Instant::now();
+            let second = respond(&listener, "204 No Content").await;
+            assert!(failed_at.elapsed() >= INITIAL_BACKOFF);
+            assert_eq!(first, second);
+            assert_eq!(
+                second,
+                serde_json::json!({
+                    "event": "deviceDeregistered",
+                    "cid": cid,
+                    "label": "test",
+                })
+            );
+        }
+    }
+}
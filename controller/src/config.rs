@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use log::LevelFilter;
 use serde::Deserialize;
 use uuid::Uuid;
@@ -6,6 +8,8 @@ use uuid::Uuid;
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub cid: Uuid,
+    #[serde(default = "default_arbiter_addr")]
+    pub arbiter_addr: SocketAddr,
     #[serde(default = "default_root_ca")]
     pub root_ca_file: String,
     #[serde(default = "default_cert_file")]
@@ -14,6 +18,41 @@ pub struct Config {
     pub key_file: String,
     #[serde(default = "default_log_filter")]
     pub log_level: LevelFilter,
+    #[serde(default)]
+    pub compress_payloads: bool,
+    #[serde(default)]
+    pub enable_attack_demos: bool,
+    // Encrypt `s` and `sall` values end to end for devices that registered an encryption key
+    #[serde(default)]
+    pub encrypt_values: bool,
+    // Ask devices to return `g` values as JSON carrying the parameter's type
+    #[serde(default)]
+    pub typed_values: bool,
+    // Run `d` automatically after each successful `c`
+    #[serde(default)]
+    pub auto_discover_on_connect: bool,
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    // Retries for discovery and token requests that time out or fail in transit
+    #[serde(default)]
+    pub request_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    // The last full device list is saved here, and shown by `d` if the arbiter can't be reached
+    #[serde(default)]
+    pub device_snapshot_file: Option<String>,
+    // The arbiter and device last used are saved here, and offered again on the next start
+    #[serde(default)]
+    pub session_state_file: Option<String>,
+    // If set, the device snapshot is only used if the arbiter signed it with this key
+    #[serde(default)]
+    pub arbiter_public_key_file: Option<String>,
+}
+
+fn default_arbiter_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 5683))
 }
 
 fn default_root_ca() -> String {
@@ -31,3 +70,15 @@ fn default_key_file() -> String {
 fn default_log_filter() -> LevelFilter {
     LevelFilter::Off
 }
+
+fn default_handshake_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_request_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
@@ -0,0 +1,68 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng, Payload},
+    Aes256Gcm, KeyInit,
+};
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use p256::{ecdh::EphemeralSecret, elliptic_curve::sec1::ToEncodedPoint, PublicKey};
+use serde::Serialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+// Must match the device's
+const KDF_INFO: &[u8] = b"NextGenTransport parameter value v1";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedValue {
+    ephemeral_key: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Encrypts a PUT value so that only the device holding the private half of `device_key` can read
+// it, and only as a value for `parameter`. `device_key` is the base64 SEC1 point the device
+// registered with the arbiter.
+pub fn encrypt_value(
+    device_key: &str,
+    device: &Uuid,
+    parameter: &str,
+    value: &str,
+) -> anyhow::Result<EncryptedValue> {
+    let device_key = PublicKey::from_sec1_bytes(
+        &STANDARD
+            .decode(device_key)
+            .context("Invalid device encryption key")?,
+    )
+    .context("Invalid device encryption key")?;
+
+    let ephemeral_secret = EphemeralSecret::random(&mut OsRng);
+    let ephemeral_key = ephemeral_secret.public_key().to_encoded_point(false);
+    let shared = ephemeral_secret.diffie_hellman(&device_key);
+    let mut info = KDF_INFO.to_vec();
+    info.extend_from_slice(ephemeral_key.as_bytes());
+    info.extend_from_slice(device_key.to_encoded_point(false).as_bytes());
+    let mut key = [0; 32];
+    shared
+        .extract::<Sha256>(None)
+        .expand(&info, &mut key)
+        .unwrap();
+
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new_from_slice(&key)
+        .unwrap()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: value.as_bytes(),
+                aad: format!("{device}/{parameter}").as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+    Ok(EncryptedValue {
+        ephemeral_key: STANDARD.encode(ephemeral_key.as_bytes()),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
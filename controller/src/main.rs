@@ -1,5 +1,6 @@
-use std::{fs::File, io::BufReader};
+use std::{fs::File, io::BufReader, process::ExitCode};
 
+use anyhow::Context;
 use rcgen::KeyPair;
 use rustls::{Certificate as RustlsCertificate, RootCertStore};
 use webrtc_dtls::config::Config as DtlsConfig;
@@ -8,16 +9,23 @@ use webrtc_dtls::crypto::{Certificate, CryptoPrivateKey};
 use self::config::Config;
 
 mod config;
+mod encryption;
 mod tui;
 
-fn main() {
+fn main() -> ExitCode {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
 
-    let config = std::fs::read_to_string("config.json").expect("No config file provided");
-    let config: Config = serde_json::from_str(&config).expect("Invalid config");
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:#}");
+            // The exit code the arbiter and devices use for configuration errors
+            return ExitCode::from(2);
+        }
+    };
 
     env_logger::Builder::new()
         .filter_level(config.log_level)
@@ -25,18 +33,41 @@ fn main() {
 
     let roots_cas = get_root_cert_store(&config.root_ca_file);
     let certificates = get_my_certs(&config.cert_file, &config.key_file);
-    let my_cid = config.cid;
 
-    let config = DtlsConfig {
+    let dtls_config = DtlsConfig {
         certificates,
         server_name: "arbiter.local".into(),
         roots_cas,
         ..Default::default()
     };
 
+    // `controller --exec "<command>"` runs a single command without the interactive loop
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, command] = args.as_slice() {
+        if flag == "--exec" {
+            return if tui::run_once(dtls_config, &config, runtime, command) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+    }
+
     // It is recommended to use a normal thread for stdin reads
     // https://docs.rs/tokio/latest/tokio/io/struct.Stdin.html
-    tui::run_tui(config, my_cid, runtime);
+    tui::run_tui(dtls_config, &config, runtime);
+    ExitCode::SUCCESS
+}
+
+fn load_config() -> anyhow::Result<Config> {
+    let config = std::fs::read_to_string("config.json").context("No config file provided")?;
+    parse_config(&config)
+}
+
+fn parse_config(config: &str) -> anyhow::Result<Config> {
+    let mut config: serde_json::Value = serde_json::from_str(config).context("Invalid config")?;
+    env_overrides::apply_env_overrides(&mut config, "CONTROLLER_");
+    serde_json::from_value(config).context("Invalid config")
 }
 
 fn get_root_cert_store(cert_file: &str) -> RootCertStore {
@@ -63,3 +94,29 @@ fn get_my_certs(cert_file: &str, key_file: &str) -> Vec<Certificate> {
         private_key,
     }]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_arbiter_address_is_a_config_error() {
+        let config = parse_config(r#"{ "cid": "00000000-0000-0000-0000-000000000001" }"#).unwrap();
+        assert_eq!(config.arbiter_addr.to_string(), "127.0.0.1:5683");
+        let config = parse_config(
+            r#"{ "cid": "00000000-0000-0000-0000-000000000001", "arbiterAddr": "[::1]:5684" }"#,
+        )
+        .unwrap();
+        assert_eq!(config.arbiter_addr.to_string(), "[::1]:5684");
+
+        let e = parse_config(
+            r#"{ "cid": "00000000-0000-0000-0000-000000000001", "arbiterAddr": "arbiter:5683" }"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{e:#}"),
+            "Invalid config: invalid socket address syntax"
+        );
+    }
+}
@@ -1,20 +1,61 @@
+use std::cell::RefCell;
 use std::fmt::Display;
-use std::net::ToSocketAddrs;
-use std::{collections::HashMap, io};
+use std::future::Future;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+};
 
-use base64::{engine::general_purpose::URL_SAFE, Engine};
+use anyhow::Context;
+#[cfg(feature = "attack-demos")]
+use base64::engine::general_purpose::URL_SAFE;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use coap::request::MessageClass;
 use coap::{
     client::CoAPClient,
     dtls::{DtlsConnection, UdpDtlsConfig},
-    request::{Method, RequestBuilder},
+    request::{CoapRequest, Method, RequestBuilder},
 };
-use coap_lite::ResponseType;
+use coap_lite::option_value::OptionValueU16;
+use coap_lite::{CoapOption, CoapResponse, ContentFormat, Packet, ResponseType};
+use flate2::read::DeflateDecoder;
+use jsonwebtoken::{Algorithm, DecodingKey};
+use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate as RustlsCertificate, RootCertStore, ServerName};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use webrtc_dtls::config::Config as DtlsConfig;
+use x509_parser::parse_x509_certificate;
+
+use crate::{
+    config::Config,
+    encryption::{encrypt_value, EncryptedValue},
+};
+
+// Commands whose first argument is a device index
+const DEVICE_COMMANDS: &[&str] = &[
+    "g",
+    "s",
+    "f",
+    "v",
+    "params",
+    "cert",
+    "pg",
+    "tokenbench",
+    "probe",
+    "scope",
+];
 
-const REQUEST_DESTINATION: &str = "127.0.0.1:5683";
+const LIVE_VIEW_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const CONTENT_ENCODING_OPTION: CoapOption = CoapOption::Unknown(65000);
+const DEFLATE: &[u8] = b"deflate";
+const SIGNATURE_OPTION: CoapOption = CoapOption::Unknown(65004);
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -24,7 +65,19 @@ struct Device {
     pub manufacturer: String,
     pub model: String,
     pub port: u16,
+    // Reported for a while after the device moved to a new port
+    #[serde(default)]
+    pub previous_port: Option<u16>,
+    #[allow(dead_code)]
     pub ttl: u64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Set if the device accepts values encrypted to it
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    // The parameters the device advertised when registering, if it did
+    #[serde(default)]
+    pub parameters: Option<Vec<String>>,
 }
 
 #[derive(Serialize)]
@@ -34,35 +87,48 @@ struct ControlTokenRequest {
     pub devices: Vec<Uuid>,
     pub params_read: Vec<String>,
     pub params_write: Vec<String>,
+    // Lets the arbiter recognise CoAP retransmissions of the same request
+    pub idempotency_key: String,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ControlTokenResponse {
     pub tokens: HashMap<Uuid, String>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitBudget>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RateLimitBudget {
+    pub remaining: u32,
+    pub reset_secs: u64,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum RequestType {
     Get,
     Put,
 }
 
-impl Into<Method> for RequestType {
-    fn into(self) -> Method {
-        match self {
-            Self::Get => Method::Get,
-            Self::Put => Method::Put,
+impl From<RequestType> for Method {
+    fn from(value: RequestType) -> Self {
+        match value {
+            RequestType::Get => Method::Get,
+            RequestType::Put => Method::Put,
         }
     }
 }
 
-impl From<&str> for RequestType {
-    fn from(value: &str) -> Self {
+impl TryFrom<&str> for RequestType {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
-            "g" => Self::Get,
-            "s" => Self::Put,
-            _ => panic!("RequestType From<&str>"),
+            "g" => Ok(Self::Get),
+            "s" => Ok(Self::Put),
+            _ => Err(format!("Unknown request type {value:?}, expected g or s")),
         }
     }
 }
@@ -80,6 +146,59 @@ impl Display for RequestType {
     }
 }
 
+#[derive(Clone, Deserialize)]
+struct ParamDescription {
+    name: String,
+    description: Option<String>,
+    #[serde(default, rename = "type")]
+    ty: Option<ParamType>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ParamType {
+    Int,
+    Float,
+    Bool,
+    String,
+}
+
+impl ParamType {
+    // Checks that a value the device returned is well-formed for the declared type
+    fn validate(self, value: &str) -> bool {
+        match self {
+            ParamType::Int => value.parse::<i64>().is_ok(),
+            ParamType::Float => value.parse::<f64>().is_ok(),
+            ParamType::Bool => value.parse::<bool>().is_ok(),
+            ParamType::String => true,
+        }
+    }
+}
+
+// GET response from devices asked for typed values
+#[derive(Deserialize)]
+struct TypedValue {
+    value: serde_json::Value,
+    #[serde(rename = "type")]
+    ty: ParamType,
+}
+
+#[derive(Serialize)]
+struct ProxyReadPayload {
+    cid: Uuid,
+}
+
+#[derive(Serialize)]
+struct RevokePayload {
+    cid: Uuid,
+    jti: Uuid,
+}
+
+#[derive(Deserialize)]
+struct TokenId {
+    jti: Uuid,
+}
+
 #[derive(Serialize)]
 struct GetParamPayload {
     token: String,
@@ -88,9 +207,37 @@ struct GetParamPayload {
 #[derive(Serialize)]
 struct SetParamPayload {
     token: String,
-    value: String,
+    #[serde(flatten)]
+    value: PutValue,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum PutValue {
+    Value(String),
+    EncryptedValue(EncryptedValue),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidateTokenPayload {
+    token: String,
+    params_read: Vec<String>,
+    params_write: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidateTokenResponse {
+    valid: bool,
+    error: Option<String>,
+    allowed_read: Vec<String>,
+    forbidden_read: Vec<String>,
+    allowed_write: Vec<String>,
+    forbidden_write: Vec<String>,
+}
+
+#[cfg(feature = "attack-demos")]
 #[derive(Deserialize, Serialize)]
 struct JwtClaims {
     iss: String,
@@ -99,54 +246,377 @@ struct JwtClaims {
     exp: u64,
     params_read: Vec<String>,
     params_write: Vec<String>,
+    // Carried over unchanged when tampering with a token
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+// The DTLS handshake and the request/response exchange are timed separately, since a
+// constrained peer can be slow to handshake but quick to answer once connected.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    handshake: Duration,
+    request: Duration,
+    // Discovery and token requests that fail without an answer from the arbiter are retried this
+    // many times, waiting retry_backoff before the first retry and twice as long each time after
+    retries: u32,
+    retry_backoff: Duration,
 }
 
-pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtime) {
+// Connection state and everything needed to run commands, shared by the interactive loop and
+// one-shot mode
+struct Session<'a> {
+    config: DtlsConfig,
+    controller_config: &'a Config,
+    runtime: &'a tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    gs_regex: regex::Regex,
+    #[cfg(feature = "attack-demos")]
+    f_regex: regex::Regex,
+    v_regex: regex::Regex,
+    cert_regex: regex::Regex,
+    sall_regex: regex::Regex,
+    pg_regex: regex::Regex,
+    params_regex: regex::Regex,
+    tokenbench_regex: regex::Regex,
+    probe_regex: regex::Regex,
+    direct_regex: regex::Regex,
+    scope_regex: regex::Regex,
+    client: Option<ArbiterClient>,
+    current_devices: Vec<Device>,
+    // Set when current_devices came from the snapshot file rather than the arbiter
+    devices_stale: bool,
+    // Most recently issued control token, for `lasttoken`
+    last_token: RefCell<Option<String>>,
+    // Parameters advertised by each device, fetched on first use
+    param_schemas: HashMap<Uuid, Vec<ParamDescription>>,
+    // As last written to the session state file
+    saved_state: SessionState,
+    // The device the operator last ran a command against
+    last_device: Option<Uuid>,
+}
+
+// Persisted between runs in the session state file
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionState {
+    // Set if the controller was connected to this arbiter when it last ran
+    arbiter: Option<String>,
+    last_device: Option<Uuid>,
+}
+
+// A DTLS session with the arbiter, and the address it's with, which requests over it name as their
+// Uri-Host
+struct ArbiterClient {
+    coap: CoAPClient<DtlsConnection>,
+    addr: SocketAddr,
+}
+
+pub fn run_tui(config: DtlsConfig, controller_config: &Config, runtime: tokio::runtime::Runtime) {
     println!("NextGen Transport Controller");
     println!("Available commands:");
-    println!("  c: Connect to local Arbiter on port 5683 via DTLS");
-    println!("  d: Discover devices via local Arbiter");
+    if controller_config.auto_discover_on_connect {
+        println!(
+            "  c: Connect to the Arbiter at {} via DTLS and discover devices",
+            controller_config.arbiter_addr
+        );
+    } else {
+        println!(
+            "  c: Connect to the Arbiter at {} via DTLS",
+            controller_config.arbiter_addr
+        );
+    }
+    println!("  d: Discover devices via the Arbiter, optionally only those with a tag");
+    if controller_config.device_snapshot_file.is_some() {
+        println!("      falls back to the last discovered devices if the Arbiter is unreachable");
+    }
+    println!("      syntax: d [tag]");
     println!("  g: Get param value from device");
     println!("      syntax: g [device_index] [parameter]");
     println!("  s: Set param value on device");
     println!("      syntax: s [device_index] [parameter] [value]");
-    println!("  f: Attempt to set param value on device_index_b using token for device_index_a");
-    println!("      syntax: s [device_index_a] [device_index_b] [parameter] [value]");
+    println!("  dg, ds: Get or set a param directly on the device on a local port, with a token");
+    println!("      read from a file, without going through the Arbiter");
+    println!("      syntax: dg [port] [token_file] [parameter]");
+    println!("      syntax: ds [port] [token_file] [parameter] [value]");
+    #[cfg(feature = "attack-demos")]
+    if controller_config.enable_attack_demos {
+        println!(
+            "  f: Attempt to set param value on device_index_b using token for device_index_a"
+        );
+        println!("      syntax: s [device_index_a] [device_index_b] [parameter] [value]");
+    }
+    #[cfg(not(feature = "attack-demos"))]
+    if controller_config.enable_attack_demos {
+        println!(
+            "  (enableAttackDemos is set, but this build doesn't include the attack-demos feature)"
+        );
+    }
+    println!(
+        "  v: Get a token as for g/s, then ask the device what it would allow without acting on it"
+    );
+    println!("      syntax: v [device_index] [g|s] [parameter] [check_parameter]...");
+    println!("  params: List the parameters a device advertises");
+    println!("      syntax: params [device_index]");
+    println!("  cert: Fetch a device's certificate chain and validate it against the root CA");
+    println!("      syntax: cert [device_index]");
+    println!("  pg: Get param value from device through the Arbiter, if it proxies reads");
+    println!("      syntax: pg [device_index] [parameter]");
+    println!("  live: Continuously show devices as they join and leave, until Enter is pressed");
+    println!("      syntax: live [tag]");
+    println!(
+        "  tokenbench: Request tokens for a device back to back and report issuance throughput"
+    );
+    println!("      syntax: tokenbench [device_index] [count]");
+    println!(
+        "  probe: Try to read each parameter a device advertises and report which are allowed"
+    );
+    println!("      syntax: probe [device_index]");
+    println!(
+        "  scope: Ask for read and write access to parameters and show what the Arbiter's ACL"
+    );
+    println!("      actually grants");
+    println!("      syntax: scope [device_index] [parameter]...");
+    println!("  lasttoken: Decode and show the most recently obtained control token, unverified");
+    println!("  revoke: Revoke a control token by its jti, or the most recently obtained one");
+    println!("      syntax: revoke [jti]");
     println!("  p: Print current devices");
     println!("  q: Quit");
 
-    let gs_regex = regex::Regex::new(r"^([gs]) (\d+) ([\w\-_]+)( [^\s]+)?$").unwrap();
-    let f_regex = regex::Regex::new(r"^f (\d+) (\d+) ([\w\-_]+) ([^\s]+)$").unwrap();
-
-    let mut client: Option<CoAPClient<DtlsConnection>> = None;
-    let mut current_devices: Vec<Device> = vec![];
+    let mut session = Session::new(config, controller_config, &runtime);
+    if let Some(ref state_file) = controller_config.session_state_file {
+        session.restore_state(state_file);
+    }
 
+    // Read line by line rather than holding the stdin lock, so the live view can wait for a
+    // keypress on another thread
     let stdin = io::stdin();
-    for line in stdin.lines() {
-        let line = line.unwrap();
-        match line.chars().next().unwrap() {
-            'q' => break,
-            'c' => {
+    loop {
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        session.note_device(line.trim_end());
+        let keep_going = session.run_command(line.trim_end());
+        if let Some(ref state_file) = controller_config.session_state_file {
+            session.save_state(state_file);
+        }
+        if !keep_going {
+            break;
+        }
+    }
+}
+
+// Asks the operator a yes/no question on stdin
+fn confirm(question: &str) -> bool {
+    println!("{question} [y/N]");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn load_session_state(file: &str) -> anyhow::Result<SessionState> {
+    Ok(serde_json::from_slice(&std::fs::read(file)?)?)
+}
+
+fn save_session_state(file: &str, state: &SessionState) -> anyhow::Result<()> {
+    Ok(std::fs::write(file, serde_json::to_vec_pretty(state)?)?)
+}
+
+// Connects, discovers devices and runs a single command, for scripting. Returns false if the
+// arbiter couldn't be reached.
+pub fn run_once(
+    config: DtlsConfig,
+    controller_config: &Config,
+    runtime: tokio::runtime::Runtime,
+    command: &str,
+) -> bool {
+    let mut session = Session::new(config, controller_config, &runtime);
+    session.run_command("c");
+    if session.client.is_none() {
+        return false;
+    }
+    if !controller_config.auto_discover_on_connect
+        && !matches!(command.split_whitespace().next(), Some("c" | "d"))
+    {
+        session.run_command("d");
+    }
+    session.run_command(command);
+    true
+}
+
+impl<'a> Session<'a> {
+    fn new(
+        config: DtlsConfig,
+        controller_config: &'a Config,
+        runtime: &'a tokio::runtime::Runtime,
+    ) -> Self {
+        Self {
+            config,
+            controller_config,
+            runtime,
+            timeouts: Timeouts {
+                handshake: Duration::from_millis(controller_config.handshake_timeout_ms),
+                request: Duration::from_millis(controller_config.request_timeout_ms),
+                retries: controller_config.request_retries,
+                retry_backoff: Duration::from_millis(controller_config.retry_backoff_ms),
+            },
+            gs_regex: regex::Regex::new(r"^([gs]) (\d+) ([\w\-_]+)( [^\s]+)?$").unwrap(),
+            #[cfg(feature = "attack-demos")]
+            f_regex: regex::Regex::new(r"^f (\d+) (\d+) ([\w\-_]+) ([^\s]+)$").unwrap(),
+            v_regex: regex::Regex::new(r"^v (\d+) ([gs]) ([\w\-_]+)((?: [\w\-_]+)*)$").unwrap(),
+            cert_regex: regex::Regex::new(r"^cert (\d+)$").unwrap(),
+            sall_regex: regex::Regex::new(r"^sall ([\w\-_]+) ([^\s]+)$").unwrap(),
+            pg_regex: regex::Regex::new(r"^pg (\d+) ([\w\-_]+)$").unwrap(),
+            params_regex: regex::Regex::new(r"^params (\d+)$").unwrap(),
+            tokenbench_regex: regex::Regex::new(r"^tokenbench (\d+) (\d+)$").unwrap(),
+            probe_regex: regex::Regex::new(r"^probe (\d+)$").unwrap(),
+            scope_regex: regex::Regex::new(r"^scope (\d+)((?: [\w\-_]+)+)$").unwrap(),
+            direct_regex: regex::Regex::new(r"^(d[gs]) (\d+) ([^\s]+) ([\w\-_]+)( [^\s]+)?$")
+                .unwrap(),
+            client: None,
+            current_devices: vec![],
+            devices_stale: false,
+            last_token: RefCell::new(None),
+            param_schemas: HashMap::new(),
+            saved_state: SessionState::default(),
+            last_device: None,
+        }
+    }
+
+    // Offers to reconnect to the arbiter and points out the device used last time
+    fn restore_state(&mut self, state_file: &str) {
+        let state = match load_session_state(state_file) {
+            Ok(state) => state,
+            Err(e) => {
+                if std::path::Path::new(state_file).exists() {
+                    println!("Failed to load session state: {e}");
+                }
+                return;
+            }
+        };
+        self.saved_state = state.clone();
+        self.last_device = state.last_device;
+
+        let arbiter_addr = self.controller_config.arbiter_addr.to_string();
+        if state.arbiter.as_ref() != Some(&arbiter_addr)
+            || !confirm(&format!("Reconnect to the Arbiter at {arbiter_addr}?"))
+        {
+            return;
+        }
+        self.run_command("c");
+        if self.client.is_none() {
+            return;
+        }
+        if !self.controller_config.auto_discover_on_connect {
+            self.run_command("d");
+        }
+        if let Some((index, device)) = state.last_device.and_then(|cid| {
+            self.current_devices
+                .iter()
+                .enumerate()
+                .find(|(_, device)| device.cid == cid)
+        }) {
+            println!(
+                "Last used device {} ({}) is device {index}",
+                device.label, device.cid
+            );
+        }
+    }
+
+    fn save_state(&mut self, state_file: &str) {
+        let state = SessionState {
+            arbiter: self.client.as_ref().map(|client| client.addr.to_string()),
+            last_device: self.last_device,
+        };
+        if state == self.saved_state {
+            return;
+        }
+        match save_session_state(state_file, &state) {
+            Ok(()) => self.saved_state = state,
+            Err(e) => println!("Failed to save session state: {e}"),
+        }
+    }
+
+    // Remembers the device a command is about to be run against
+    fn note_device(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|command| DEVICE_COMMANDS.contains(&command))
+        {
+            return;
+        }
+        if let Some(device) = words
+            .next()
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| self.current_devices.get(index))
+        {
+            self.last_device = Some(device.cid);
+        }
+    }
+
+    fn remember_token(&self, response: &ControlTokenResponse) {
+        if let Some(token) = response.tokens.values().next() {
+            *self.last_token.borrow_mut() = Some(token.clone());
+        }
+    }
+
+    // Returns false when the operator asked to quit
+    fn run_command(&mut self, line: &str) -> bool {
+        let Some(command) = line.split_whitespace().next() else {
+            return true;
+        };
+        if let Some(reason) = disabled_command_reason(command, self.controller_config) {
+            println!("{reason}");
+            return true;
+        }
+        match command {
+            "q" => return false,
+            "c" => {
                 println!("Connecting to Arbiter...");
-                match connect_to_arbiter(config.clone(), &runtime) {
+                match connect_to_arbiter(
+                    self.config.clone(),
+                    self.controller_config.arbiter_addr,
+                    self.runtime,
+                    self.timeouts,
+                ) {
                     Ok(c) => {
                         println!("Connected to Arbiter.");
-                        client = Some(c)
+                        self.client = Some(c);
+                        if self.controller_config.auto_discover_on_connect {
+                            return self.run_command("d");
+                        }
                     }
                     Err(e) => {
                         println!("Failed to connect to Arbiter: {:?}", e);
                     }
                 };
             }
-            'd' => {
-                if let Some(ref client) = client {
-                    match discover_devices(client, &runtime) {
-                        Ok(devices) => {
+            "d" => {
+                let tag = line.split_whitespace().nth(1);
+                if let Some(ref client) = self.client {
+                    match discover_devices(
+                        client,
+                        self.runtime,
+                        self.timeouts,
+                        self.controller_config.compress_payloads,
+                        tag,
+                    ) {
+                        Ok((devices, snapshot)) => {
                             println!("Discovered {} devices", devices.len());
-                            let devices: Vec<Device> =
-                                devices.into_iter().map(|device| device.into()).collect();
                             print_devices(&devices);
-                            current_devices = devices;
+                            // A filtered list would hide the other devices when offline
+                            if let (Some(file), None) =
+                                (&self.controller_config.device_snapshot_file, tag)
+                            {
+                                if let Err(e) = save_device_snapshot(file, &snapshot) {
+                                    println!("Failed to save device snapshot: {e}");
+                                }
+                            }
+                            self.current_devices = devices;
+                            self.devices_stale = false;
+                            return true;
                         }
                         Err(e) => {
                             println!("Failed to discover devices: {:?}", e);
@@ -155,81 +625,154 @@ pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtim
                 } else {
                     println!("Not connected to Arbiter");
                 }
+
+                if let Some(ref file) = self.controller_config.device_snapshot_file {
+                    match load_device_snapshot(
+                        file,
+                        self.controller_config.arbiter_public_key_file.as_deref(),
+                        tag,
+                    ) {
+                        Ok(devices) => {
+                            println!(
+                                "STALE: showing {} devices from the last successful discovery",
+                                devices.len()
+                            );
+                            print_devices(&devices);
+                            self.current_devices = devices;
+                            self.devices_stale = true;
+                        }
+                        Err(e) => println!("Failed to load device snapshot: {e}"),
+                    }
+                }
             }
-            'g' | 's' => {
-                let Some(captures) = gs_regex.captures(&line) else {
+            "g" | "s" => {
+                let Some(captures) = self.gs_regex.captures(line) else {
                     println!("Invalid syntax");
-                    continue;
+                    return true;
                 };
 
-                let request_type: RequestType = captures.get(1).unwrap().as_str().into();
+                let Ok(request_type) = RequestType::try_from(captures.get(1).unwrap().as_str())
+                else {
+                    println!("Invalid request type");
+                    return true;
+                };
                 if request_type == RequestType::Put && captures.get(4).is_none() {
                     println!("Invalid syntax");
-                    continue;
+                    return true;
                 }
 
                 let device_index = captures.get(2).unwrap().as_str();
                 let Ok(device_index) = device_index.parse::<usize>() else {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 };
 
-                if device_index > current_devices.len() {
+                if device_index >= self.current_devices.len() {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 }
 
                 let parameter = captures.get(3).unwrap().as_str();
 
-                let Some(ref client) = client else {
+                let Some(ref client) = self.client else {
                     println!("Not connected to Arbiter");
-                    continue;
+                    return true;
                 };
 
-                let device = &current_devices[device_index];
+                let device = &self.current_devices[device_index];
 
-                let token = request_control_token(
-                    client,
-                    &runtime,
-                    &my_cid,
-                    device,
-                    if request_type == RequestType::Get {
-                        vec![parameter.to_string()]
-                    } else {
-                        vec![]
-                    },
-                    if request_type == RequestType::Put {
-                        vec![parameter.to_string()]
-                    } else {
-                        vec![]
-                    },
-                );
+                // A PUT whose token expired on the way gets one retry with a fresh token
+                let mut refreshed = false;
+                let result = loop {
+                    let token = request_control_token(
+                        client,
+                        self.runtime,
+                        self.timeouts,
+                        &self.controller_config.cid,
+                        device,
+                        if request_type == RequestType::Get {
+                            vec![parameter.to_string()]
+                        } else {
+                            vec![]
+                        },
+                        if request_type == RequestType::Put {
+                            vec![parameter.to_string()]
+                        } else {
+                            vec![]
+                        },
+                    );
 
-                let token = match token {
-                    Ok(token) => token,
-                    Err(err) => {
-                        println!("Failed to get control token: {err}");
-                        continue;
+                    let token = match token {
+                        Ok(token) => token,
+                        Err(err) => {
+                            println!("Failed to get control token: {err}");
+                            return true;
+                        }
+                    };
+                    self.remember_token(&token);
+
+                    let value = match request_type {
+                        RequestType::Get => None,
+                        RequestType::Put => match put_value(
+                            self.controller_config,
+                            device,
+                            parameter,
+                            captures.get(4).unwrap().as_str().trim(),
+                        ) {
+                            Ok(value) => Some(value),
+                            Err(e) => {
+                                println!("Failed to encrypt value: {e}");
+                                return true;
+                            }
+                        },
+                    };
+
+                    println!(
+                        "Got control token for device. Sending {request_type} /{parameter}...",
+                    );
+
+                    match send_request(
+                        self.config.clone(),
+                        self.runtime,
+                        self.timeouts,
+                        request_type,
+                        device.port,
+                        token.tokens.get(&device.cid).unwrap().clone(),
+                        parameter,
+                        value,
+                        self.controller_config.typed_values,
+                    ) {
+                        Err(e)
+                            if request_type == RequestType::Put
+                                && !refreshed
+                                && e.is::<TokenExpired>() =>
+                        {
+                            println!("Token expired ({e}), retrying with a fresh one...");
+                            refreshed = true;
+                        }
+                        result => break result,
                     }
                 };
 
-                println!("Got control token for device. Sending {request_type} /{parameter}...",);
-
-                match send_request(
-                    config.clone(),
-                    &runtime,
-                    request_type,
-                    device.port,
-                    token.tokens.get(&device.cid).unwrap().clone(),
-                    parameter,
-                    if request_type == RequestType::Put {
-                        Some(captures.get(4).unwrap().as_str().trim().to_string())
-                    } else {
-                        None
-                    },
-                ) {
+                match result {
                     Ok(Some(result)) => {
-                        println!("Got GET result: {result}");
+                        // Devices that don't support typed values answer with the plain value
+                        match serde_json::from_str::<TypedValue>(&result) {
+                            Ok(typed) if self.controller_config.typed_values => {
+                                println!("Got GET result: {} ({:?})", typed.value, typed.ty)
+                            }
+                            _ => {
+                                let ty = parameter_type(
+                                    &mut self.param_schemas,
+                                    self.config.clone(),
+                                    self.runtime,
+                                    self.timeouts,
+                                    device,
+                                    parameter,
+                                );
+                                print_get_result(&result, parameter, ty);
+                            }
+                        }
                     }
                     Ok(None) => {
                         println!("SET successfully");
@@ -239,49 +782,102 @@ pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtim
                     }
                 }
             }
-            'f' => {
-                let Some(captures) = f_regex.captures(&line) else {
+            "dg" | "ds" => {
+                let Some(captures) = self.direct_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let request_type = match captures.get(1).unwrap().as_str() {
+                    "dg" => RequestType::Get,
+                    _ => RequestType::Put,
+                };
+                if request_type == RequestType::Put && captures.get(5).is_none() {
+                    println!("Invalid syntax");
+                    return true;
+                }
+
+                let Ok(port) = captures.get(2).unwrap().as_str().parse::<u16>() else {
+                    println!("Invalid port");
+                    return true;
+                };
+
+                let token_file = captures.get(3).unwrap().as_str();
+                let token = match std::fs::read_to_string(token_file) {
+                    Ok(token) => token.trim().to_string(),
+                    Err(e) => {
+                        println!("Failed to read token from {token_file}: {e}");
+                        return true;
+                    }
+                };
+                *self.last_token.borrow_mut() = Some(token.clone());
+
+                let parameter = captures.get(4).unwrap().as_str();
+                println!("Sending {request_type} /{parameter} to port {port}...");
+
+                match send_request(
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    request_type,
+                    port,
+                    token,
+                    parameter,
+                    captures
+                        .get(5)
+                        .map(|value| PutValue::Value(value.as_str().trim().to_string())),
+                    false,
+                ) {
+                    Ok(Some(result)) => println!("Got GET result: {result}"),
+                    Ok(None) => println!("SET successfully"),
+                    Err(e) => println!("Failed to execute {request_type} request: {e}"),
+                }
+            }
+            #[cfg(feature = "attack-demos")]
+            "f" => {
+                let Some(captures) = self.f_regex.captures(line) else {
                     println!("Invalid syntax");
-                    continue;
+                    return true;
                 };
 
                 let device_index_a = captures.get(1).unwrap().as_str();
                 let Ok(device_index_a) = device_index_a.parse::<usize>() else {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 };
 
-                if device_index_a > current_devices.len() {
+                if device_index_a >= self.current_devices.len() {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 }
 
                 let device_index_b = captures.get(2).unwrap().as_str();
                 let Ok(device_index_b) = device_index_b.parse::<usize>() else {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 };
 
-                if device_index_b > current_devices.len() {
+                if device_index_b >= self.current_devices.len() {
                     println!("Invalid device index");
-                    continue;
+                    return true;
                 }
 
                 let parameter = captures.get(3).unwrap().as_str();
                 let value = captures.get(4).unwrap().as_str();
 
-                let Some(ref client) = client else {
+                let Some(ref client) = self.client else {
                     println!("Not connected to Arbiter");
-                    continue;
+                    return true;
                 };
 
-                let device_a = &current_devices[device_index_a];
-                let device_b = &current_devices[device_index_b];
+                let device_a = &self.current_devices[device_index_a];
+                let device_b = &self.current_devices[device_index_b];
 
                 let token = request_control_token(
                     client,
-                    &runtime,
-                    &my_cid,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
                     device_a,
                     vec![],
                     vec![parameter.to_string()],
@@ -291,9 +887,10 @@ pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtim
                     Ok(token) => token,
                     Err(err) => {
                         println!("Failed to get control token: {err}");
-                        continue;
+                        return true;
                     }
                 };
+                self.remember_token(&token);
 
                 println!("Got control token for device {device_index_a}.");
                 println!("Changing audience in token to CID of device {device_index_b}... >:)");
@@ -305,13 +902,15 @@ pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtim
                 println!("Sending PUT /{parameter}...");
 
                 match send_request(
-                    config.clone(),
-                    &runtime,
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
                     RequestType::Put,
                     device_b.port,
                     token,
                     parameter,
-                    Some(value.to_string()),
+                    Some(PutValue::Value(value.to_string())),
+                    false,
                 ) {
                     Ok(Some(result)) => {
                         println!("Got GET result: {result}");
@@ -324,94 +923,1416 @@ pub fn run_tui(config: DtlsConfig, my_cid: Uuid, runtime: tokio::runtime::Runtim
                     }
                 }
             }
-            'p' => {
-                if current_devices.is_empty() {
+            "sall" => {
+                let Some(captures) = self.sall_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let parameter = captures.get(1).unwrap().as_str();
+                let value = captures.get(2).unwrap().as_str();
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                if self.current_devices.is_empty() {
                     println!("No devices discovered");
-                } else {
-                    print_devices(&current_devices)
+                    return true;
                 }
-            }
-            _ => {}
-        }
-    }
-}
 
-fn connect_to_arbiter(
-    config: DtlsConfig,
-    runtime: &tokio::runtime::Runtime,
-) -> anyhow::Result<CoAPClient<DtlsConnection>> {
-    let config = UdpDtlsConfig {
-        config,
-        dest_addr: ("127.0.0.1", 5683)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap(),
-    };
-    Ok(runtime.block_on(async move { CoAPClient::from_udp_dtls_config(config).await })?)
-}
+                // Devices without the parameter are left out rather than asked for a token the
+                // arbiter would refuse
+                let mut targets = vec![];
+                let mut skipped = vec![];
+                for device in &self.current_devices {
+                    let schema = self.param_schemas.get(&device.cid).map(Vec::as_slice);
+                    let declared = match declares_parameter(device, schema, parameter) {
+                        Some(declared) => Ok(declared),
+                        None => list_device_parameters(
+                            self.config.clone(),
+                            self.runtime,
+                            self.timeouts,
+                            device.port,
+                        )
+                        .map(|params| {
+                            let declared = declares_parameter(device, Some(&params), parameter);
+                            self.param_schemas.insert(device.cid, params);
+                            declared == Some(true)
+                        }),
+                    };
+                    match declared {
+                        Ok(true) => targets.push(device),
+                        Ok(false) => {
+                            skipped.push((device, format!("has no parameter {parameter}")))
+                        }
+                        Err(e) => {
+                            skipped.push((device, format!("couldn't list its parameters: {e}")))
+                        }
+                    }
+                }
 
-fn discover_devices(
-    client: &CoAPClient<DtlsConnection>,
-    runtime: &tokio::runtime::Runtime,
-) -> anyhow::Result<Vec<Device>> {
-    let request = RequestBuilder::new("/devices", Method::Get)
-        .domain(REQUEST_DESTINATION.to_string())
-        .build();
+                println!("Sending PUT /{parameter} to {} devices...", targets.len());
+                let results: Vec<_> = targets
+                    .into_iter()
+                    .map(|device| {
+                        let result = request_control_token(
+                            client,
+                            self.runtime,
+                            self.timeouts,
+                            &self.controller_config.cid,
+                            device,
+                            vec![],
+                            vec![parameter.to_string()],
+                        )
+                        .map_err(|e| anyhow::anyhow!("Failed to get control token: {e}"))
+                        .and_then(|token| {
+                            self.remember_token(&token);
+                            send_request(
+                                self.config.clone(),
+                                self.runtime,
+                                self.timeouts,
+                                RequestType::Put,
+                                device.port,
+                                token.tokens.get(&device.cid).unwrap().clone(),
+                                parameter,
+                                Some(put_value(self.controller_config, device, parameter, value)?),
+                                false,
+                            )
+                        });
+                        (device, result.map(|_| ()))
+                    })
+                    .collect();
+                print_bulk_results(&results, &skipped);
+            }
+            "v" => {
+                let Some(captures) = self.v_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
 
-    let response = runtime.block_on(async move { client.send(request).await })?;
-    Ok(serde_json::from_slice(&response.message.payload)?)
-}
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
 
-fn print_devices(devices: &Vec<Device>) {
-    for (index, device) in devices.iter().enumerate() {
-        println!(
-            "{}: {} ({}) {} {}",
-            index, device.label, device.cid, device.manufacturer, device.model
-        );
-    }
-}
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
 
-fn request_control_token(
-    client: &CoAPClient<DtlsConnection>,
-    runtime: &tokio::runtime::Runtime,
-    my_cid: &Uuid,
-    device: &Device,
-    params_read: Vec<String>,
-    params_write: Vec<String>,
-) -> anyhow::Result<ControlTokenResponse> {
-    let payload = ControlTokenRequest {
-        cid: my_cid.clone(),
-        devices: vec![device.cid],
-        params_read,
-        params_write,
-    };
+                let Ok(request_type) = RequestType::try_from(captures.get(2).unwrap().as_str())
+                else {
+                    println!("Invalid request type");
+                    return true;
+                };
+                let parameter = captures.get(3).unwrap().as_str().to_string();
+                let mut check_params: Vec<String> = captures
+                    .get(4)
+                    .unwrap()
+                    .as_str()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect();
+                if check_params.is_empty() {
+                    check_params.push(parameter.clone());
+                }
 
-    let request = RequestBuilder::new("/controlToken", Method::Get)
-        .domain(REQUEST_DESTINATION.to_string())
-        .data(Some(serde_json::to_vec(&payload)?))
-        .build();
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
 
-    let response = runtime.block_on(async move { client.send(request).await })?;
-    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
-        Ok(serde_json::from_slice(&response.message.payload)?)
-    } else {
-        Err(anyhow::anyhow!(
-            String::from_utf8(response.message.payload).unwrap()
-        ))
-    }
-}
+                let (params_read, params_write) = match request_type {
+                    RequestType::Get => (vec![parameter], vec![]),
+                    RequestType::Put => (vec![], vec![parameter]),
+                };
+                let token = match request_control_token(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    device,
+                    params_read,
+                    params_write,
+                ) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        println!("Failed to get control token: {err}");
+                        return true;
+                    }
+                };
+                self.remember_token(&token);
 
-fn send_request(
-    mut config: DtlsConfig,
+                println!("Got control token for device. Validating against device...");
+                match validate_token_on_device(
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    device.port,
+                    token.tokens.get(&device.cid).unwrap().clone(),
+                    check_params.clone(),
+                    check_params,
+                ) {
+                    Ok(result) => print_token_validation(&result),
+                    Err(e) => println!("Failed to validate token: {e}"),
+                }
+            }
+            "params" => {
+                let Some(captures) = self.params_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                match list_device_parameters(
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    device.port,
+                ) {
+                    Ok(params) => {
+                        print_parameters(&params);
+                        self.param_schemas.insert(device.cid, params);
+                    }
+                    Err(e) => println!("Failed to list parameters: {e}"),
+                }
+            }
+            "cert" => {
+                let Some(captures) = self.cert_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                match fetch_device_certificates(
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    device.port,
+                ) {
+                    Ok(chain) => print_certificate_chain(&chain, &self.config.roots_cas),
+                    Err(e) => println!("Failed to fetch certificate chain: {e}"),
+                }
+            }
+            "pg" => {
+                let Some(captures) = self.pg_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let parameter = captures.get(2).unwrap().as_str();
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                match proxied_read(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    device,
+                    parameter,
+                ) {
+                    Ok(result) => println!("Got GET result: {result}"),
+                    Err(e) => println!("Failed to execute proxied GET request: {e}"),
+                }
+            }
+            "live" => {
+                let tag = line.split_whitespace().nth(1);
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                self.current_devices = live_view(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    self.controller_config.compress_payloads,
+                    tag,
+                );
+                self.devices_stale = false;
+            }
+            "tokenbench" => {
+                let Some(captures) = self.tokenbench_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Ok(count) = captures.get(2).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid count");
+                    return true;
+                };
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                let stats = benchmark_token_issuance(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    device,
+                    count,
+                );
+                print_token_bench_stats(&stats);
+            }
+            "probe" => {
+                let Some(captures) = self.probe_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                let params = match list_device_parameters(
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    device.port,
+                ) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        println!("Failed to list parameters: {e}");
+                        return true;
+                    }
+                };
+
+                let (results, tokens) = probe_device(
+                    client,
+                    self.config.clone(),
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    device,
+                    &params,
+                );
+                print_probe_results(&results);
+                let unrevoked = revoke_tokens(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    &tokens,
+                );
+                print_probe_tokens(tokens.len(), unrevoked);
+                self.param_schemas.insert(device.cid, params);
+            }
+            "scope" => {
+                let Some(captures) = self.scope_regex.captures(line) else {
+                    println!("Invalid syntax");
+                    return true;
+                };
+
+                let Ok(device_index) = captures.get(1).unwrap().as_str().parse::<usize>() else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let Some(device) = self.current_devices.get(device_index) else {
+                    println!("Invalid device index");
+                    return true;
+                };
+
+                let params: Vec<String> = captures
+                    .get(2)
+                    .unwrap()
+                    .as_str()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect();
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                let request_token = |params_read: Vec<String>, params_write: Vec<String>| {
+                    request_control_token(
+                        client,
+                        self.runtime,
+                        self.timeouts,
+                        &self.controller_config.cid,
+                        device,
+                        params_read,
+                        params_write,
+                    )
+                };
+                let (read, write) = match request_token(params.clone(), params.clone()) {
+                    Ok(token) => {
+                        self.remember_token(&token);
+                        let granted = match device_token(&token, device)
+                            .and_then(|token| granted_scope(token))
+                        {
+                            Ok(granted) => granted,
+                            Err(e) => {
+                                println!("Failed to decode token: {e}");
+                                return true;
+                            }
+                        };
+                        (
+                            scope_diff(&params, &granted.params_read),
+                            scope_diff(&params, &granted.params_write),
+                        )
+                    }
+                    // The arbiter refuses the whole request if any of it isn't allowed, so find out
+                    // which parts would be granted on their own
+                    Err(e) => {
+                        println!("Arbiter refused the request: {e}");
+                        let mut tokens = Vec::new();
+                        let mut granted = |read: bool| -> Vec<String> {
+                            params
+                                .iter()
+                                .filter(|param| {
+                                    let scope = vec![param.to_string()];
+                                    let response = if read {
+                                        request_token(scope, vec![])
+                                    } else {
+                                        request_token(vec![], scope)
+                                    };
+                                    match response {
+                                        Ok(response) => {
+                                            tokens.extend(response.tokens.into_values());
+                                            true
+                                        }
+                                        Err(_) => false,
+                                    }
+                                })
+                                .cloned()
+                                .collect()
+                        };
+                        let diff = (
+                            scope_diff(&params, &granted(true)),
+                            scope_diff(&params, &granted(false)),
+                        );
+                        let unrevoked = revoke_tokens(
+                            client,
+                            self.runtime,
+                            self.timeouts,
+                            &self.controller_config.cid,
+                            &tokens,
+                        );
+                        print_probe_tokens(tokens.len(), unrevoked);
+                        diff
+                    }
+                };
+                print_scope_diff("read", &read);
+                print_scope_diff("write", &write);
+            }
+            "lasttoken" => match self.last_token.borrow().as_deref() {
+                Some(token) => match format_jwt(token) {
+                    Ok(formatted) => println!("{formatted}"),
+                    Err(e) => println!("Failed to decode token: {e}"),
+                },
+                None => println!("No control token has been requested yet"),
+            },
+            "revoke" => {
+                let jti = match line.split_whitespace().nth(1) {
+                    Some(jti) => jti.parse().map_err(anyhow::Error::from),
+                    None => match self.last_token.borrow().as_deref() {
+                        Some(token) => token_id(token),
+                        None => Err(anyhow::anyhow!("No control token has been requested yet")),
+                    },
+                };
+                let jti = match jti {
+                    Ok(jti) => jti,
+                    Err(e) => {
+                        println!("Invalid token ID: {e}");
+                        return true;
+                    }
+                };
+
+                let Some(ref client) = self.client else {
+                    println!("Not connected to Arbiter");
+                    return true;
+                };
+
+                match revoke_token(
+                    client,
+                    self.runtime,
+                    self.timeouts,
+                    &self.controller_config.cid,
+                    jti,
+                ) {
+                    Ok(()) => println!("Revoked token {jti}"),
+                    Err(e) => println!("Failed to revoke token: {e}"),
+                }
+            }
+            "p" => {
+                if self.current_devices.is_empty() {
+                    println!("No devices discovered");
+                } else {
+                    if self.devices_stale {
+                        println!("STALE: devices are from the last successful discovery");
+                    }
+                    print_devices(&self.current_devices)
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+// Why the configuration refuses a command, if it does
+fn disabled_command_reason(command: &str, config: &Config) -> Option<&'static str> {
+    match command {
+        "f" if !config.enable_attack_demos => Some("attack demos disabled"),
+        "f" if !cfg!(feature = "attack-demos") => {
+            Some("attack demos disabled, this build doesn't include the attack-demos feature")
+        }
+        _ => None,
+    }
+}
+
+fn connect_to_arbiter(
+    config: DtlsConfig,
+    addr: SocketAddr,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+) -> anyhow::Result<ArbiterClient> {
+    let config = UdpDtlsConfig {
+        config,
+        dest_addr: addr,
+    };
+    let coap = with_timeout(
+        runtime,
+        timeouts.handshake,
+        "DTLS handshake",
+        CoAPClient::from_udp_dtls_config(config),
+    )?;
+    Ok(ArbiterClient { coap, addr })
+}
+
+fn discover_devices(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    compress: bool,
+    tag: Option<&str>,
+) -> anyhow::Result<(Vec<Device>, DeviceSnapshot)> {
+    let mut request = RequestBuilder::new("/devices", Method::Get)
+        .domain(client.addr.to_string())
+        .build();
+    if let Some(tag) = tag {
+        request
+            .message
+            .add_option(CoapOption::UriQuery, format!("tag={tag}").into_bytes());
+    }
+    if compress {
+        request
+            .message
+            .add_option(CONTENT_ENCODING_OPTION, DEFLATE.to_vec());
+    }
+
+    let response = send_with_retries(&client.coap, runtime, timeouts, request)?;
+    let payload = decoded_payload(&response.message)?;
+    let signature = response
+        .message
+        .get_option(SIGNATURE_OPTION)
+        .and_then(|values| values.front())
+        .map(|value| String::from_utf8_lossy(value).into_owned());
+    Ok((
+        serde_json::from_slice(&payload)?,
+        DeviceSnapshot { payload, signature },
+    ))
+}
+
+// Polls the arbiter and prints devices joining and leaving until a line is entered on stdin.
+// Returns the last device list seen so that indices match what was displayed.
+fn live_view(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    compress: bool,
+    tag: Option<&str>,
+) -> Vec<Device> {
+    let (quit_tx, quit_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = io::stdin().read_line(&mut String::new());
+        let _ = quit_tx.send(());
+    });
+
+    println!("Live view, press Enter to stop");
+    let mut devices = vec![];
+    loop {
+        match discover_devices(client, runtime, timeouts, compress, tag) {
+            Ok((new_devices, _)) => {
+                let changes = device_changes(&devices, &new_devices);
+                for change in &changes {
+                    println!("{change}");
+                }
+                if !changes.is_empty() {
+                    print_devices(&new_devices);
+                }
+                devices = new_devices;
+            }
+            Err(e) => println!("Failed to discover devices: {e}"),
+        }
+
+        match quit_rx.recv_timeout(LIVE_VIEW_POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => break,
+        }
+    }
+    println!("Live view stopped");
+    devices
+}
+
+// What the arbiter sent for a device list, kept as-is so that its signature can be checked later
+struct DeviceSnapshot {
+    payload: Vec<u8>,
+    signature: Option<String>,
+}
+
+// The signature, if any, goes in a separate `.sig` file next to the payload
+fn save_device_snapshot(file: &str, snapshot: &DeviceSnapshot) -> anyhow::Result<()> {
+    std::fs::write(file, &snapshot.payload)?;
+    let signature_file = format!("{file}.sig");
+    match snapshot.signature {
+        Some(ref signature) => std::fs::write(signature_file, signature)?,
+        None => {
+            let _ = std::fs::remove_file(signature_file);
+        }
+    }
+    Ok(())
+}
+
+// If the arbiter's public key is configured, the snapshot must carry a valid signature from it
+fn load_device_snapshot(
+    file: &str,
+    arbiter_public_key_file: Option<&str>,
+    tag: Option<&str>,
+) -> anyhow::Result<Vec<Device>> {
+    let payload = std::fs::read(file)?;
+    if let Some(key_file) = arbiter_public_key_file {
+        let key = DecodingKey::from_ec_pem(&std::fs::read(key_file)?)?;
+        let signature =
+            std::fs::read_to_string(format!("{file}.sig")).context("Snapshot is not signed")?;
+        if !jsonwebtoken::crypto::verify(signature.trim(), &payload, &key, Algorithm::ES256)? {
+            anyhow::bail!("Snapshot signature is invalid, it may have been tampered with");
+        }
+    }
+
+    let devices: Vec<Device> = serde_json::from_slice(&payload)?;
+    Ok(devices
+        .into_iter()
+        .filter(|device| tag.is_none_or(|tag| device.tags.iter().any(|t| t == tag)))
+        .collect())
+}
+
+// One line per device that joined or left
+fn device_changes(old: &[Device], new: &[Device]) -> Vec<String> {
+    let joined = new
+        .iter()
+        .filter(|device| !old.iter().any(|d| d.cid == device.cid))
+        .map(|device| format!("+ {} ({}) joined", device.label, device.cid));
+    let left = old
+        .iter()
+        .filter(|device| !new.iter().any(|d| d.cid == device.cid))
+        .map(|device| format!("- {} ({}) left", device.label, device.cid));
+    joined.chain(left).collect()
+}
+
+// Inflates the payload if the arbiter compressed it, see the arbiter's compression module.
+fn decoded_payload(message: &Packet) -> anyhow::Result<Vec<u8>> {
+    let compressed = message
+        .get_option(CONTENT_ENCODING_OPTION)
+        .is_some_and(|values| values.iter().any(|value| value == DEFLATE));
+    if !compressed {
+        return Ok(message.payload.clone());
+    }
+
+    let mut payload = vec![];
+    DeflateDecoder::new(message.payload.as_slice()).read_to_end(&mut payload)?;
+    Ok(payload)
+}
+
+fn print_devices(devices: &[Device]) {
+    for (index, device) in devices.iter().enumerate() {
+        print!(
+            "{}: {} ({}) {} {}",
+            index, device.label, device.cid, device.manufacturer, device.model
+        );
+        if !device.tags.is_empty() {
+            print!(" [{}]", device.tags.join(", "));
+        }
+        match device.previous_port {
+            Some(port) => println!(" (moved from port {port})"),
+            None => println!(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct BulkSummary {
+    succeeded: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+fn bulk_summary(results: &[(&Device, anyhow::Result<()>)], skipped: usize) -> BulkSummary {
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    BulkSummary {
+        succeeded: results.len() - failed,
+        failed,
+        skipped,
+    }
+}
+
+fn print_bulk_results(results: &[(&Device, anyhow::Result<()>)], skipped: &[(&Device, String)]) {
+    for (device, result) in results {
+        match result {
+            Ok(()) => println!("  {} ({}): OK", device.label, device.cid),
+            Err(e) => println!("  {} ({}): FAILED: {e}", device.label, device.cid),
+        }
+    }
+    for (device, reason) in skipped {
+        println!("  {} ({}): SKIPPED: {reason}", device.label, device.cid);
+    }
+    let summary = bulk_summary(results, skipped.len());
+    println!(
+        "{} succeeded, {} failed, {} skipped",
+        summary.succeeded, summary.failed, summary.skipped
+    );
+}
+
+fn request_control_token(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    device: &Device,
+    params_read: Vec<String>,
+    params_write: Vec<String>,
+) -> anyhow::Result<ControlTokenResponse> {
+    let payload = ControlTokenRequest {
+        cid: *my_cid,
+        devices: vec![device.cid],
+        params_read,
+        params_write,
+        idempotency_key: Uuid::new_v4().to_string(),
+    };
+
+    let request = RequestBuilder::new("/controlToken", Method::Get)
+        .domain(client.addr.to_string())
+        .data(Some(serde_json::to_vec(&payload)?))
+        .build();
+
+    let response = send_with_retries(&client.coap, runtime, timeouts, request)?;
+    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
+        let token: ControlTokenResponse = serde_json::from_slice(&response.message.payload)?;
+        if let Some(ref budget) = token.rate_limit {
+            println!(
+                "Token requests left: {}, resets in {}s",
+                budget.remaining, budget.reset_secs
+            );
+        }
+        Ok(token)
+    } else {
+        Err(error_response(&response.message.payload))
+    }
+}
+
+struct TokenBenchStats {
+    requested: usize,
+    succeeded: usize,
+    elapsed: Duration,
+    // Latencies of the successful requests, sorted
+    latencies: Vec<Duration>,
+}
+
+impl TokenBenchStats {
+    fn new(requested: usize, elapsed: Duration, mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        Self {
+            requested,
+            succeeded: latencies.len(),
+            elapsed,
+            latencies,
+        }
+    }
+
+    // Successfully issued tokens per second
+    fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.succeeded as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // Nearest-rank percentile of the successful requests' latencies
+    fn percentile(&self, percent: usize) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (percent * self.latencies.len()).div_ceil(100).max(1);
+        Some(self.latencies[rank - 1])
+    }
+}
+
+fn benchmark_token_issuance(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    device: &Device,
+    count: usize,
+) -> TokenBenchStats {
+    let mut latencies = Vec::with_capacity(count);
+    let start = Instant::now();
+    for _ in 0..count {
+        let request_start = Instant::now();
+        match request_control_token(client, runtime, timeouts, my_cid, device, vec![], vec![]) {
+            Ok(_) => latencies.push(request_start.elapsed()),
+            Err(e) => println!("Token request failed: {e}"),
+        }
+    }
+    TokenBenchStats::new(count, start.elapsed(), latencies)
+}
+
+enum ProbeOutcome {
+    Readable(String),
+    // The arbiter wouldn't issue a read token
+    Denied(String),
+    // A token was issued but the read itself failed
+    ReadFailed(String),
+}
+
+struct ProbeSummary {
+    readable: usize,
+    denied: usize,
+    read_failed: usize,
+}
+
+impl ProbeSummary {
+    fn new(results: &[(String, ProbeOutcome)]) -> Self {
+        let mut summary = Self {
+            readable: 0,
+            denied: 0,
+            read_failed: 0,
+        };
+        for (_, outcome) in results {
+            match outcome {
+                ProbeOutcome::Readable(_) => summary.readable += 1,
+                ProbeOutcome::Denied(_) => summary.denied += 1,
+                ProbeOutcome::ReadFailed(_) => summary.read_failed += 1,
+            }
+        }
+        summary
+    }
+}
+
+// Requests a separate read token for each parameter, so that one forbidden parameter doesn't
+// hide the others. Also returns the tokens that were issued so they can be revoked afterwards.
+fn probe_device(
+    client: &ArbiterClient,
+    config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    device: &Device,
+    params: &[ParamDescription],
+) -> (Vec<(String, ProbeOutcome)>, Vec<String>) {
+    let mut tokens = Vec::new();
+    let results = params
+        .iter()
+        .map(|param| {
+            let outcome = match request_control_token(
+                client,
+                runtime,
+                timeouts,
+                my_cid,
+                device,
+                vec![param.name.clone()],
+                vec![],
+            ) {
+                Err(e) => ProbeOutcome::Denied(e.to_string()),
+                Ok(response) => {
+                    let token = device_token(&response, device).cloned();
+                    tokens.extend(response.tokens.into_values());
+                    match token.and_then(|token| {
+                        send_request(
+                            config.clone(),
+                            runtime,
+                            timeouts,
+                            RequestType::Get,
+                            device.port,
+                            token,
+                            &param.name,
+                            None,
+                            false,
+                        )
+                    }) {
+                        Ok(value) => ProbeOutcome::Readable(value.unwrap_or_default()),
+                        Err(e) => ProbeOutcome::ReadFailed(e.to_string()),
+                    }
+                }
+            };
+            (param.name.clone(), outcome)
+        })
+        .collect();
+    (results, tokens)
+}
+
+// The arbiter may leave a device out of a response, e.g. if it deregistered in the meantime
+fn device_token<'a>(
+    response: &'a ControlTokenResponse,
+    device: &Device,
+) -> anyhow::Result<&'a String> {
+    response
+        .tokens
+        .get(&device.cid)
+        .ok_or_else(|| anyhow::anyhow!("Arbiter didn't issue a token for device {}", device.cid))
+}
+
+// Revokes tokens that were only needed for a single check, returning how many couldn't be revoked
+fn revoke_tokens(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    tokens: &[String],
+) -> usize {
+    tokens
+        .iter()
+        .filter(|token| {
+            token_id(token)
+                .and_then(|jti| revoke_token(client, runtime, timeouts, my_cid, jti))
+                .is_err()
+        })
+        .count()
+}
+
+fn print_probe_tokens(issued: usize, unrevoked: usize) {
+    if issued == 0 {
+        return;
+    }
+    if unrevoked == 0 {
+        println!("Issued and revoked {issued} probe tokens");
+    } else {
+        println!(
+            "Issued {issued} probe tokens; {unrevoked} couldn't be revoked and stay valid until they expire"
+        );
+    }
+}
+
+fn print_probe_results(results: &[(String, ProbeOutcome)]) {
+    if results.is_empty() {
+        println!("Device advertises no parameters");
+        return;
+    }
+    let width = results.iter().map(|(name, _)| name.len()).max().unwrap();
+    for (name, outcome) in results {
+        match outcome {
+            ProbeOutcome::Readable(value) => println!("  {name:width$}  readable  {value}"),
+            ProbeOutcome::Denied(reason) => println!("  {name:width$}  denied    {reason}"),
+            ProbeOutcome::ReadFailed(reason) => println!("  {name:width$}  failed    {reason}"),
+        }
+    }
+    let summary = ProbeSummary::new(results);
+    println!(
+        "{} readable, {} denied, {} failed",
+        summary.readable, summary.denied, summary.read_failed
+    );
+}
+
+// The scope a control token grants, read from its claims without verifying it
+#[derive(Deserialize)]
+struct GrantedScope {
+    #[serde(default)]
+    params_read: Vec<String>,
+    #[serde(default)]
+    params_write: Vec<String>,
+}
+
+fn granted_scope(token: &str) -> anyhow::Result<GrantedScope> {
+    let Some(claims) = token.split('.').nth(1) else {
+        anyhow::bail!("Token doesn't have a claims segment");
+    };
+    Ok(serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?)
+}
+
+struct ScopeDiff {
+    granted: Vec<String>,
+    // Requested but not granted
+    missing: Vec<String>,
+    // Granted without being requested
+    extra: Vec<String>,
+}
+
+fn scope_diff(requested: &[String], granted: &[String]) -> ScopeDiff {
+    let (granted_requested, missing) = requested
+        .iter()
+        .cloned()
+        .partition(|param| granted.contains(param));
+    ScopeDiff {
+        granted: granted_requested,
+        missing,
+        extra: granted
+            .iter()
+            .filter(|param| !requested.contains(param))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn print_scope_diff(kind: &str, diff: &ScopeDiff) {
+    for param in &diff.granted {
+        println!("  {kind} {param}: granted");
+    }
+    for param in &diff.missing {
+        println!("  {kind} {param}: NOT granted");
+    }
+    for param in &diff.extra {
+        println!("  {kind} {param}: granted without being requested");
+    }
+}
+
+fn print_token_bench_stats(stats: &TokenBenchStats) {
+    println!(
+        "Issued {}/{} tokens in {:.2?} ({:.1} tokens/s)",
+        stats.succeeded,
+        stats.requested,
+        stats.elapsed,
+        stats.throughput()
+    );
+    if let (Some(p50), Some(p90), Some(p99), Some(max)) = (
+        stats.percentile(50),
+        stats.percentile(90),
+        stats.percentile(99),
+        stats.latencies.last(),
+    ) {
+        println!("Latency: p50 {p50:.2?}, p90 {p90:.2?}, p99 {p99:.2?}, max {max:.2?}");
+    }
+}
+
+fn proxied_read(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    device: &Device,
+    parameter: &str,
+) -> anyhow::Result<String> {
+    let request = RequestBuilder::new(
+        &format!("/devices/{}/params/{parameter}", device.cid),
+        Method::Get,
+    )
+    .domain(client.addr.to_string())
+    .data(Some(serde_json::to_vec(&ProxyReadPayload {
+        cid: *my_cid,
+    })?))
+    .build();
+
+    let response = send_with_timeout(&client.coap, runtime, timeouts, request)?;
+    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
+        Ok(String::from_utf8(response.message.payload)?)
+    } else {
+        Err(error_response(&response.message.payload))
+    }
+}
+
+fn revoke_token(
+    client: &ArbiterClient,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    my_cid: &Uuid,
+    jti: Uuid,
+) -> anyhow::Result<()> {
+    let request = RequestBuilder::new("/revoke", Method::Post)
+        .domain(client.addr.to_string())
+        .data(Some(serde_json::to_vec(&RevokePayload {
+            cid: *my_cid,
+            jti,
+        })?))
+        .build();
+
+    let response = send_with_retries(&client.coap, runtime, timeouts, request)?;
+    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
+        Ok(())
+    } else {
+        Err(error_response(&response.message.payload))
+    }
+}
+
+fn token_id(token: &str) -> anyhow::Result<Uuid> {
+    let Some(claims) = token.split('.').nth(1) else {
+        anyhow::bail!("Token doesn't have a claims segment");
+    };
+    let id: TokenId = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+    Ok(id.jti)
+}
+
+fn connect_to_device(
+    mut config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    port: u16,
+) -> anyhow::Result<CoAPClient<DtlsConnection>> {
+    config.server_name = "device.local".to_string();
+    let config = UdpDtlsConfig {
+        config,
+        dest_addr: ("127.0.0.1", port)
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap(),
+    };
+    with_timeout(
+        runtime,
+        timeouts.handshake,
+        "DTLS handshake",
+        CoAPClient::from_udp_dtls_config(config),
+    )
+}
+
+fn send_with_timeout(
+    client: &CoAPClient<DtlsConnection>,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    request: CoapRequest<SocketAddr>,
+) -> anyhow::Result<CoapResponse> {
+    with_timeout(runtime, timeouts.request, "Request", client.send(request))
+}
+
+// Resends the same request, so the arbiter can recognise a retried token request by its
+// idempotency key
+fn send_with_retries(
+    client: &CoAPClient<DtlsConnection>,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    request: CoapRequest<SocketAddr>,
+) -> anyhow::Result<CoapResponse> {
+    let mut backoff = timeouts.retry_backoff;
+    for _ in 0..timeouts.retries {
+        match send_with_timeout(client, runtime, timeouts, request.clone()) {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                println!("Request failed: {e}, retrying in {backoff:?}");
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+    send_with_timeout(client, runtime, timeouts, request)
+}
+
+fn with_timeout<T>(
+    runtime: &tokio::runtime::Runtime,
+    timeout: Duration,
+    description: &str,
+    future: impl Future<Output = io::Result<T>>,
+) -> anyhow::Result<T> {
+    runtime
+        .block_on(async { tokio::time::timeout(timeout, future).await })
+        .map_err(|_| anyhow::anyhow!("{description} timed out after {timeout:?}"))?
+        .map_err(Into::into)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_request(
+    config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    request_type: RequestType,
+    port: u16,
+    token: String,
+    parameter: &str,
+    value: Option<PutValue>,
+    accept_json: bool,
+) -> anyhow::Result<Option<String>> {
+    let client = connect_to_device(config, runtime, timeouts, port)?;
+
+    let payload = match request_type {
+        RequestType::Get => serde_json::to_vec(&GetParamPayload { token }).unwrap(),
+        RequestType::Put => serde_json::to_vec(&SetParamPayload {
+            token,
+            value: value.unwrap(),
+        })
+        .unwrap(),
+    };
+
+    let mut request = RequestBuilder::new(&format!("/{parameter}"), request_type.into())
+        .domain(format!("127.0.0.1:{port}"))
+        .data(Some(payload))
+        .build();
+    if accept_json {
+        request.message.add_option_as(
+            CoapOption::Accept,
+            OptionValueU16(usize::from(ContentFormat::ApplicationJSON) as u16),
+        );
+    }
+
+    let response = send_with_timeout(&client, runtime, timeouts, request)?;
+
+    match (request_type, response.message.header.code) {
+        (RequestType::Get, MessageClass::Response(ResponseType::Content)) => {
+            Ok(Some(String::from_utf8(response.message.payload)?))
+        }
+        (RequestType::Put, MessageClass::Response(ResponseType::Content)) => Ok(None),
+        (_, MessageClass::Response(ResponseType::Unauthorized)) => {
+            Err(TokenExpired(error_response(&response.message.payload).to_string()).into())
+        }
+        _ => Err(error_response(&response.message.payload)),
+    }
+}
+
+// Devices answer 4.01 to a token that has expired, which a fresh token may fix
+#[derive(Debug)]
+struct TokenExpired(String);
+
+impl Display for TokenExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TokenExpired {}
+
+#[derive(Deserialize)]
+struct DeviceError {
+    reason: String,
+    message: String,
+}
+
+// Devices describe errors as JSON with a machine-readable reason; the arbiter uses plain text
+fn error_response(payload: &[u8]) -> anyhow::Error {
+    match serde_json::from_slice::<DeviceError>(payload) {
+        Ok(error) => anyhow::anyhow!("{} ({})", error.message, error.reason),
+        Err(_) => anyhow::anyhow!(String::from_utf8_lossy(payload).into_owned()),
+    }
+}
+
+// Encrypted to the device if encryptValues is set and the device registered a key
+fn put_value(
+    config: &Config,
+    device: &Device,
+    parameter: &str,
+    value: &str,
+) -> anyhow::Result<PutValue> {
+    match device.encryption_key {
+        Some(ref key) if config.encrypt_values => Ok(PutValue::EncryptedValue(encrypt_value(
+            key,
+            &device.cid,
+            parameter,
+            value,
+        )?)),
+        None if config.encrypt_values => {
+            println!(
+                "Device {} has no encryption key, sending the value in the clear",
+                device.label
+            );
+            Ok(PutValue::Value(value.to_string()))
+        }
+        _ => Ok(PutValue::Value(value.to_string())),
+    }
+}
+
+fn validate_token_on_device(
+    config: DtlsConfig,
     runtime: &tokio::runtime::Runtime,
-    request_type: RequestType,
+    timeouts: Timeouts,
     port: u16,
     token: String,
+    params_read: Vec<String>,
+    params_write: Vec<String>,
+) -> anyhow::Result<ValidateTokenResponse> {
+    let client = connect_to_device(config, runtime, timeouts, port)?;
+
+    let payload = ValidateTokenPayload {
+        token,
+        params_read,
+        params_write,
+    };
+    let request = RequestBuilder::new("/validateToken", Method::Post)
+        .domain(format!("127.0.0.1:{port}"))
+        .data(Some(serde_json::to_vec(&payload)?))
+        .build();
+
+    let response = send_with_timeout(&client, runtime, timeouts, request)?;
+    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
+        Ok(serde_json::from_slice(&response.message.payload)?)
+    } else {
+        Err(error_response(&response.message.payload))
+    }
+}
+
+fn list_device_parameters(
+    config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    port: u16,
+) -> anyhow::Result<Vec<ParamDescription>> {
+    let client = connect_to_device(config, runtime, timeouts, port)?;
+
+    let request = RequestBuilder::new("/params", Method::Get)
+        .domain(format!("127.0.0.1:{port}"))
+        .build();
+
+    let response = send_with_timeout(&client, runtime, timeouts, request)?;
+    if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
+        Ok(serde_json::from_slice(&response.message.payload)?)
+    } else {
+        Err(error_response(&response.message.payload))
+    }
+}
+
+// Going by what the device advertised to the arbiter, or else its GET /params listing. None if
+// neither is known.
+fn declares_parameter(
+    device: &Device,
+    schema: Option<&[ParamDescription]>,
     parameter: &str,
-    value: Option<String>,
-) -> anyhow::Result<Option<String>> {
+) -> Option<bool> {
+    if let Some(ref advertised) = device.parameters {
+        return Some(advertised.iter().any(|name| name == parameter));
+    }
+    schema.map(|params| params.iter().any(|param| param.name == parameter))
+}
+
+fn parameter_type(
+    schemas: &mut HashMap<Uuid, Vec<ParamDescription>>,
+    config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    device: &Device,
+    parameter: &str,
+) -> Option<ParamType> {
+    let params = match schemas.entry(device.cid) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        // Devices that don't advertise a schema just get their values printed as they are
+        Entry::Vacant(entry) => {
+            entry.insert(list_device_parameters(config, runtime, timeouts, device.port).ok()?)
+        }
+    };
+    params
+        .iter()
+        .find(|param| param.name == parameter)
+        .and_then(|param| param.ty)
+}
+
+fn print_get_result(result: &str, parameter: &str, ty: Option<ParamType>) {
+    match ty {
+        Some(ty) if !ty.validate(result) => {
+            println!("Got GET result: {result}");
+            println!(
+                "Warning: {parameter} is declared as {ty:?} but the device returned {result:?}"
+            );
+        }
+        Some(ty) => println!("Got GET result: {result} ({ty:?})"),
+        None => println!("Got GET result: {result}"),
+    }
+}
+
+fn print_parameters(params: &[ParamDescription]) {
+    if params.is_empty() {
+        println!("Device advertises no parameters");
+    }
+    for param in params {
+        let ty = param.ty.map(|ty| format!(" ({ty:?})")).unwrap_or_default();
+        match param.description {
+            Some(ref description) => println!("  {}{ty}: {description}", param.name),
+            None => println!("  {}{ty}", param.name),
+        }
+    }
+}
+
+fn print_token_validation(result: &ValidateTokenResponse) {
+    if !result.valid {
+        println!(
+            "Token rejected by device: {}",
+            result.error.as_deref().unwrap_or("unknown error")
+        );
+    }
+    for param in &result.allowed_read {
+        println!("  GET /{param}: allowed");
+    }
+    for param in &result.forbidden_read {
+        println!("  GET /{param}: forbidden");
+    }
+    for param in &result.allowed_write {
+        println!("  PUT /{param}: allowed");
+    }
+    for param in &result.forbidden_write {
+        println!("  PUT /{param}: forbidden");
+    }
+}
+
+fn fetch_device_certificates(
+    mut config: DtlsConfig,
+    runtime: &tokio::runtime::Runtime,
+    timeouts: Timeouts,
+    port: u16,
+) -> anyhow::Result<Vec<RustlsCertificate>> {
+    // Accept whatever the device presents so that an untrusted chain can still be displayed;
+    // validation against our root CA happens separately afterwards.
+    let captured = Arc::new(Mutex::new(vec![]));
+    let captured_clone = captured.clone();
     config.server_name = "device.local".to_string();
+    config.insecure_skip_verify = true;
+    config.verify_peer_certificate = Some(Arc::new(move |certs: &[Vec<u8>], _| {
+        *captured_clone.lock().unwrap() = certs.to_vec();
+        Ok(())
+    }));
+
     let config = UdpDtlsConfig {
         config,
         dest_addr: ("127.0.0.1", port)
@@ -420,46 +2341,79 @@ fn send_request(
             .next()
             .unwrap(),
     };
-    let client = runtime.block_on(async move { CoAPClient::from_udp_dtls_config(config).await })?;
+    with_timeout(
+        runtime,
+        timeouts.handshake,
+        "DTLS handshake",
+        DtlsConnection::try_new(config),
+    )?;
 
-    let payload = match request_type {
-        RequestType::Get => serde_json::to_vec(&GetParamPayload { token }).unwrap(),
-        RequestType::Put => serde_json::to_vec(&SetParamPayload {
-            token,
-            value: value.unwrap(),
-        })
-        .unwrap(),
-    };
+    let certs = captured
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(RustlsCertificate)
+        .collect();
+    Ok(certs)
+}
 
-    let request = RequestBuilder::new(&format!("/{parameter}"), request_type.into())
-        .domain(format!("127.0.0.1:{port}"))
-        .data(Some(payload))
-        .build();
+fn validate_certificate_chain(
+    chain: &[RustlsCertificate],
+    roots: &RootCertStore,
+    server_name: &str,
+) -> anyhow::Result<()> {
+    let (end_entity, intermediates) = chain
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("No certificates presented"))?;
 
-    let response = runtime.block_on(async move { client.send(request).await })?;
+    WebPkiVerifier::new(roots.clone(), None).verify_server_cert(
+        end_entity,
+        intermediates,
+        &ServerName::try_from(server_name)?,
+        &mut std::iter::empty(),
+        &[],
+        SystemTime::now(),
+    )?;
+    Ok(())
+}
 
-    match request_type {
-        RequestType::Get => {
-            if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
-                Ok(Some(String::from_utf8(response.message.payload)?))
-            } else {
-                Err(anyhow::anyhow!(
-                    String::from_utf8(response.message.payload).unwrap()
-                ))
-            }
-        }
-        RequestType::Put => {
-            if let MessageClass::Response(ResponseType::Content) = response.message.header.code {
-                Ok(None)
-            } else {
-                Err(anyhow::anyhow!(
-                    String::from_utf8(response.message.payload).unwrap()
-                ))
+fn print_certificate_chain(chain: &[RustlsCertificate], roots: &RootCertStore) {
+    for (index, cert) in chain.iter().enumerate() {
+        match parse_x509_certificate(&cert.0) {
+            Ok((_, parsed)) => {
+                println!("{index}: Subject:    {}", parsed.subject());
+                println!("   Issuer:     {}", parsed.issuer());
+                println!("   Not before: {}", parsed.validity().not_before);
+                println!("   Not after:  {}", parsed.validity().not_after);
             }
+            Err(e) => println!("{index}: Couldn't parse certificate: {e}"),
         }
     }
+
+    match validate_certificate_chain(chain, roots, "device.local") {
+        Ok(()) => println!("Certificate chain is valid for the configured root CA"),
+        Err(e) => println!("Certificate chain is NOT valid for the configured root CA: {e}"),
+    }
+}
+
+// Shows a JWT's header, claims and signature for inspection, without verifying it
+fn format_jwt(token: &str) -> anyhow::Result<String> {
+    let [header, claims, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+        anyhow::bail!("Token doesn't have three segments");
+    };
+    let decode_json = |segment: &str| -> anyhow::Result<String> {
+        let json: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(segment)?)?;
+        Ok(serde_json::to_string_pretty(&json)?)
+    };
+    let signature_len = URL_SAFE_NO_PAD.decode(signature)?.len();
+    Ok(format!(
+        "Header: {}\nClaims: {}\nSignature ({signature_len} bytes, not verified): {signature}",
+        decode_json(header)?,
+        decode_json(claims)?,
+    ))
 }
 
+#[cfg(feature = "attack-demos")]
 fn tamper_with_token(token: &str, new_audience: String) -> String {
     let token_parts: Vec<&str> = token.split('.').collect();
     let payload_decoded = URL_SAFE.decode(token_parts[1].as_bytes()).unwrap();
@@ -472,3 +2426,775 @@ fn tamper_with_token(token: &str, new_audience: String) -> String {
     let payload_encoded = URL_SAFE.encode(payload_encoded.as_bytes());
     format!("{}.{}.{}", token_parts[0], payload_encoded, token_parts[2])
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use webrtc_util::conn::Listener as _;
+
+    use super::*;
+
+    fn device(parameters: Option<&[&str]>) -> Device {
+        serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "port": 47111,
+            "ttl": 3600,
+            "parameters": parameters,
+        }))
+        .unwrap()
+    }
+
+    fn schema(names: &[&str]) -> Vec<ParamDescription> {
+        names
+            .iter()
+            .map(|name| ParamDescription {
+                name: name.to_string(),
+                description: None,
+                ty: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn advertised_parameters_decide_before_the_schema() {
+        let advertised = device(Some(&["temp"]));
+        assert_eq!(declares_parameter(&advertised, None, "temp"), Some(true));
+        assert_eq!(
+            declares_parameter(&advertised, Some(&schema(&["mode"])), "mode"),
+            Some(false)
+        );
+
+        let unadvertised = device(None);
+        assert_eq!(declares_parameter(&unadvertised, None, "temp"), None);
+        assert_eq!(
+            declares_parameter(&unadvertised, Some(&schema(&["temp"])), "temp"),
+            Some(true)
+        );
+        assert_eq!(
+            declares_parameter(&unadvertised, Some(&schema(&["temp"])), "mode"),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn bulk_summary_counts_each_outcome() {
+        let devices = [device(None), device(None), device(None)];
+        let results = vec![
+            (&devices[0], Ok(())),
+            (&devices[1], Err(anyhow::anyhow!("No permission"))),
+            (&devices[2], Ok(())),
+        ];
+        assert_eq!(
+            bulk_summary(&results, 2),
+            BulkSummary {
+                succeeded: 2,
+                failed: 1,
+                skipped: 2,
+            }
+        );
+        assert_eq!(
+            bulk_summary(&[], 0),
+            BulkSummary {
+                succeeded: 0,
+                failed: 0,
+                skipped: 0,
+            }
+        );
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn scope_diff_splits_granted_missing_and_extra() {
+        let diff = scope_diff(&names(&["temp", "mode"]), &names(&["temp", "fan"]));
+        assert_eq!(diff.granted, names(&["temp"]));
+        assert_eq!(diff.missing, names(&["mode"]));
+        assert_eq!(diff.extra, names(&["fan"]));
+
+        let diff = scope_diff(&names(&["temp"]), &[]);
+        assert!(diff.granted.is_empty());
+        assert_eq!(diff.missing, names(&["temp"]));
+        assert!(diff.extra.is_empty());
+    }
+
+    #[test]
+    fn missing_device_token_is_an_error() {
+        let response = ControlTokenResponse {
+            tokens: HashMap::from([(Uuid::from_u128(1), "token".to_string())]),
+            rate_limit: None,
+        };
+        assert!(device_token(&response, &device(None)).is_err());
+    }
+
+    #[test]
+    fn request_type_parses_only_g_and_s() {
+        assert_eq!(RequestType::try_from("g"), Ok(RequestType::Get));
+        assert_eq!(RequestType::try_from("s"), Ok(RequestType::Put));
+        assert!(RequestType::try_from("x").is_err());
+    }
+
+    #[test]
+    fn compressed_payload_is_inflated() {
+        use std::io::Write;
+
+        let payload = br#"{"devices":[]}"#.repeat(20);
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&payload).unwrap();
+        let mut message = Packet::new();
+        message.payload = encoder.finish().unwrap();
+        message.add_option(CONTENT_ENCODING_OPTION, DEFLATE.to_vec());
+        assert_eq!(decoded_payload(&message).unwrap(), payload);
+
+        // Uncompressed payloads are passed through as they are
+        let mut message = Packet::new();
+        message.payload = payload.clone();
+        assert_eq!(decoded_payload(&message).unwrap(), payload);
+    }
+
+    fn ca(name: &str) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn device_cert(signer: &rcgen::Certificate) -> RustlsCertificate {
+        let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "device.local".to_string(),
+        ]))
+        .unwrap();
+        RustlsCertificate(cert.serialize_der_with_signer(signer).unwrap())
+    }
+
+    #[test]
+    fn certificate_chain_is_only_valid_if_it_leads_to_a_configured_root() {
+        let root = ca("root");
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&RustlsCertificate(root.serialize_der().unwrap()))
+            .unwrap();
+
+        let good = [device_cert(&root)];
+        assert!(validate_certificate_chain(&good, &roots, "device.local").is_ok());
+        assert!(validate_certificate_chain(&good, &roots, "other.local").is_err());
+
+        let bad = [device_cert(&ca("impostor"))];
+        assert!(validate_certificate_chain(&bad, &roots, "device.local").is_err());
+        assert!(validate_certificate_chain(&[], &roots, "device.local").is_err());
+    }
+
+    #[test]
+    fn attack_demos_are_refused_unless_enabled() {
+        let config = |enable_attack_demos: bool| -> Config {
+            serde_json::from_value(json!({
+                "cid": Uuid::nil(),
+                "enableAttackDemos": enable_attack_demos,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            disabled_command_reason("f", &config(false)),
+            Some("attack demos disabled")
+        );
+        assert_eq!(
+            disabled_command_reason("f", &config(true)).is_none(),
+            cfg!(feature = "attack-demos")
+        );
+        assert_eq!(disabled_command_reason("s", &config(false)), None);
+    }
+
+    fn timeouts(handshake_ms: u64, request_ms: u64) -> Timeouts {
+        Timeouts {
+            handshake: Duration::from_millis(handshake_ms),
+            request: Duration::from_millis(request_ms),
+            retries: 0,
+            retry_backoff: Duration::ZERO,
+        }
+    }
+
+    fn client_config() -> DtlsConfig {
+        DtlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        }
+    }
+
+    // A DTLS server on an ephemeral port with a throwaway certificate, and the port
+    fn dtls_listener(runtime: &tokio::runtime::Runtime) -> (impl webrtc_util::conn::Listener, u16) {
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["device.local".to_string()]);
+        params.key_pair = Some(key_pair);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let server_config = DtlsConfig {
+            certificates: vec![webrtc_dtls::crypto::Certificate {
+                certificate: vec![RustlsCertificate(cert.serialize_der().unwrap())],
+                private_key: webrtc_dtls::crypto::CryptoPrivateKey::from_key_pair(
+                    cert.get_key_pair(),
+                )
+                .unwrap(),
+            }],
+            ..Default::default()
+        };
+        let listener = runtime
+            .block_on(webrtc_dtls::listener::listen("127.0.0.1:0", server_config))
+            .unwrap();
+        let port = runtime.block_on(listener.addr()).unwrap().port();
+        (listener, port)
+    }
+
+    #[test]
+    fn handshake_timeout_fires_for_a_peer_that_never_answers() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+
+        let started = Instant::now();
+        let e = connect_to_device(client_config(), &runtime, timeouts(200, 60_000), port)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "DTLS handshake timed out after 200ms");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn request_timeout_fires_once_connected_to_a_peer_that_never_responds() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (listener, port) = dtls_listener(&runtime);
+        // Completes handshakes, then holds on to the connection without ever reading from it
+        runtime.spawn(async move {
+            let _conn = listener.accept().await;
+            std::future::pending::<()>().await
+        });
+
+        let timeouts = timeouts(60_000, 200);
+        let client = connect_to_device(client_config(), &runtime, timeouts, port).unwrap();
+        let request = RequestBuilder::new("/temp", Method::Get)
+            .domain(format!("127.0.0.1:{port}"))
+            .build();
+        let e = send_with_timeout(&client, &runtime, timeouts, request)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Request timed out after 200ms");
+    }
+
+    #[test]
+    fn live_view_reports_devices_joining_and_leaving() {
+        let named = |n: u128, label: &str| Device {
+            cid: Uuid::from_u128(n),
+            label: label.to_string(),
+            ..device(None)
+        };
+        let sequence = [
+            vec![],
+            vec![named(1, "lobby")],
+            vec![named(1, "lobby"), named(2, "roof")],
+            vec![named(1, "lobby"), named(2, "roof")],
+            vec![named(2, "roof")],
+        ];
+        let changes: Vec<_> = sequence
+            .windows(2)
+            .map(|pair| device_changes(&pair[0], &pair[1]))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                vec![format!("+ lobby ({}) joined", Uuid::from_u128(1))],
+                vec![format!("+ roof ({}) joined", Uuid::from_u128(2))],
+                vec![],
+                vec![format!("- lobby ({}) left", Uuid::from_u128(1))],
+            ]
+        );
+    }
+
+    // Stands in for the arbiter or a device, answering everything with the given payload. Returns
+    // its port and the "{method} {path}" of each request it gets.
+    fn fake_server(
+        runtime: &tokio::runtime::Runtime,
+        payload: serde_json::Value,
+    ) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let (listener, port) = dtls_listener(runtime);
+        let received = Arc::new(Mutex::new(vec![]));
+        // Server::from_listeners needs to be called from within the runtime
+        let _runtime_context = runtime.enter();
+        let server = coap::Server::from_listeners(vec![Box::new(listener)]);
+        let payload = serde_json::to_vec(&payload).unwrap();
+        runtime.spawn(server.run({
+            let received = received.clone();
+            move |mut request: Box<CoapRequest<SocketAddr>>| {
+                let received = received.clone();
+                let payload = payload.clone();
+                async move {
+                    received.lock().unwrap().push(format!(
+                        "{:?} {}",
+                        request.get_method(),
+                        request.get_path()
+                    ));
+                    request.response.as_mut().unwrap().message.payload = payload;
+                    request
+                }
+            }
+        }));
+        (port, received)
+    }
+
+    #[test]
+    fn exec_connects_discovers_and_runs_the_command() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (port, received) = fake_server(&runtime, json!([]));
+
+        let controller_config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "arbiterAddr": format!("127.0.0.1:{port}"),
+        }))
+        .unwrap();
+        let jti = Uuid::from_u128(1);
+        assert!(run_once(
+            client_config(),
+            &controller_config,
+            runtime,
+            &format!("revoke {jti}")
+        ));
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["Get devices".to_string(), "Post revoke".to_string()]
+        );
+    }
+
+    #[test]
+    fn get_results_are_checked_against_the_declared_type() {
+        for (ty, good, bad) in [
+            (ParamType::Int, "21", "21.5"),
+            (ParamType::Float, "21.5", "warm"),
+            (ParamType::Bool, "true", "yes"),
+        ] {
+            assert!(ty.validate(good), "{good:?} should be a valid {ty:?}");
+            assert!(!ty.validate(bad), "{bad:?} shouldn't be a valid {ty:?}");
+        }
+        assert!(ParamType::String.validate("anything"));
+    }
+
+    #[test]
+    fn token_bench_stats_from_fixed_timings() {
+        let ms = Duration::from_millis;
+        // 10 requested, 8 issued, in 2s
+        let stats = TokenBenchStats::new(
+            10,
+            Duration::from_secs(2),
+            [40, 10, 20, 30, 80, 50, 70, 60].map(ms).to_vec(),
+        );
+        assert_eq!(stats.requested, 10);
+        assert_eq!(stats.succeeded, 8);
+        assert_eq!(stats.throughput(), 4.0);
+        assert_eq!(stats.percentile(50), Some(ms(40)));
+        assert_eq!(stats.percentile(90), Some(ms(80)));
+        assert_eq!(stats.percentile(0), Some(ms(10)));
+
+        let nothing_issued = TokenBenchStats::new(3, Duration::ZERO, vec![]);
+        assert_eq!(nothing_issued.throughput(), 0.0);
+        assert_eq!(nothing_issued.percentile(50), None);
+    }
+
+    #[test]
+    fn offline_discovery_loads_the_saved_snapshot() {
+        let file =
+            std::env::temp_dir().join(format!("controller-snapshot-{}.json", Uuid::new_v4()));
+        let file = file.to_str().unwrap().to_string();
+        let device = |cid: u128, tags: &[&str]| {
+            json!({
+                "cid": Uuid::from_u128(cid),
+                "label": "test",
+                "manufacturer": "ACME",
+                "model": "Test",
+                "port": 47111,
+                "ttl": 3600,
+                "tags": tags,
+            })
+        };
+        let snapshot = DeviceSnapshot {
+            payload: serde_json::to_vec(&[device(1, &["kitchen"]), device(2, &[])]).unwrap(),
+            signature: None,
+        };
+        save_device_snapshot(&file, &snapshot).unwrap();
+
+        let controller_config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "deviceSnapshotFile": file,
+        }))
+        .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut session = Session::new(client_config(), &controller_config, &runtime);
+
+        // Never connected, so the arbiter is unreachable
+        assert!(session.run_command("d"));
+        assert!(session.devices_stale);
+        assert_eq!(session.current_devices.len(), 2);
+
+        assert!(session.run_command("d kitchen"));
+        assert_eq!(session.current_devices.len(), 1);
+        assert_eq!(session.current_devices[0].cid, Uuid::from_u128(1));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn last_token_is_shown_segment_by_segment() {
+        let token = [
+            URL_SAFE_NO_PAD.encode(br#"{"alg":"ES256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(br#"{"aud":"device","params_read":["temp"]}"#),
+            URL_SAFE_NO_PAD.encode([0xab; 64]),
+        ]
+        .join(".");
+
+        let formatted = format_jwt(&token).unwrap();
+        assert!(formatted.starts_with("Header: {\n  \"alg\": \"ES256\",\n  \"typ\": \"JWT\"\n}\n"));
+        assert!(formatted.contains("Claims: {\n  \"aud\": \"device\",\n  \"params_read\": [\n"));
+        assert!(formatted.ends_with(&format!(
+            "Signature (64 bytes, not verified): {}",
+            URL_SAFE_NO_PAD.encode([0xab; 64])
+        )));
+
+        assert!(format_jwt("header.claims").is_err());
+    }
+
+    #[test]
+    fn snapshot_must_carry_a_valid_signature_once_the_arbiter_key_is_set() {
+        let dir = std::env::temp_dir().join(format!("controller-snapshot-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        std::fs::write(path("arbiter.pub"), key_pair.public_key_pem()).unwrap();
+        let payload = serde_json::to_vec(&[json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "port": 47111,
+            "ttl": 3600,
+        })])
+        .unwrap();
+        let signature = jsonwebtoken::crypto::sign(
+            &payload,
+            &jsonwebtoken::EncodingKey::from_ec_der(&key_pair.serialize_der()),
+            Algorithm::ES256,
+        )
+        .unwrap();
+        let load = |file: &str| load_device_snapshot(&path(file), Some(&path("arbiter.pub")), None);
+
+        let signed = DeviceSnapshot {
+            payload: payload.clone(),
+            signature: Some(signature.clone()),
+        };
+        save_device_snapshot(&path("signed.json"), &signed).unwrap();
+        assert_eq!(load("signed.json").unwrap().len(), 1);
+
+        let mut tampered = payload.clone();
+        tampered.splice(..0, b" ".iter().copied());
+        let tampered = DeviceSnapshot {
+            payload: tampered,
+            signature: Some(signature),
+        };
+        save_device_snapshot(&path("tampered.json"), &tampered).unwrap();
+        assert!(load("tampered.json")
+            .unwrap_err()
+            .to_string()
+            .contains("tampered"));
+
+        let unsigned = DeviceSnapshot {
+            payload,
+            signature: None,
+        };
+        save_device_snapshot(&path("unsigned.json"), &unsigned).unwrap();
+        assert!(load("unsigned.json")
+            .unwrap_err()
+            .to_string()
+            .contains("not signed"));
+        // Without the key, nothing is checked
+        assert!(load_device_snapshot(&path("unsigned.json"), None, None).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn connect_discovers_devices_when_auto_discover_is_set() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let device = json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "port": 47111,
+            "ttl": 3600,
+        });
+        let (port, received) = fake_server(&runtime, json!([device]));
+        let config = |auto_discover: bool| -> Config {
+            serde_json::from_value(json!({
+                "cid": Uuid::nil(),
+                "arbiterAddr": format!("127.0.0.1:{port}"),
+                "autoDiscoverOnConnect": auto_discover,
+            }))
+            .unwrap()
+        };
+
+        let manual = config(false);
+        let mut session = Session::new(client_config(), &manual, &runtime);
+        assert!(session.run_command("c"));
+        assert!(session.client.is_some());
+        assert!(session.current_devices.is_empty());
+        assert!(received.lock().unwrap().is_empty());
+
+        let auto = config(true);
+        let mut session = Session::new(client_config(), &auto, &runtime);
+        assert!(session.run_command("c"));
+        assert_eq!(session.current_devices.len(), 1);
+        assert_eq!(*received.lock().unwrap(), ["Get devices".to_string()]);
+    }
+
+    #[test]
+    fn discovery_is_retried_after_a_request_goes_unanswered() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (listener, port) = dtls_listener(&runtime);
+        // Stands in for an arbiter whose first answer gets lost
+        let requests = Arc::new(Mutex::new(0));
+        {
+            let _runtime_context = runtime.enter();
+            let server = coap::Server::from_listeners(vec![Box::new(listener)]);
+            runtime.spawn(server.run({
+                let requests = requests.clone();
+                move |mut request: Box<CoapRequest<SocketAddr>>| {
+                    let requests = requests.clone();
+                    async move {
+                        let mut count = requests.lock().unwrap();
+                        *count += 1;
+                        if *count == 1 {
+                            request.response = None;
+                        } else {
+                            request.response.as_mut().unwrap().message.payload = b"[]".to_vec();
+                        }
+                        request
+                    }
+                }
+            }));
+        }
+
+        let timeouts = Timeouts {
+            retries: 1,
+            retry_backoff: Duration::from_millis(10),
+            ..timeouts(1000, 200)
+        };
+        let arbiter_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let client = connect_to_arbiter(client_config(), arbiter_addr, &runtime, timeouts).unwrap();
+        let (devices, _) = discover_devices(&client, &runtime, timeouts, false, None).unwrap();
+        assert!(devices.is_empty());
+        assert_eq!(*requests.lock().unwrap(), 2);
+
+        // Without retries the lost answer is a failure
+        *requests.lock().unwrap() = 0;
+        let timeouts = Timeouts {
+            retries: 0,
+            ..timeouts
+        };
+        assert!(discover_devices(&client, &runtime, timeouts, false, None).is_err());
+    }
+
+    #[cfg(not(feature = "attack-demos"))]
+    #[test]
+    fn default_build_refuses_forgery_even_when_enabled_at_runtime() {
+        let config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "enableAttackDemos": true,
+        }))
+        .unwrap();
+        assert_eq!(
+            disabled_command_reason("f", &config),
+            Some("attack demos disabled, this build doesn't include the attack-demos feature")
+        );
+    }
+
+    #[cfg(feature = "attack-demos")]
+    #[test]
+    fn tampered_token_keeps_everything_but_the_audience() {
+        let claims = json!({
+            "iss": "arbiter",
+            "sub": "controller",
+            "aud": "device-1",
+            "exp": 1,
+            "params_read": ["temp"],
+            "params_write": [],
+            "jti": Uuid::nil(),
+        });
+        let token = format!(
+            "header.{}.signature",
+            URL_SAFE.encode(serde_json::to_vec(&claims).unwrap())
+        );
+
+        let tampered = tamper_with_token(&token, "device-2".to_string());
+        let [header, payload, signature] = tampered.split('.').collect::<Vec<_>>()[..] else {
+            panic!("Not a JWT: {tampered}");
+        };
+        assert_eq!((header, signature), ("header", "signature"));
+        let mut expected = claims;
+        expected["aud"] = json!("device-2");
+        let tampered_claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE.decode(payload).unwrap()).unwrap();
+        assert_eq!(tampered_claims, expected);
+    }
+
+    #[test]
+    fn probe_summary_counts_each_outcome() {
+        let results = vec![
+            ("temp".to_string(), ProbeOutcome::Readable("20".to_string())),
+            (
+                "mode".to_string(),
+                ProbeOutcome::Readable("auto".to_string()),
+            ),
+            (
+                "secret".to_string(),
+                ProbeOutcome::Denied("forbidden".to_string()),
+            ),
+            (
+                "speed".to_string(),
+                ProbeOutcome::ReadFailed("timed out".to_string()),
+            ),
+        ];
+        let summary = ProbeSummary::new(&results);
+        assert_eq!(
+            (summary.readable, summary.denied, summary.read_failed),
+            (2, 1, 1)
+        );
+
+        let summary = ProbeSummary::new(&[]);
+        assert_eq!(
+            (summary.readable, summary.denied, summary.read_failed),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn direct_get_uses_the_token_file_without_an_arbiter() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (port, received) = fake_server(&runtime, json!(21));
+        let token_file = std::env::temp_dir().join(format!("controller-token-{}", Uuid::new_v4()));
+        std::fs::write(&token_file, "header.claims.signature\n").unwrap();
+
+        let controller_config: Config =
+            serde_json::from_value(json!({ "cid": Uuid::nil() })).unwrap();
+        let mut session = Session::new(client_config(), &controller_config, &runtime);
+        assert!(session.run_command(&format!("dg {port} {} temp", token_file.display())));
+        assert!(session.client.is_none());
+        assert_eq!(*received.lock().unwrap(), ["Get temp".to_string()]);
+        assert_eq!(
+            session.last_token.borrow().as_deref(),
+            Some("header.claims.signature")
+        );
+        std::fs::remove_file(token_file).unwrap();
+    }
+
+    #[test]
+    fn session_state_survives_a_restart() {
+        let file = std::env::temp_dir().join(format!("controller-state-{}.json", Uuid::new_v4()));
+        let file = file.to_str().unwrap().to_string();
+        let state = SessionState {
+            arbiter: Some("127.0.0.1:5683".to_string()),
+            last_device: Some(Uuid::from_u128(1)),
+        };
+        save_session_state(&file, &state).unwrap();
+        assert!(load_session_state(&file).unwrap() == state);
+
+        // A session that was never connected only remembers the device
+        let controller_config: Config =
+            serde_json::from_value(json!({ "cid": Uuid::nil() })).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut session = Session::new(client_config(), &controller_config, &runtime);
+        session.last_device = Some(Uuid::from_u128(2));
+        session.save_state(&file);
+
+        let mut restarted = Session::new(client_config(), &controller_config, &runtime);
+        restarted.restore_state(&file);
+        assert_eq!(restarted.last_device, Some(Uuid::from_u128(2)));
+        assert!(restarted.client.is_none());
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn expired_token_is_refreshed_and_the_put_retried_once() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (arbiter_port, arbiter_received) = fake_server(
+            &runtime,
+            json!({ "tokens": { Uuid::nil().to_string(): "token" } }),
+        );
+
+        // Stands in for a device that says the token expired the first `expiries` times
+        let device_port = |expiries: usize| {
+            let (listener, port) = dtls_listener(&runtime);
+            let puts = Arc::new(Mutex::new(0));
+            let _runtime_context = runtime.enter();
+            let server = coap::Server::from_listeners(vec![Box::new(listener)]);
+            runtime.spawn(server.run({
+                let puts = puts.clone();
+                move |mut request: Box<CoapRequest<SocketAddr>>| {
+                    let puts = puts.clone();
+                    async move {
+                        let mut count = puts.lock().unwrap();
+                        *count += 1;
+                        let response = request.response.as_mut().unwrap();
+                        if *count <= expiries {
+                            response.set_status(ResponseType::Unauthorized);
+                            response.message.payload =
+                                br#"{"error":"token_expired","message":"Token expired"}"#.to_vec();
+                        }
+                        request
+                    }
+                }
+            }));
+            (port, puts)
+        };
+
+        let controller_config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "arbiterAddr": format!("127.0.0.1:{arbiter_port}"),
+        }))
+        .unwrap();
+        let mut session = Session::new(client_config(), &controller_config, &runtime);
+        assert!(session.run_command("c"));
+
+        // A second expiry isn't retried again
+        for expiries in [1, 2] {
+            arbiter_received.lock().unwrap().clear();
+            let (port, puts) = device_port(expiries);
+            let mut target = device(None);
+            target.port = port;
+            session.current_devices = vec![target];
+            assert!(session.run_command("s 0 temp 21"));
+            assert_eq!(*puts.lock().unwrap(), 2);
+            assert_eq!(arbiter_received.lock().unwrap().len(), 2);
+        }
+    }
+}
@@ -1,7 +1,16 @@
 use rcgen::{
     BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    PKCS_ECDSA_P256_SHA256,
 };
+use serde_json::{Map, Value};
 use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+// The device's config, to test that provisioned configs parse. Only Config itself is used.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../device/src/config.rs"]
+mod device;
 
 const ROOT_HOSTNAME: &str = "trustedroot.esta.org";
 const COUNTRY: &str = "US";
@@ -13,40 +22,129 @@ fn main() {
     let now = OffsetDateTime::now_utc();
     let expiry = now + Duration::days(365);
 
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, template, rest @ ..] = args.as_slice() {
+        if command == "provision" {
+            let count = rest
+                .first()
+                .map_or(1, |count| count.parse().expect("Invalid count"));
+            provision_devices(template, count, &now, &expiry);
+            return;
+        }
+    }
+
     std::fs::create_dir_all("out").unwrap();
 
     let (root_cert, root_key) = create_root_cert(&now, &expiry);
-    create_signed_cert(&root_cert, &root_key, "arbiter", &now, &expiry);
-    create_signed_cert(&root_cert, &root_key, "controller", &now, &expiry);
-    create_signed_cert(&root_cert, &root_key, "device", &now, &expiry);
+    create_signed_cert(&root_cert, &root_key, "arbiter", "arbiter", &now, &expiry);
+    create_signed_cert(
+        &root_cert,
+        &root_key,
+        "controller",
+        "controller",
+        &now,
+        &expiry,
+    );
+    create_signed_cert(&root_cert, &root_key, "device", "device", &now, &expiry);
     create_self_signed_cert("client", &now, &expiry);
 }
 
+// Generates a certificate and a ready-to-run config.json for each of `count` new devices, from a
+// template holding the device config fields shared by the model (manufacturer, model,
+// parameters...). Certificates are signed by the root created by a previous plain run.
+fn provision_devices(template: &str, count: usize, now: &OffsetDateTime, expiry: &OffsetDateTime) {
+    let template = std::fs::read_to_string(template).expect("Couldn't read template");
+    let template: Map<String, Value> =
+        serde_json::from_str(&template).expect("Template must be a JSON object");
+
+    let (root_cert, root_key) = load_root_cert(now, expiry);
+    std::fs::create_dir_all("out/provisioned").unwrap();
+
+    for index in 1..=count {
+        let cid = Uuid::new_v4();
+        let name = device_file_prefix(&cid);
+        // Controllers and the arbiter expect every device to present device.local
+        create_signed_cert(&root_cert, &root_key, "device", &name, now, expiry);
+
+        let config = device_config(&template, &cid, index);
+        std::fs::write(
+            format!("out/provisioned/{name}.json"),
+            serde_json::to_string_pretty(&config).unwrap(),
+        )
+        .unwrap();
+        println!("Provisioned device {cid}");
+    }
+}
+
+fn device_file_prefix(cid: &Uuid) -> String {
+    format!("device-{cid}")
+}
+
+// The template with the fields that differ between devices filled in. Labels are numbered after
+// the template's label, or its model if it has none.
+fn device_config(template: &Map<String, Value>, cid: &Uuid, index: usize) -> Map<String, Value> {
+    let label_prefix = template
+        .get("label")
+        .or(template.get("model"))
+        .and_then(Value::as_str)
+        .unwrap_or("device");
+    let name = device_file_prefix(cid);
+
+    let mut config = template.clone();
+    config.insert("cid".into(), cid.to_string().into());
+    config.insert("label".into(), format!("{label_prefix}-{index}").into());
+    config.insert(
+        "certFile".into(),
+        format!("../certs/{name}-cert.pem").into(),
+    );
+    config.insert("keyFile".into(), format!("../certs/{name}-key.pem").into());
+    config
+}
+
+// Recreates the root certificate from its saved key. The subject and key are the same as the
+// one on disk, so certificates it signs chain to that one.
+fn load_root_cert(now: &OffsetDateTime, expiry: &OffsetDateTime) -> (Certificate, KeyPair) {
+    let key_pem = std::fs::read_to_string("out/root-key.pem")
+        .expect("No root key in out/, run without arguments first");
+    let key_pair = KeyPair::from_pem(&key_pem).unwrap();
+    let cert = root_cert_params(now, expiry)
+        .self_signed(&key_pair)
+        .unwrap();
+    (cert, key_pair)
+}
+
 // Equivalent OpenSSL command:
-// openssl req -x509 -nodes -days 365 -newkey ed25519 -keyout root-key.pem -out root-cert.pem
+// openssl req -x509 -nodes -days 365 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -keyout root-key.pem -out root-cert.pem
 fn create_root_cert(now: &OffsetDateTime, expiry: &OffsetDateTime) -> (Certificate, KeyPair) {
-    let mut cert_params = CertificateParams::new(vec![ROOT_HOSTNAME.to_string()]).unwrap();
-    update_dn(&mut cert_params.distinguished_name, ROOT_HOSTNAME);
-    cert_params.not_before = now.clone();
-    cert_params.not_after = expiry.clone();
-    cert_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-
-    let key_pair = KeyPair::generate().unwrap();
+    // P-256 like the other keys, rather than whatever rcgen defaults to
+    let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
     std::fs::write("out/root-key.pem", key_pair.serialize_pem()).unwrap();
-    let cert = cert_params.self_signed(&key_pair).unwrap();
-    std::fs::write("out/root-cert.pem", &cert.pem()).unwrap();
+    let cert = root_cert_params(now, expiry)
+        .self_signed(&key_pair)
+        .unwrap();
+    std::fs::write("out/root-cert.pem", cert.pem()).unwrap();
 
     (cert, key_pair)
 }
 
+fn root_cert_params(now: &OffsetDateTime, expiry: &OffsetDateTime) -> CertificateParams {
+    let mut cert_params = CertificateParams::new(vec![ROOT_HOSTNAME.to_string()]).unwrap();
+    update_dn(&mut cert_params.distinguished_name, ROOT_HOSTNAME);
+    cert_params.not_before = *now;
+    cert_params.not_after = *expiry;
+    cert_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    cert_params
+}
+
 fn create_self_signed_cert(component_name: &str, now: &OffsetDateTime, expiry: &OffsetDateTime) {
     let hostname = format!("{component_name}.local");
     let mut cert_params = CertificateParams::new(vec![hostname.clone()]).unwrap();
     update_dn(&mut cert_params.distinguished_name, &hostname);
-    cert_params.not_before = now.clone();
-    cert_params.not_after = expiry.clone();
+    cert_params.not_before = *now;
+    cert_params.not_after = *expiry;
 
-    let key_pair = KeyPair::generate().unwrap();
+    // P-256 like the other keys, rather than whatever rcgen defaults to
+    let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
     std::fs::write(
         format!("out/{component_name}-selfsigned-key.pem"),
         key_pair.serialize_pem(),
@@ -55,42 +153,44 @@ fn create_self_signed_cert(component_name: &str, now: &OffsetDateTime, expiry: &
     let cert = cert_params.self_signed(&key_pair).unwrap();
     std::fs::write(
         format!("out/{component_name}-selfsigned-cert.pem"),
-        &cert.pem(),
+        cert.pem(),
     )
     .unwrap();
 }
 
 // Equivalent OpenSSL commands:
-// openssl req -new -nodes -newkey ed25519 -keyout [component-name]-key.pem -out [component-name]-req.csr
+// openssl req -new -nodes -newkey ec -pkeyopt ec_paramgen_curve:P-256 -keyout [component-name]-key.pem -out [component-name]-req.csr
 // openssl x509 -req -in [component-name]-req.csr -days 365 -CA root-cert.pem -CAkey root-key.pem -CAcreateserial -out [component-name]-cert.pem
 fn create_signed_cert(
     root_cert: &Certificate,
     root_key: &KeyPair,
     component_name: &str,
+    file_prefix: &str,
     now: &OffsetDateTime,
     expiry: &OffsetDateTime,
 ) {
     let hostname = format!("{component_name}.local");
     let mut cert_params = CertificateParams::new(vec![hostname.clone()]).unwrap();
     update_dn(&mut cert_params.distinguished_name, &hostname);
-    cert_params.not_before = now.clone();
-    cert_params.not_after = expiry.clone();
+    cert_params.not_before = *now;
+    cert_params.not_after = *expiry;
 
-    let key_pair = KeyPair::generate().unwrap();
+    // P-256, since the arbiter signs control tokens with ES256 using its key
+    let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
     std::fs::write(
-        format!("out/{component_name}-key.pem"),
+        format!("out/{file_prefix}-key.pem"),
         key_pair.serialize_pem(),
     )
     .unwrap();
     std::fs::write(
-        format!("out/{component_name}-key.pub.pem"),
+        format!("out/{file_prefix}-key.pub.pem"),
         key_pair.public_key_pem(),
     )
     .unwrap();
     let cert = cert_params
         .signed_by(&key_pair, root_cert, root_key)
         .unwrap();
-    std::fs::write(format!("out/{component_name}-cert.pem"), &cert.pem()).unwrap();
+    std::fs::write(format!("out/{file_prefix}-cert.pem"), cert.pem()).unwrap();
 }
 
 fn update_dn(dn: &mut DistinguishedName, cn: &str) {
@@ -100,3 +200,28 @@ fn update_dn(dn: &mut DistinguishedName, cn: &str) {
     dn.push(DnType::LocalityName, LOCALITY);
     dn.push(DnType::OrganizationName, ORGANIZATION);
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn provisioned_config_is_a_valid_device_config() {
+        let template = json!({
+            "manufacturer": "ACME",
+            "model": "Dimmer",
+            "parameters": { "level": { "pattern": "[0-9]+" } },
+        });
+        let cid = Uuid::new_v4();
+        let config = device_config(template.as_object().unwrap(), &cid, 2);
+
+        let config: device::Config = serde_json::from_value(config.into()).unwrap();
+        assert_eq!(config.cid, cid);
+        assert_eq!(config.label, "Dimmer-2");
+        assert_eq!(config.cert_file, format!("../certs/device-{cid}-cert.pem"));
+        assert_eq!(config.key_file, format!("../certs/device-{cid}-key.pem"));
+        assert!(config.parameters.contains_key("level"));
+    }
+}
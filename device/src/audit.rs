@@ -0,0 +1,60 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time,
+};
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+#[serde(tag = "operation", rename_all = "camelCase")]
+pub enum AuditEvent<'a> {
+    #[serde(rename_all = "camelCase")]
+    Get {
+        parameter: &'a str,
+        controller: &'a str,
+        value: &'a str,
+    },
+    #[serde(rename_all = "camelCase")]
+    Put {
+        parameter: &'a str,
+        controller: &'a str,
+        old_value: Option<&'a str>,
+        new_value: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+// Append-only JSON lines log of authorized parameter accesses
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut line = serde_json::to_vec(&AuditRecord { timestamp, event }).unwrap();
+        line.push(b'\n');
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            println!("Error writing audit log: {e}");
+        }
+    }
+}
@@ -1,5 +1,8 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use anyhow::Context;
 use log::LevelFilter;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Deserialize)]
@@ -9,6 +12,22 @@ pub struct Config {
     pub label: String,
     pub manufacturer: String,
     pub model: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Advertised via GET /params, keyed by parameter name
+    #[serde(default)]
+    pub parameters: BTreeMap<String, ParameterConfig>,
+    // Tell the arbiter the names of the parameters above when registering, so that it refuses
+    // tokens for parameters this device doesn't have. Parameters added later with POST /params
+    // aren't included.
+    #[serde(default)]
+    pub advertise_parameters: bool,
+    // Named sets of parameter values, switched between with PUT /_profile
+    #[serde(default)]
+    pub profiles: BTreeMap<String, BTreeMap<String, String>>,
+    // Profile applied at startup
+    #[serde(default)]
+    pub default_profile: Option<String>,
     #[serde(default = "default_root_ca")]
     pub root_ca_file: String,
     #[serde(default = "default_cert_file")]
@@ -17,8 +36,130 @@ pub struct Config {
     pub key_file: String,
     #[serde(default = "default_arbiter_public_key_file")]
     pub arbiter_public_key_file: String,
+    // Port to serve on; 0 picks an ephemeral port
+    #[serde(default)]
+    pub listen_port: u16,
+    #[serde(default = "default_arbiter_addr")]
+    pub arbiter_addr: SocketAddr,
+    // If set, tokens must name this as their issuer. Left unset, tokens signed with the arbiter's
+    // key are accepted whatever issuer they name.
+    #[serde(default)]
+    pub arbiter_cid: Option<Uuid>,
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    // Lifetime the device registers with. It registers again every half this, so that the
+    // registration doesn't lapse while the device is up.
+    #[serde(default = "default_registration_ttl_secs")]
+    pub registration_ttl_secs: u64,
+    // Wait this long after starting before registering
+    #[serde(default)]
+    pub startup_delay_secs: u64,
+    // If set, poll the arbiter's /health before registering, giving up after this long
+    #[serde(default)]
+    pub arbiter_ready_timeout_secs: Option<u64>,
+    // If set, the device's clock is compared against the arbiter's before registering, and a
+    // difference of more than this is warned about, or fatal with clockSkewFatal
+    #[serde(default)]
+    pub max_clock_skew_secs: Option<u64>,
+    #[serde(default)]
+    pub clock_skew_fatal: bool,
     #[serde(default = "default_log_filter")]
     pub log_level: LevelFilter,
+    // Simulated processing time added before every response, for testing controllers: a fixed
+    // delay plus a uniformly random jitter of up to responseJitterMs. Set jitterSeed to get the
+    // same sequence of delays on every run.
+    #[serde(default)]
+    pub response_delay_ms: u64,
+    #[serde(default)]
+    pub response_jitter_ms: u64,
+    #[serde(default)]
+    pub jitter_seed: Option<u64>,
+    // If set, a message whose ID was already seen from the same peer within this long is treated
+    // as a retransmission: it gets the first copy's response again instead of being handled twice
+    #[serde(default)]
+    pub message_dedup_window_ms: Option<u64>,
+    // If set, every authorized GET and PUT is appended to this file as a JSON line
+    #[serde(default)]
+    pub audit_file: Option<String>,
+    // Longer tokens are rejected without being decoded
+    #[serde(default = "default_max_token_length")]
+    pub max_token_length: usize,
+    #[serde(default = "default_require_token_exp")]
+    pub require_token_exp: bool,
+    // If set, tokens issued (per their iat claim) longer ago than this are rejected even if they
+    // haven't expired yet
+    #[serde(default)]
+    pub max_token_age_secs: Option<u64>,
+    // Only accept tokens whose audience is this device alone. Turn off to accept tokens that
+    // list several devices, one of them this one.
+    #[serde(default = "default_strict_audience")]
+    pub strict_audience: bool,
+    // Expect tokens addressed to this device's label instead of its CID. Must match the
+    // arbiter's labelAudience setting.
+    #[serde(default)]
+    pub label_audience: bool,
+    // If set, the arbiter's list of revoked tokens is fetched this often and tokens on it are
+    // rejected. A token keeps working here until the first fetch after it was revoked.
+    #[serde(default)]
+    pub revocation_poll_secs: Option<u64>,
+    // If set, a token is only accepted for one write: the jtis of this many of the most recently
+    // used tokens are remembered, and writes with any of them are refused. Refused requests don't
+    // use a token up. With replayProtectReads, reads use tokens up too, which breaks controllers
+    // that read several parameters with one token.
+    #[serde(default)]
+    pub replay_cache_size: Option<usize>,
+    #[serde(default)]
+    pub replay_protect_reads: bool,
+    // If set, only tokens issued to these controllers are accepted. Matched against the token
+    // subject, which is the controller CID unless the arbiter maps it to another identity.
+    #[serde(default)]
+    pub allowed_controllers: Option<Vec<String>>,
+    // Generate a key pair at startup and register its public key, so that controllers can
+    // encrypt PUT values end to end. With requireEncryptedValues, values sent in the clear are
+    // refused.
+    #[serde(default)]
+    pub value_encryption: bool,
+    #[serde(default)]
+    pub require_encrypted_values: bool,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParameterConfig {
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default, rename = "type")]
+    pub ty: Option<ParameterType>,
+    // Value held from startup until the parameter is first written, unless the default profile
+    // sets it
+    #[serde(default)]
+    pub initial_value: Option<String>,
+    // PUT values must match this regex in full
+    #[serde(default)]
+    pub pattern: Option<String>,
+    // Other parameters that must currently hold the given values before this one can be written,
+    // e.g. "mode": "manual" before a setpoint
+    #[serde(default)]
+    pub requires: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ParameterType {
+    Int,
+    Float,
+    Bool,
+    String,
+}
+
+// Reads config.json, applying any environment overrides
+pub fn load() -> anyhow::Result<Config> {
+    let config = std::fs::read_to_string("config.json").context("No config file provided")?;
+    let mut config: serde_json::Value = serde_json::from_str(&config).context("Invalid config")?;
+    env_overrides::apply_env_overrides(&mut config, "DEVICE_");
+    serde_json::from_value(config).context("Invalid config")
 }
 
 fn default_root_ca() -> String {
@@ -37,6 +178,34 @@ fn default_arbiter_public_key_file() -> String {
     "../certs/arbiter-key.pub.pem".to_string()
 }
 
+fn default_arbiter_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 5683))
+}
+
+fn default_registration_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_request_timeout_ms() -> u64 {
+    5000
+}
+
 fn default_log_filter() -> LevelFilter {
     LevelFilter::Off
 }
+
+fn default_strict_audience() -> bool {
+    true
+}
+
+fn default_max_token_length() -> usize {
+    8192
+}
+
+fn default_require_token_exp() -> bool {
+    true
+}
@@ -0,0 +1,115 @@
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use p256::{elliptic_curve::sec1::ToEncodedPoint, PublicKey, SecretKey};
+use serde::Deserialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+// Must match the controller's
+const KDF_INFO: &[u8] = b"NextGenTransport parameter value v1";
+
+// A PUT value encrypted for this device alone: AES-256-GCM under a key derived by ECDH between
+// the controller's ephemeral key and ours, bound to this device and parameter
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedValue {
+    ephemeral_key: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Generated fresh at every start, and registered with the arbiter for controllers to encrypt to
+pub struct ValueKey {
+    secret: SecretKey,
+}
+
+impl ValueKey {
+    pub fn generate() -> Self {
+        Self {
+            secret: SecretKey::random(&mut OsRng),
+        }
+    }
+
+    // SEC1 uncompressed point, base64
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.secret.public_key().to_encoded_point(false).as_bytes())
+    }
+
+    pub fn decrypt(
+        &self,
+        value: &EncryptedValue,
+        device: &Uuid,
+        parameter: &str,
+    ) -> Result<String, String> {
+        let decode = |field: &str| {
+            STANDARD
+                .decode(field)
+                .map_err(|e| format!("Invalid base64: {e}"))
+        };
+        let ephemeral_key = PublicKey::from_sec1_bytes(&decode(&value.ephemeral_key)?)
+            .map_err(|_| "Invalid ephemeral key".to_string())?;
+        let nonce = decode(&value.nonce)?;
+        if nonce.len() != 12 {
+            return Err("Invalid nonce".to_string());
+        }
+
+        let shared =
+            p256::ecdh::diffie_hellman(self.secret.to_nonzero_scalar(), ephemeral_key.as_affine());
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(ephemeral_key.to_encoded_point(false).as_bytes());
+        info.extend_from_slice(self.secret.public_key().to_encoded_point(false).as_bytes());
+        let mut key = [0; 32];
+        shared
+            .extract::<Sha256>(None)
+            .expand(&info, &mut key)
+            .unwrap();
+
+        let plaintext = Aes256Gcm::new_from_slice(&key)
+            .unwrap()
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &decode(&value.ciphertext)?,
+                    aad: format!("{device}/{parameter}").as_bytes(),
+                },
+            )
+            .map_err(|_| "Decryption failed".to_string())?;
+        String::from_utf8(plaintext).map_err(|_| "Decrypted value isn't UTF-8".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller_encryption::encrypt_value;
+
+    const DEVICE: Uuid = Uuid::from_u128(0xd1);
+
+    // What the controller sends, as the device receives it
+    fn encrypted(key: &ValueKey, device: &Uuid, parameter: &str, value: &str) -> EncryptedValue {
+        let sent = encrypt_value(&key.public_key(), device, parameter, value).unwrap();
+        serde_json::from_value(serde_json::to_value(sent).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn value_encrypted_by_the_controller_is_decrypted_by_the_device() {
+        let key = ValueKey::generate();
+        let value = encrypted(&key, &DEVICE, "setpoint", "21.5");
+        assert_eq!(key.decrypt(&value, &DEVICE, "setpoint").unwrap(), "21.5");
+
+        // Bound to the device, the parameter and the key it was encrypted to
+        let failed = Err("Decryption failed".to_string());
+        assert_eq!(key.decrypt(&value, &DEVICE, "mode"), failed);
+        assert_eq!(
+            key.decrypt(&value, &Uuid::from_u128(0xd2), "setpoint"),
+            failed
+        );
+        assert_eq!(
+            ValueKey::generate().decrypt(&value, &DEVICE, "setpoint"),
+            failed
+        );
+    }
+}
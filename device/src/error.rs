@@ -0,0 +1,106 @@
+use std::fmt::Display;
+
+use coap_lite::{error::HandlingError, ResponseType};
+use serde::Serialize;
+
+// What kind of failure an error response is, for controllers to act on without parsing the
+// message. Sent alongside the CoAP code, which doesn't always tell these apart.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorReason {
+    // The token is valid but doesn't allow this
+    Forbidden,
+    // The value breaks one of the parameter's constraints
+    ConstraintViolation,
+    UnknownParameter,
+    // The token couldn't be decoded or isn't meant for this device, or its controller isn't
+    // allowed here
+    InvalidToken,
+    // The token was valid once; a fresh one will do
+    TokenExpired,
+    TokenRevoked,
+    // The token was already used here, and this device only accepts each token once
+    TokenReplayed,
+    // The request itself is malformed
+    InvalidRequest,
+    NotFound,
+    Conflict,
+    Unavailable,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    reason: ErrorReason,
+    message: &'a str,
+}
+
+// An error whose message is the JSON body {"reason": ..., "message": ...}
+pub fn rejection(code: ResponseType, reason: ErrorReason, message: impl Display) -> HandlingError {
+    let message = message.to_string();
+    HandlingError::with_code(
+        code,
+        serde_json::to_string(&ErrorBody {
+            reason,
+            message: &message,
+        })
+        .unwrap(),
+    )
+}
+
+// Failures that stop the device from starting, with the same exit codes as the arbiter's where
+// they mean the same thing
+#[derive(Debug)]
+pub enum StartupError {
+    Config(anyhow::Error),
+    Certificate(anyhow::Error),
+    Bind(anyhow::Error),
+    Server(anyhow::Error),
+    AuditLog(anyhow::Error),
+    // The arbiter wasn't ready in time, or its clock is too far off ours
+    Arbiter(anyhow::Error),
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Certificate(_) => 3,
+            Self::Bind(_) => 4,
+            Self::Server(_) => 5,
+            Self::AuditLog(_) => 6,
+            Self::Arbiter(_) => 7,
+        }
+    }
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "Configuration error: {e:#}"),
+            Self::Certificate(e) => write!(f, "Certificate error: {e:#}"),
+            Self::Bind(e) => write!(f, "Couldn't bind listener: {e:#}"),
+            Self::Server(e) => write!(f, "Server error: {e:#}"),
+            Self::AuditLog(e) => write!(f, "Couldn't open audit log: {e:#}"),
+            Self::Arbiter(e) => write!(f, "Arbiter error: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_startup_error_has_its_own_exit_code() {
+        let errors = [
+            StartupError::Config(anyhow::anyhow!("")),
+            StartupError::Certificate(anyhow::anyhow!("")),
+            StartupError::Bind(anyhow::anyhow!("")),
+            StartupError::Server(anyhow::anyhow!("")),
+            StartupError::AuditLog(anyhow::anyhow!("")),
+            StartupError::Arbiter(anyhow::anyhow!("")),
+        ];
+        let codes: Vec<_> = errors.iter().map(StartupError::exit_code).collect();
+        assert_eq!(codes, vec![2, 3, 4, 5, 6, 7]);
+    }
+}
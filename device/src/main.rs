@@ -1,204 +1,122 @@
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::{fs::File, io::BufReader};
-
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufReader,
+    process::ExitCode,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
 use coap::client::CoAPClient;
-use coap::dtls::UdpDtlsConfig;
-use coap::request::{CoapRequest, Method, RequestBuilder};
+use coap::dtls::{DtlsConnection, UdpDtlsConfig};
+use coap::request::{Method, RequestBuilder};
 use coap::Server;
-use coap_lite::error::HandlingError;
 use coap_lite::ResponseType;
-use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation};
+use jsonwebtoken::DecodingKey;
 use rcgen::KeyPair;
 use rustls::{Certificate as RustlsCertificate, RootCertStore};
 use serde::{Deserialize, Serialize};
+use tokio::time::{timeout, Instant};
 use uuid::Uuid;
 use webrtc_dtls::config::{ClientAuthType, Config as DtlsConfig};
 use webrtc_dtls::crypto::{Certificate, CryptoPrivateKey};
 use webrtc_dtls::listener::listen;
 use webrtc_util::conn::Listener;
 
-use self::config::Config;
+use self::{
+    audit::AuditLog, config::Config, encryption::ValueKey, error::StartupError,
+    request_handler::RequestHandler,
+};
 
+mod audit;
 mod config;
+// The controller's side of value encryption, to test that the two agree
+#[cfg(test)]
+#[path = "../../controller/src/encryption.rs"]
+mod controller_encryption;
+mod encryption;
+mod error;
+mod request_handler;
+
+const ARBITER_READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct ArbiterInfo {
+    time: u64,
+}
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct PutDevicePayload {
     label: String,
     manufacturer: String,
     model: String,
     port: u16,
     ttl: u64,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption_key: Option<String>,
+    // From the last registration, when renewing it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registration_key: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct GetParamPayload {
-    token: String,
-}
-
-#[derive(Deserialize)]
-struct SetParamPayload {
-    token: String,
-    value: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct JwtClaims {
-    iss: String,
-    sub: String,
-    aud: String,
-    exp: u64,
-    params_read: Vec<String>,
-    params_write: Vec<String>,
+#[serde(rename_all = "camelCase")]
+struct RegistrationResponse {
+    registration_key: String,
 }
 
-struct RequestHandler {
-    jwt_decoder: DecodingKey,
-    my_cid: Uuid,
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteDevicePayload<'a> {
+    registration_key: &'a str,
 }
 
-impl RequestHandler {
-    pub fn new(jwt_decoder: DecodingKey, my_cid: Uuid) -> Self {
-        Self {
-            jwt_decoder,
-            my_cid,
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(e.exit_code())
         }
     }
 }
 
-impl coap::server::RequestHandler for RequestHandler {
-    fn handle_request<'life0, 'async_trait>(
-        &'life0 self,
-        mut request: Box<CoapRequest<SocketAddr>>,
-    ) -> core::pin::Pin<
-        Box<
-            dyn core::future::Future<Output = Box<CoapRequest<SocketAddr>>>
-                + core::marker::Send
-                + 'async_trait,
-        >,
-    >
-    where
-        'life0: 'async_trait,
-        Self: 'async_trait,
-    {
-        Box::pin(async {
-            let method = request.get_method();
-            match method {
-                Method::Get => {
-                    let parameter = request.get_path();
-                    println!("Handling GET /{}", parameter);
-
-                    let payload =
-                        match serde_json::from_slice::<GetParamPayload>(&request.message.payload) {
-                            Ok(payload) => payload,
-                            Err(e) => {
-                                request.apply_from_error(HandlingError::bad_request(format!(
-                                    "Couldn't parse payload of GET /: {e}"
-                                )));
-                                return request;
-                            }
-                        };
-
-                    let jwt_data = match decode_jwt(
-                        &payload.token,
-                        &self.jwt_decoder,
-                        &self.my_cid.to_string(),
-                    ) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            println!("Error decoding control token: {e}");
-                            request.apply_from_error(HandlingError::bad_request(format!(
-                                "Couldn't decode JWT: {e}"
-                            )));
-                            return request;
-                        }
-                    };
-
-                    println!(
-                        "Received token: {}",
-                        serde_json::to_string_pretty(&jwt_data.claims).unwrap()
-                    );
-
-                    if !jwt_data.claims.params_read.contains(&parameter) {
-                        println!("Validation error: Token does not have permission to access parameter {parameter}");
-                        request.apply_from_error(HandlingError::with_code(
-                            ResponseType::Forbidden,
-                            "No permission for parameter",
-                        ));
-                    } else {
-                        println!("Get request validated successfully.");
-                        if let Some(ref mut message) = request.response {
-                            message.message.payload = b"42".to_vec();
-                        }
-                    }
-                }
-                Method::Put => {
-                    let parameter = request.get_path();
-                    println!("Handling PUT /{}", parameter);
-
-                    let payload =
-                        match serde_json::from_slice::<SetParamPayload>(&request.message.payload) {
-                            Ok(payload) => payload,
-                            Err(e) => {
-                                request.apply_from_error(HandlingError::bad_request(format!(
-                                    "Couldn't parse payload of SET /: {e}"
-                                )));
-                                return request;
-                            }
-                        };
-
-                    let jwt_data = match decode_jwt(
-                        &payload.token,
-                        &self.jwt_decoder,
-                        &self.my_cid.to_string(),
-                    ) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            request.apply_from_error(HandlingError::bad_request(format!(
-                                "Couldn't decode JWT: {e}"
-                            )));
-                            return request;
-                        }
-                    };
-
-                    println!(
-                        "Received token: {}",
-                        serde_json::to_string_pretty(&jwt_data.claims).unwrap()
-                    );
-
-                    if !jwt_data.claims.params_write.contains(&parameter) {
-                        println!("Validation error: Token does not have permission to write parameter {parameter}");
-                        request.apply_from_error(HandlingError::with_code(
-                            ResponseType::Forbidden,
-                            "No permission for parameter",
-                        ));
-                    } else {
-                        println!("Put request validated successfully.");
-                        println!("Setting {parameter} to {}", payload.value);
-                        if let Some(ref mut message) = request.response {
-                            message.message.payload.clear();
-                        }
-                    }
-                }
-                _ => println!("Received unhandled method {:?}", method),
-            }
-
-            return request;
-        })
-    }
-}
-
-#[tokio::main]
-async fn main() {
-    let config = std::fs::read_to_string("config.json").expect("No config file provided");
-    let config: Config = serde_json::from_str(&config).expect("Invalid config");
+async fn run() -> Result<(), StartupError> {
+    let config = config::load().map_err(StartupError::Config)?;
 
     env_logger::Builder::new()
         .filter_level(config.log_level)
         .init();
 
-    let roots_cas = get_root_cert_store(&config.root_ca_file);
-    let certificates = get_my_certs(&config.cert_file, &config.key_file);
-    let jwt_decoder = get_jwt_decoder(&config.arbiter_public_key_file);
+    let roots_cas = get_root_cert_store(&config.root_ca_file).map_err(StartupError::Certificate)?;
+    let certificates =
+        get_my_certs(&config.cert_file, &config.key_file).map_err(StartupError::Certificate)?;
+    let jwt_decoder =
+        get_jwt_decoder(&config.arbiter_public_key_file).map_err(StartupError::Certificate)?;
+    let audit_log = config
+        .audit_file
+        .as_ref()
+        .map(|file| AuditLog::open(file).with_context(|| format!("Couldn't open {file}")))
+        .transpose()
+        .map_err(StartupError::AuditLog)?;
+    let value_key = config.value_encryption.then(ValueKey::generate);
+    let encryption_key = value_key.as_ref().map(ValueKey::public_key);
+    let revoked_tokens = Arc::new(RwLock::new(HashSet::new()));
+    // Before registering, so that a bad parameter definition stops the device straight away
+    let handler = RequestHandler::new(
+        jwt_decoder,
+        audit_log,
+        value_key,
+        revoked_tokens.clone(),
+        &config,
+    )
+    .map_err(StartupError::Config)?;
 
     let server_config = DtlsConfig {
         certificates: certificates.clone(),
@@ -207,106 +125,644 @@ async fn main() {
         ..Default::default()
     };
 
-    let listener = listen("127.0.0.1:0", server_config).await.unwrap();
-    let port = listener.addr().await.unwrap().port();
+    let (listener, port) = bind(config.listen_port, server_config)
+        .await
+        .map_err(StartupError::Bind)?;
     let listener = Box::new(listener);
     let server = Server::from_listeners(vec![listener]);
     println!("Server up on port {port}");
 
-    register_with_arbiter(&config, port, certificates, roots_cas).await;
+    if config.startup_delay_secs > 0 {
+        println!("Waiting {}s before registering", config.startup_delay_secs);
+        tokio::time::sleep(Duration::from_secs(config.startup_delay_secs)).await;
+    }
+    if let Some(ready_timeout) = config.arbiter_ready_timeout_secs {
+        wait_for_arbiter(
+            &config,
+            Duration::from_secs(ready_timeout),
+            &certificates,
+            &roots_cas,
+        )
+        .await
+        .map_err(StartupError::Arbiter)?;
+    }
+
+    if let Some(max_skew) = config.max_clock_skew_secs {
+        let client = connect_to_arbiter(&config, certificates.clone(), roots_cas.clone())
+            .await
+            .map_err(StartupError::Arbiter)?;
+        check_clock_skew(&config, &client, max_skew)
+            .await
+            .map_err(StartupError::Arbiter)?;
+    }
+
+    let registration_key = match register_with_arbiter(
+        &config,
+        port,
+        encryption_key.clone(),
+        None,
+        certificates.clone(),
+        roots_cas.clone(),
+    )
+    .await
+    {
+        Ok(key) => Some(key),
+        Err(e) => {
+            println!("Couldn't register with the arbiter: {e:#}");
+            None
+        }
+    };
+    let registration_key = Mutex::new(registration_key);
+
+    tokio::select! {
+        result = server.run(handler) => {
+            result.context("CoAP server failed").map_err(StartupError::Server)?
+        }
+        _ = poll_revocations(&config, certificates.clone(), roots_cas.clone(), revoked_tokens) => {}
+        _ = renew_registration(
+            &config,
+            port,
+            encryption_key,
+            certificates.clone(),
+            roots_cas.clone(),
+            &registration_key,
+        ) => {}
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutting down");
+            let registration_key = registration_key.lock().unwrap().clone();
+            match registration_key {
+                Some(ref key) => deregister_from_arbiter(&config, key, certificates, roots_cas).await,
+                None => println!("Not registered with the arbiter, so not deregistering"),
+            }
+        }
+    }
+    Ok(())
+}
 
-    server
-        .run(RequestHandler::new(jwt_decoder, config.cid))
+// Port 0 gets an ephemeral port. Returns the port actually bound.
+async fn bind(listen_port: u16, server_config: DtlsConfig) -> anyhow::Result<(impl Listener, u16)> {
+    let listener = listen(("127.0.0.1", listen_port), server_config)
         .await
-        .unwrap();
+        .with_context(|| format!("Couldn't listen on port {listen_port}"))?;
+    let port = listener
+        .addr()
+        .await
+        .context("Couldn't get the bound address")?
+        .port();
+    Ok((listener, port))
 }
 
-fn get_root_cert_store(cert_file: &str) -> RootCertStore {
+fn get_root_cert_store(cert_file: &str) -> anyhow::Result<RootCertStore> {
     let mut store = RootCertStore::empty();
-    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file).unwrap())) {
-        store
-            .add(&RustlsCertificate(cert.unwrap().to_vec()))
-            .unwrap();
+    let file = File::open(cert_file).with_context(|| format!("Couldn't open {cert_file}"))?;
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        store.add(&RustlsCertificate(cert?.to_vec()))?;
     }
-    store
+    Ok(store)
 }
 
-fn get_my_certs(cert_file: &str, key_file: &str) -> Vec<Certificate> {
-    let private_key = std::fs::read_to_string(key_file).unwrap();
-    let private_key = KeyPair::from_pem(&private_key).unwrap();
-    let private_key = CryptoPrivateKey::from_key_pair(&private_key).unwrap();
+fn get_my_certs(cert_file: &str, key_file: &str) -> anyhow::Result<Vec<Certificate>> {
+    let private_key =
+        std::fs::read_to_string(key_file).with_context(|| format!("Couldn't read {key_file}"))?;
+    let private_key = KeyPair::from_pem(&private_key)?;
+    let private_key = CryptoPrivateKey::from_key_pair(&private_key)?;
 
-    let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file).unwrap()))
-        .map(|cert_result| RustlsCertificate(cert_result.unwrap().to_vec()))
-        .collect();
+    let file = File::open(cert_file).with_context(|| format!("Couldn't open {cert_file}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map(|cert_result| Ok(RustlsCertificate(cert_result?.to_vec())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    vec![Certificate {
+    Ok(vec![Certificate {
         certificate: certs,
         private_key,
-    }]
+    }])
 }
 
-fn get_jwt_decoder(public_key_file: &str) -> DecodingKey {
-    let public_key = std::fs::read(public_key_file).unwrap();
-    DecodingKey::from_ec_pem(&public_key).unwrap()
+fn get_jwt_decoder(public_key_file: &str) -> anyhow::Result<DecodingKey> {
+    let public_key = std::fs::read(public_key_file)
+        .with_context(|| format!("Couldn't read {public_key_file}"))?;
+    Ok(DecodingKey::from_ec_pem(&public_key)?)
 }
 
-fn decode_jwt(
-    token: &str,
-    decoder: &DecodingKey,
-    my_cid: &str,
-) -> anyhow::Result<TokenData<JwtClaims>> {
-    let mut validation = Validation::new(Algorithm::ES256);
-    validation.set_audience(&[my_cid]);
-
-    Ok(jsonwebtoken::decode::<JwtClaims>(
-        token,
-        decoder,
-        &validation,
-    )?)
+fn arbiter_client_config(certificates: Vec<Certificate>, roots_cas: RootCertStore) -> DtlsConfig {
+    DtlsConfig {
+        certificates,
+        server_name: "arbiter.local".into(),
+        roots_cas,
+        ..Default::default()
+    }
+}
+
+// Polls the arbiter's /health until it answers, so that the device can be started at the same
+// time as the arbiter
+async fn wait_for_arbiter(
+    config: &Config,
+    ready_timeout: Duration,
+    certificates: &[Certificate],
+    roots_cas: &RootCertStore,
+) -> anyhow::Result<()> {
+    println!("Waiting for arbiter at {}...", config.arbiter_addr);
+    let deadline = Instant::now() + ready_timeout;
+    loop {
+        let check = async {
+            let client = timeout(
+                Duration::from_millis(config.handshake_timeout_ms),
+                CoAPClient::from_udp_dtls_config(UdpDtlsConfig {
+                    config: arbiter_client_config(certificates.to_vec(), roots_cas.clone()),
+                    dest_addr: config.arbiter_addr,
+                }),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            let request = RequestBuilder::new("/health", Method::Get)
+                .domain(config.arbiter_addr.to_string())
+                .build();
+            timeout(
+                Duration::from_millis(config.request_timeout_ms),
+                client.send(request),
+            )
+            .await
+            .ok()?
+            .ok()
+        };
+        if check.await.is_some() {
+            println!("Arbiter is ready");
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            anyhow::bail!("Arbiter wasn't ready after {ready_timeout:?}");
+        }
+        tokio::time::sleep(ARBITER_READY_POLL_INTERVAL).await;
+    }
+}
+
+async fn connect_to_arbiter(
+    config: &Config,
+    certificates: Vec<Certificate>,
+    roots_cas: RootCertStore,
+) -> anyhow::Result<CoAPClient<DtlsConnection>> {
+    let client = timeout(
+        Duration::from_millis(config.handshake_timeout_ms),
+        CoAPClient::from_udp_dtls_config(UdpDtlsConfig {
+            config: arbiter_client_config(certificates, roots_cas),
+            dest_addr: config.arbiter_addr,
+        }),
+    )
+    .await
+    .context("DTLS handshake with arbiter timed out")??;
+    Ok(client)
+}
+
+// Returns the key to renew or end the registration with. A device renewing its registration
+// passes the key it got last time.
+fn registration_payload(
+    config: &Config,
+    port: u16,
+    encryption_key: Option<String>,
+    renewal_key: Option<String>,
+) -> PutDevicePayload {
+    PutDevicePayload {
+        label: config.label.clone(),
+        manufacturer: config.manufacturer.clone(),
+        model: config.model.clone(),
+        port,
+        ttl: config.registration_ttl_secs,
+        tags: config.tags.clone(),
+        parameters: config
+            .advertise_parameters
+            .then(|| config.parameters.keys().cloned().collect()),
+        encryption_key,
+        registration_key: renewal_key,
+    }
 }
 
 async fn register_with_arbiter(
     config: &Config,
     port: u16,
+    encryption_key: Option<String>,
+    renewal_key: Option<String>,
     certificates: Vec<Certificate>,
     roots_cas: RootCertStore,
+) -> anyhow::Result<String> {
+    // Register with the Arbiter
+    let payload = registration_payload(config, port, encryption_key, renewal_key);
+    let request = RequestBuilder::new(&format!("/devices/{}", config.cid), Method::Put)
+        .domain(config.arbiter_addr.to_string())
+        .data(Some(serde_json::to_vec(&payload).unwrap()))
+        .build();
+
+    let client = connect_to_arbiter(config, certificates, roots_cas).await?;
+
+    println!("Registering device {} with arbiter...", config.cid);
+    let response = timeout(
+        Duration::from_millis(config.request_timeout_ms),
+        client.send(request),
+    )
+    .await
+    .context("Registration request timed out")??;
+    match response.get_status() {
+        ResponseType::Created | ResponseType::Changed | ResponseType::Content => {
+            println!("Server reply: {:?}", response.get_status().clone());
+            let registration: RegistrationResponse =
+                serde_json::from_slice(&response.message.payload)
+                    .context("Invalid registration response")?;
+            Ok(registration.registration_key)
+        }
+        status => anyhow::bail!(
+            "Arbiter refused with {status:?}: {}",
+            failure_reason(&response.message.payload)
+        ),
+    }
+}
+
+// Runs forever, registering again every half TTL. A failed attempt is retried at the next one,
+// which also covers the first registration having failed.
+async fn renew_registration(
+    config: &Config,
+    port: u16,
+    encryption_key: Option<String>,
+    certificates: Vec<Certificate>,
+    roots_cas: RootCertStore,
+    registration_key: &Mutex<Option<String>>,
 ) {
-    let dtls_config = DtlsConfig {
-        certificates,
-        server_name: "arbiter.local".into(),
-        roots_cas,
-        ..Default::default()
+    let period = Duration::from_secs((config.registration_ttl_secs / 2).max(1));
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let renewal_key = registration_key.lock().unwrap().clone();
+        match register_with_arbiter(
+            config,
+            port,
+            encryption_key.clone(),
+            renewal_key,
+            certificates.clone(),
+            roots_cas.clone(),
+        )
+        .await
+        {
+            Ok(key) => *registration_key.lock().unwrap() = Some(key),
+            Err(e) => println!("Couldn't renew registration with the arbiter: {e:#}"),
+        }
+    }
+}
+
+// Best effort, since we're exiting either way; the registration expires on its own otherwise
+async fn deregister_from_arbiter(
+    config: &Config,
+    registration_key: &str,
+    certificates: Vec<Certificate>,
+    roots_cas: RootCertStore,
+) {
+    let deregister = async {
+        let client = timeout(
+            Duration::from_millis(config.handshake_timeout_ms),
+            CoAPClient::from_udp_dtls_config(UdpDtlsConfig {
+                config: arbiter_client_config(certificates, roots_cas),
+                dest_addr: config.arbiter_addr,
+            }),
+        )
+        .await
+        .ok()?
+        .ok()?;
+        let request = RequestBuilder::new(&format!("/devices/{}", config.cid), Method::Delete)
+            .domain(config.arbiter_addr.to_string())
+            .data(Some(
+                serde_json::to_vec(&DeleteDevicePayload { registration_key }).unwrap(),
+            ))
+            .build();
+        timeout(
+            Duration::from_millis(config.request_timeout_ms),
+            client.send(request),
+        )
+        .await
+        .ok()?
+        .ok()
     };
-    let client_config = UdpDtlsConfig {
-        config: dtls_config,
-        dest_addr: ("127.0.0.1", 5683)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap(),
+    match deregister.await {
+        Some(response) => match response.get_status() {
+            ResponseType::Content | ResponseType::Deleted => println!("Deregistered from arbiter"),
+            status => println!(
+                "Deregistration failed with {status:?}: {}",
+                failure_reason(&response.message.payload)
+            ),
+        },
+        None => println!("Couldn't reach the arbiter to deregister"),
+    }
+}
+
+// Runs forever, and never returns at all if revocationPollSecs isn't set. A failed fetch keeps
+// the previous list.
+async fn poll_revocations(
+    config: &Config,
+    certificates: Vec<Certificate>,
+    roots_cas: RootCertStore,
+    revoked_tokens: Arc<RwLock<HashSet<Uuid>>>,
+) {
+    let Some(poll_secs) = config.revocation_poll_secs else {
+        return std::future::pending().await;
     };
+    let mut interval = tokio::time::interval(Duration::from_secs(poll_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let fetch = async {
+            let client = timeout(
+                Duration::from_millis(config.handshake_timeout_ms),
+                CoAPClient::from_udp_dtls_config(UdpDtlsConfig {
+                    config: arbiter_client_config(certificates.clone(), roots_cas.clone()),
+                    dest_addr: config.arbiter_addr,
+                }),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            let request = RequestBuilder::new("/revocations", Method::Get)
+                .domain(config.arbiter_addr.to_string())
+                .build();
+            let response = timeout(
+                Duration::from_millis(config.request_timeout_ms),
+                client.send(request),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            serde_json::from_slice::<HashSet<Uuid>>(&response.message.payload).ok()
+        };
+        match fetch.await {
+            Some(revoked) => {
+                let mut current = revoked_tokens.write().unwrap();
+                if *current != revoked {
+                    println!("{} tokens are revoked", revoked.len());
+                    *current = revoked;
+                }
+            }
+            None => println!("Couldn't fetch revoked tokens from the arbiter"),
+        }
+    }
+}
 
-    // Register with the Arbiter
-    let request = RequestBuilder::new(&format!("/devices/{}", config.cid), Method::Put)
-        .domain("127.0.0.1:5683".into())
-        .data(Some(
-            serde_json::to_vec(&PutDevicePayload {
-                label: config.label.clone(),
-                manufacturer: config.manufacturer.clone(),
-                model: config.model.clone(),
-                port,
-                ttl: 3600,
-            })
-            .unwrap(),
-        ))
+async fn check_clock_skew(
+    config: &Config,
+    client: &CoAPClient<DtlsConnection>,
+    max_skew: u64,
+) -> anyhow::Result<()> {
+    let request = RequestBuilder::new("/info", Method::Get)
+        .domain(config.arbiter_addr.to_string())
         .build();
+    let response = timeout(
+        Duration::from_millis(config.request_timeout_ms),
+        client.send(request),
+    )
+    .await
+    .context("Arbiter info request timed out")??;
+    let info: ArbiterInfo =
+        serde_json::from_slice(&response.message.payload).context("Invalid arbiter info")?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let skew = clock_skew(now, info.time);
+    if skew.unsigned_abs() <= max_skew {
+        return Ok(());
+    }
+    let message = format!(
+        "Clock is {}s {} the arbiter's, more than the allowed {max_skew}s; tokens may be \
+         rejected as expired or not yet valid",
+        skew.unsigned_abs(),
+        if skew > 0 { "ahead of" } else { "behind" }
+    );
+    if config.clock_skew_fatal {
+        anyhow::bail!(message);
+    }
+    println!("Warning: {message}");
+    Ok(())
+}
 
-    let client = CoAPClient::from_udp_dtls_config(client_config)
-        .await
+// Positive when our clock is ahead of the arbiter's
+fn clock_skew(now: u64, arbiter_time: u64) -> i64 {
+    now as i64 - arbiter_time as i64
+}
+
+// The arbiter puts the reason for an error response in its payload
+fn failure_reason(payload: &[u8]) -> String {
+    match String::from_utf8_lossy(payload).trim() {
+        "" => "no reason given".to_string(),
+        reason => reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use coap_lite::CoapRequest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn server_config() -> DtlsConfig {
+        let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["device.local".to_string()]);
+        params.key_pair = Some(key_pair);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        DtlsConfig {
+            certificates: vec![Certificate {
+                certificate: vec![RustlsCertificate(cert.serialize_der().unwrap())],
+                private_key: CryptoPrivateKey::from_key_pair(cert.get_key_pair()).unwrap(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn configured_listen_port_is_bound_and_registered() {
+        // A port that was free a moment ago
+        let listen_port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "listenPort": listen_port,
+        }))
         .unwrap();
 
-    println!("Registering device {} with arbiter...", config.cid);
-    let response = client.send(request).await.unwrap();
-    println!("Server reply: {:?}", response.get_status().clone());
+        let (_listener, port) = bind(config.listen_port, server_config()).await.unwrap();
+        assert_eq!(port, listen_port);
+        assert_eq!(
+            registration_payload(&config, port, None, None).port,
+            listen_port
+        );
+
+        // Without one the device gets whatever port is free
+        let (_listener, port) = bind(0, server_config()).await.unwrap();
+        assert_ne!(port, 0);
+    }
+
+    // An arbiter certificate signed by a CA of its own, and a store trusting that CA
+    fn arbiter_certs() -> (Certificate, RootCertStore) {
+        let mut ca_params = rcgen::CertificateParams::new(vec![]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let mut roots_cas = RootCertStore::empty();
+        roots_cas
+            .add(&RustlsCertificate(ca.serialize_der().unwrap()))
+            .unwrap();
+
+        let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "arbiter.local".to_string(),
+        ]))
+        .unwrap();
+        let certificate = Certificate {
+            certificate: vec![RustlsCertificate(
+                cert.serialize_der_with_signer(&ca).unwrap(),
+            )],
+            private_key: CryptoPrivateKey::from_key_pair(cert.get_key_pair()).unwrap(),
+        };
+        (certificate, roots_cas)
+    }
+
+    #[tokio::test]
+    async fn registration_waits_for_the_arbiter_to_become_ready() {
+        let arbiter_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config: Config = serde_json::from_value(json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "arbiterAddr": arbiter_addr,
+            "handshakeTimeoutMs": 300,
+            "requestTimeoutMs": 300,
+        }))
+        .unwrap();
+        let (arbiter_cert, roots_cas) = arbiter_certs();
+
+        // Nothing is listening yet
+        let e = wait_for_arbiter(&config, Duration::from_millis(500), &[], &roots_cas)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Arbiter wasn't ready after 500ms");
+
+        // Stands in for an arbiter that takes a while to come up
+        let start = Instant::now();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            let dtls_config = DtlsConfig {
+                certificates: vec![arbiter_cert],
+                ..Default::default()
+            };
+            let listener = listen(arbiter_addr, dtls_config).await.unwrap();
+            let server = Server::from_listeners(vec![Box::new(listener)]);
+            server
+                .run(|request: Box<CoapRequest<SocketAddr>>| async { request })
+                .await
+        });
+        wait_for_arbiter(&config, Duration::from_secs(10), &[], &roots_cas)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+    }
+
+    // Stands in for the arbiter, answering every request with the given status and payload.
+    // Returns a config pointing at it and the store to verify it with.
+    async fn mock_arbiter(
+        status: ResponseType,
+        payload: Vec<u8>,
+        config: serde_json::Value,
+    ) -> (Config, RootCertStore) {
+        let (arbiter_cert, roots_cas) = arbiter_certs();
+        let dtls_config = DtlsConfig {
+            certificates: vec![arbiter_cert],
+            ..Default::default()
+        };
+        let listener = listen("127.0.0.1:0", dtls_config).await.unwrap();
+        let arbiter_addr = listener.addr().await.unwrap();
+        let server = Server::from_listeners(vec![Box::new(listener)]);
+        tokio::spawn(
+            server.run(move |mut request: Box<CoapRequest<SocketAddr>>| {
+                let payload = payload.clone();
+                async move {
+                    let response = request.response.as_mut().unwrap();
+                    response.set_status(status);
+                    response.message.payload = payload;
+                    request
+                }
+            }),
+        );
+
+        let mut fields = json!({
+            "cid": Uuid::nil(),
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "arbiterAddr": arbiter_addr,
+        });
+        fields
+            .as_object_mut()
+            .unwrap()
+            .extend(config.as_object().unwrap().clone());
+        (serde_json::from_value(fields).unwrap(), roots_cas)
+    }
+
+    #[tokio::test]
+    async fn refused_registration_reports_the_arbiter_reason() {
+        let (config, roots_cas) = mock_arbiter(
+            ResponseType::BadRequest,
+            b"Device isn't provisioned".to_vec(),
+            json!({}),
+        )
+        .await;
+
+        let e = register_with_arbiter(&config, 47111, None, None, vec![], roots_cas)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Arbiter refused with BadRequest: Device isn't provisioned"
+        );
+        assert_eq!(failure_reason(b" \n"), "no reason given");
+    }
+
+    #[tokio::test]
+    async fn clock_skew_is_checked_against_the_arbiter_time() {
+        assert_eq!(clock_skew(1000, 880), 120);
+        assert_eq!(clock_skew(880, 1000), -120);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // The arbiter's clock is two minutes behind ours
+        let info = serde_json::to_vec(&json!({ "time": now - 120 })).unwrap();
+        for fatal in [false, true] {
+            let (config, roots_cas) = mock_arbiter(
+                ResponseType::Content,
+                info.clone(),
+                json!({ "clockSkewFatal": fatal }),
+            )
+            .await;
+            let client = connect_to_arbiter(&config, vec![], roots_cas)
+                .await
+                .unwrap();
+
+            assert!(check_clock_skew(&config, &client, 300).await.is_ok());
+            let result = check_clock_skew(&config, &client, 60).await;
+            if fatal {
+                let e = result.unwrap_err().to_string();
+                assert!(e.starts_with("Clock is 12"), "{e}");
+                assert!(e.contains("s ahead of the arbiter's, more than the allowed 60s"));
+            } else {
+                assert!(result.is_ok());
+            }
+        }
+    }
 }
@@ -0,0 +1,2638 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use coap::request::{CoapRequest, Method};
+use coap_lite::error::HandlingError;
+use coap_lite::option_value::OptionValueU16;
+use coap_lite::{CoapOption, ContentFormat, MessageClass, Packet, ResponseType};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, TokenData, Validation};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    config::{Config, ParameterConfig, ParameterType},
+    encryption::{EncryptedValue, ValueKey},
+    error::{rejection, ErrorReason},
+};
+
+#[derive(Deserialize)]
+struct GetParamPayload {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetParamPayload {
+    token: String,
+    // One of these two
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    encrypted_value: Option<EncryptedValue>,
+}
+
+#[derive(Deserialize)]
+struct BatchGetPayload {
+    token: String,
+    params: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct BatchSetPayload {
+    token: String,
+    // Written in order of parameter name
+    values: BTreeMap<String, WriteValue>,
+}
+
+#[derive(Deserialize)]
+struct GroupPayload {
+    token: String,
+    values: BTreeMap<String, WriteValue>,
+}
+
+// A value in a group or batch write, either plain or shaped like a PUT payload so that it can be
+// encrypted
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WriteValue {
+    Plain(String),
+    #[serde(rename_all = "camelCase")]
+    Put {
+        #[serde(default)]
+        value: Option<String>,
+        #[serde(default)]
+        encrypted_value: Option<EncryptedValue>,
+    },
+}
+
+impl WriteValue {
+    fn into_parts(self) -> (Option<String>, Option<EncryptedValue>) {
+        match self {
+            Self::Plain(value) => (Some(value), None),
+            Self::Put {
+                value,
+                encrypted_value,
+            } => (value, encrypted_value),
+        }
+    }
+}
+
+// One parameter's outcome in a batch response, the value read or written or why not
+#[derive(Serialize)]
+struct BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<serde_json::Value>,
+}
+
+impl From<Result<String, HandlingError>> for BatchResult {
+    fn from(result: Result<String, HandlingError>) -> Self {
+        match result {
+            Ok(value) => BatchResult {
+                value: Some(value),
+                error: None,
+            },
+            // The message is already the JSON error body
+            Err(e) => BatchResult {
+                value: None,
+                error: Some(serde_json::from_str(&e.message).unwrap_or(e.message.into())),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateParamPayload {
+    token: String,
+    name: String,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(flatten)]
+    config: ParameterConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidateTokenPayload {
+    token: String,
+    #[serde(default)]
+    params_read: Vec<String>,
+    #[serde(default)]
+    params_write: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidateTokenResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    allowed_read: Vec<String>,
+    forbidden_read: Vec<String>,
+    allowed_write: Vec<String>,
+    forbidden_write: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ParamDescription<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    ty: Option<ParameterType>,
+}
+
+// GET response for requests that accept JSON
+#[derive(Serialize)]
+struct TypedValue {
+    value: serde_json::Value,
+    #[serde(rename = "type")]
+    ty: ParameterType,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ParamMeta {
+    // The controller (token subject) that last wrote the parameter
+    modified_by: String,
+    // Unix seconds
+    modified_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JwtClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat: Option<u64>,
+    jti: Uuid,
+    params_read: Vec<String>,
+    params_write: Vec<String>,
+    // Any claims the arbiter added from its ACL, e.g. a role
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+// The JWT aud claim may be a single string or a list
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn allows(&self, cid: &str, strict: bool) -> bool {
+        match self {
+            Audience::Single(aud) => aud == cid,
+            Audience::Multiple(auds) => !strict && auds.iter().any(|aud| aud == cid),
+        }
+    }
+}
+
+impl Display for Audience {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Audience::Single(aud) => write!(f, "{aud}"),
+            Audience::Multiple(auds) => write!(f, "[{}]", auds.join(", ")),
+        }
+    }
+}
+
+// What a token is being decoded for, which decides whether replay protection applies
+#[derive(Clone, Copy)]
+enum TokenUse {
+    Read,
+    Write,
+    // Only checking the token, as POST /validateToken does, which doesn't use it up
+    Check,
+}
+
+// The jtis of the most recent tokens used, forgetting the oldest once full
+struct SeenTokens {
+    capacity: usize,
+    order: VecDeque<Uuid>,
+    jtis: HashSet<Uuid>,
+}
+
+impl SeenTokens {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            jtis: HashSet::with_capacity(capacity),
+        }
+    }
+
+    fn contains(&self, jti: &Uuid) -> bool {
+        self.jtis.contains(jti)
+    }
+
+    // False if the jti was already seen
+    fn insert(&mut self, jti: Uuid) -> bool {
+        if !self.jtis.insert(jti) {
+            return false;
+        }
+        self.order.push_back(jti);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.jtis.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+struct SeenMessage {
+    received: Instant,
+    // None until the first copy has been handled
+    response: Option<Packet>,
+}
+
+pub struct RequestHandler {
+    jwt_decoder: DecodingKey,
+    // What tokens for this device carry as their audience: its CID, or its label
+    audience: String,
+    // The arbiter's CID, which tokens must carry as their issuer if it is configured
+    issuer: Option<String>,
+    require_token_exp: bool,
+    max_token_age_secs: Option<u64>,
+    max_token_length: usize,
+    strict_audience: bool,
+    allowed_controllers: Option<Vec<String>>,
+    // Kept up to date from the arbiter if revocationPollSecs is set
+    revoked_tokens: Arc<RwLock<HashSet<Uuid>>>,
+    // Set if tokens may only be used once
+    seen_tokens: Option<Mutex<SeenTokens>>,
+    replay_protect_reads: bool,
+    cid: Uuid,
+    value_key: Option<ValueKey>,
+    require_encrypted_values: bool,
+    // Parameters can be added at runtime with POST /params, and their constraints reloaded from
+    // the config file with POST /_reload
+    parameters: RwLock<Parameters>,
+    profiles: BTreeMap<String, BTreeMap<String, String>>,
+    values: Mutex<HashMap<String, String>>,
+    param_meta: Mutex<HashMap<String, ParamMeta>>,
+    // While set, parameter writes are refused with 5.03 and reads carry on as usual
+    maintenance: AtomicBool,
+    audit_log: Option<AuditLog>,
+    response_delay: Duration,
+    response_jitter_ms: u64,
+    jitter_rng: Mutex<StdRng>,
+    dedup_window: Option<Duration>,
+    // Keyed by peer and message ID
+    seen_messages: Mutex<HashMap<(SocketAddr, u16), SeenMessage>>,
+}
+
+impl RequestHandler {
+    pub fn new(
+        jwt_decoder: DecodingKey,
+        audit_log: Option<AuditLog>,
+        value_key: Option<ValueKey>,
+        revoked_tokens: Arc<RwLock<HashSet<Uuid>>>,
+        config: &Config,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            jwt_decoder,
+            audience: if config.label_audience {
+                config.label.clone()
+            } else {
+                config.cid.to_string()
+            },
+            issuer: config.arbiter_cid.map(|cid| cid.to_string()),
+            require_token_exp: config.require_token_exp,
+            max_token_age_secs: config.max_token_age_secs,
+            max_token_length: config.max_token_length,
+            strict_audience: config.strict_audience,
+            allowed_controllers: config.allowed_controllers.clone(),
+            revoked_tokens,
+            seen_tokens: config
+                .replay_cache_size
+                .map(|capacity| Mutex::new(SeenTokens::new(capacity.max(1)))),
+            replay_protect_reads: config.replay_protect_reads,
+            cid: config.cid,
+            value_key,
+            require_encrypted_values: config.require_encrypted_values,
+            parameters: RwLock::new(
+                Parameters::new(config.parameters.clone()).map_err(anyhow::Error::msg)?,
+            ),
+            profiles: config.profiles.clone(),
+            values: Mutex::new(initial_values(config)),
+            param_meta: Mutex::new(HashMap::new()),
+            maintenance: AtomicBool::new(false),
+            audit_log,
+            response_delay: Duration::from_millis(config.response_delay_ms),
+            response_jitter_ms: config.response_jitter_ms,
+            jitter_rng: Mutex::new(match config.jitter_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
+            dedup_window: config.message_dedup_window_ms.map(Duration::from_millis),
+            seen_messages: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // True if the request is a duplicate and has been answered (or dropped, while the original is
+    // still being handled) without handling it again
+    fn deduplicate(&self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        let (Some(window), Some(source)) = (self.dedup_window, request.source) else {
+            return false;
+        };
+        let message_id = request.message.header.message_id;
+        let now = Instant::now();
+
+        let mut seen_messages = self.seen_messages.lock().unwrap();
+        seen_messages.retain(|_, seen| now.duration_since(seen.received) < window);
+        let Some(seen) = seen_messages.get(&(source, message_id)) else {
+            seen_messages.insert(
+                (source, message_id),
+                SeenMessage {
+                    received: now,
+                    response: None,
+                },
+            );
+            return false;
+        };
+
+        match (&seen.response, request.response.as_mut()) {
+            (Some(response), Some(reply)) => {
+                println!("Repeating response to duplicate message {message_id} from {source}");
+                reply.message = response.clone();
+            }
+            _ => {
+                println!(
+                    "Dropping duplicate of message {message_id} from {source}, still handling it"
+                );
+                request.response = None;
+            }
+        }
+        true
+    }
+
+    fn remember_response(&self, request: &CoapRequest<SocketAddr>) {
+        let (Some(source), Some(response)) = (request.source, request.response.as_ref()) else {
+            return;
+        };
+        let key = (source, request.message.header.message_id);
+        if let Some(seen) = self.seen_messages.lock().unwrap().get_mut(&key) {
+            seen.response = Some(response.message.clone());
+        }
+    }
+
+    // 4.04 for a parameter with no value, telling apart ones the device doesn't have at all
+    fn missing_value(&self, parameter: &str) -> HandlingError {
+        if self
+            .parameters
+            .read()
+            .unwrap()
+            .configs
+            .contains_key(parameter)
+        {
+            println!("Parameter {parameter} has no value yet");
+            rejection(
+                ResponseType::NotFound,
+                ErrorReason::NotFound,
+                format!("Parameter {parameter} has no value yet"),
+            )
+        } else {
+            println!("No parameter named {parameter}");
+            rejection(
+                ResponseType::NotFound,
+                ErrorReason::UnknownParameter,
+                format!("No parameter named {parameter}"),
+            )
+        }
+    }
+
+    fn simulated_delay(&self) -> Duration {
+        let jitter = match self.response_jitter_ms {
+            0 => 0,
+            max => self.jitter_rng.lock().unwrap().gen_range(0..=max),
+        };
+        self.response_delay + Duration::from_millis(jitter)
+    }
+
+    fn get_parameter(
+        &self,
+        request: &mut CoapRequest<SocketAddr>,
+        parameter: String,
+    ) -> Result<(), HandlingError> {
+        println!("Handling GET /{}", parameter);
+
+        let payload: GetParamPayload = parse_payload(request, "GET /")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Read)?;
+        let value = self.read_parameter(&jwt_data.claims, &parameter)?;
+        self.use_token(&jwt_data.claims, TokenUse::Read)?;
+
+        let ty = self
+            .parameters
+            .read()
+            .unwrap()
+            .configs
+            .get(&parameter)
+            .and_then(|config| config.ty);
+        let accepts_json = accepts_json(request);
+        if let Some(ref mut message) = request.response {
+            if accepts_json {
+                let ty = ty.unwrap_or(ParameterType::String);
+                message.message.payload = serde_json::to_vec(&TypedValue {
+                    value: typed_value(&value, ty),
+                    ty,
+                })
+                .unwrap();
+                message
+                    .message
+                    .set_content_format(ContentFormat::ApplicationJSON);
+            } else {
+                message.message.payload = value.into_bytes();
+            }
+        }
+        Ok(())
+    }
+
+    fn set_parameter(
+        &self,
+        request: &mut CoapRequest<SocketAddr>,
+        parameter: String,
+    ) -> Result<(), HandlingError> {
+        println!("Handling PUT /{}", parameter);
+        self.check_not_in_maintenance()?;
+
+        let payload: SetParamPayload = parse_payload(request, "SET /")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+        let value = self.put_value(payload.value, payload.encrypted_value, &parameter)?;
+        self.write_parameter(&jwt_data.claims, &parameter, value, true)?;
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    // The permission check and lookup behind GET /{param}, shared with POST /batchGet
+    fn read_parameter(&self, claims: &JwtClaims, parameter: &str) -> Result<String, HandlingError> {
+        if !claims.params_read.iter().any(|p| p == parameter) {
+            println!(
+                "Validation error: Token does not have permission to access parameter {parameter}"
+            );
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission for parameter",
+            ));
+        }
+
+        println!("Get request validated successfully.");
+        let Some(value) = self.values.lock().unwrap().get(parameter).cloned() else {
+            return Err(self.missing_value(parameter));
+        };
+        self.audit(AuditEvent::Get {
+            parameter,
+            controller: &claims.sub,
+            value: &value,
+        });
+        Ok(value)
+    }
+
+    // The checks and write behind PUT /{param}, shared with POST /batchSet. Uses the token up
+    // along with the write if asked to.
+    fn write_parameter(
+        &self,
+        claims: &JwtClaims,
+        parameter: &str,
+        value: String,
+        use_token: bool,
+    ) -> Result<(), HandlingError> {
+        check_write_permission(claims, parameter)?;
+
+        // Held until the value is written, so that the whole write is checked against one set of
+        // constraints even if they are reloaded meanwhile
+        let parameters = self.parameters.read().unwrap();
+        let mut values = self.values.lock().unwrap();
+        parameters.check_value(&values, parameter, &value)?;
+        if use_token {
+            self.use_token(claims, TokenUse::Write)?;
+        }
+
+        println!("Put request validated successfully.");
+        println!("Setting {parameter} to {value}");
+        let old_value = values.insert(parameter.to_string(), value.clone());
+        drop(values);
+        drop(parameters);
+        self.record_write(claims, parameter, old_value.as_deref(), &value);
+        Ok(())
+    }
+
+    fn record_write(
+        &self,
+        claims: &JwtClaims,
+        parameter: &str,
+        old_value: Option<&str>,
+        new_value: &str,
+    ) {
+        self.audit(AuditEvent::Put {
+            parameter,
+            controller: &claims.sub,
+            old_value,
+            new_value,
+        });
+        self.param_meta.lock().unwrap().insert(
+            parameter.to_string(),
+            ParamMeta {
+                modified_by: claims.sub.clone(),
+                modified_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            },
+        );
+    }
+
+    // Sets several parameters all or nothing. The token must allow writing every one of them,
+    // and each value is checked against the values the device would hold once the whole group is
+    // applied, so that e.g. a mode and a setpoint requiring it can be set together. Nothing is
+    // written unless every check passes.
+    fn set_group(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling PUT /_group");
+        self.check_not_in_maintenance()?;
+
+        let payload: GroupPayload = parse_payload(request, "PUT /_group")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        let mut group = BTreeMap::new();
+        for (parameter, value) in payload.values {
+            check_write_permission(&jwt_data.claims, &parameter)?;
+            let (value, encrypted_value) = value.into_parts();
+            let value = self.put_value(value, encrypted_value, &parameter)?;
+            group.insert(parameter, value);
+        }
+
+        self.apply_values(&jwt_data.claims, group)?;
+        if let Some(ref mut message) = request.response {
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    // Sets the values all or nothing, each one checked against the values the device would hold
+    // once all of them are applied, shared by PUT /_group and PUT /_profile
+    fn apply_values(
+        &self,
+        claims: &JwtClaims,
+        group: BTreeMap<String, String>,
+    ) -> Result<(), HandlingError> {
+        let parameters = self.parameters.read().unwrap();
+        let mut values = self.values.lock().unwrap();
+        let mut new_values = values.clone();
+        new_values.extend(group.clone());
+        for (parameter, value) in &group {
+            parameters.check_value(&new_values, parameter, value)?;
+        }
+        self.use_token(claims, TokenUse::Write)?;
+
+        println!(
+            "Values validated successfully, setting {} parameters",
+            group.len()
+        );
+        let old_values = std::mem::replace(&mut *values, new_values);
+        drop(values);
+        drop(parameters);
+        for (parameter, value) in &group {
+            println!("Setting {parameter} to {value}");
+            self.record_write(
+                claims,
+                parameter,
+                old_values.get(parameter).map(String::as_str),
+                value,
+            );
+        }
+        Ok(())
+    }
+
+    // Reads several parameters at once. Responds with a JSON object mapping each requested
+    // parameter to either its value or the error a GET of it alone would have given, so that
+    // parameters the token can't read don't fail the whole batch.
+    fn batch_get(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling POST /batchGet");
+
+        let payload: BatchGetPayload = parse_payload(request, "POST /batchGet")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Read)?;
+
+        let results: BTreeMap<_, _> = payload
+            .params
+            .into_iter()
+            .map(|parameter| {
+                let result = self.read_parameter(&jwt_data.claims, &parameter);
+                (parameter, BatchResult::from(result))
+            })
+            .collect();
+        if results.values().any(|result| result.error.is_none()) {
+            self.use_token(&jwt_data.claims, TokenUse::Read)?;
+        }
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload = serde_json::to_vec(&results).unwrap();
+            message
+                .message
+                .set_content_format(ContentFormat::ApplicationJSON);
+        }
+        Ok(())
+    }
+
+    // Writes several parameters at once, reporting on each like POST /batchGet. A value that
+    // fails its checks is skipped and the rest are still written.
+    fn batch_set(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling POST /batchSet");
+        self.check_not_in_maintenance()?;
+
+        let payload: BatchSetPayload = parse_payload(request, "POST /batchSet")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        // The token is used up by the first write that goes through
+        let mut token_used = false;
+        let mut results = BTreeMap::new();
+        for (parameter, value) in payload.values {
+            println!("Setting {parameter} from batch");
+            let (value, encrypted_value) = value.into_parts();
+            let result = self
+                .put_value(value, encrypted_value, &parameter)
+                .and_then(|value| {
+                    self.write_parameter(&jwt_data.claims, &parameter, value.clone(), !token_used)?;
+                    Ok(value)
+                });
+            token_used |= result.is_ok();
+            results.insert(parameter, BatchResult::from(result));
+        }
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload = serde_json::to_vec(&results).unwrap();
+            message
+                .message
+                .set_content_format(ContentFormat::ApplicationJSON);
+        }
+        Ok(())
+    }
+
+    // The value a PUT carries, decrypted if it was encrypted to our value key
+    fn put_value(
+        &self,
+        value: Option<String>,
+        encrypted_value: Option<EncryptedValue>,
+        parameter: &str,
+    ) -> Result<String, HandlingError> {
+        match (value, encrypted_value) {
+            (Some(_), Some(_)) | (None, None) => Err(rejection(
+                ResponseType::BadRequest,
+                ErrorReason::InvalidRequest,
+                "Exactly one of value and encryptedValue must be given",
+            )),
+            (Some(_), None) if self.require_encrypted_values => {
+                println!("Validation error: Value for {parameter} wasn't encrypted");
+                Err(rejection(
+                    ResponseType::BadRequest,
+                    ErrorReason::ConstraintViolation,
+                    "Values must be encrypted",
+                ))
+            }
+            (Some(value), None) => Ok(value),
+            (None, Some(encrypted)) => {
+                let Some(ref value_key) = self.value_key else {
+                    return Err(rejection(
+                        ResponseType::BadRequest,
+                        ErrorReason::InvalidRequest,
+                        "This device doesn't accept encrypted values",
+                    ));
+                };
+                value_key
+                    .decrypt(&encrypted, &self.cid, parameter)
+                    .map_err(|e| {
+                        println!("Validation error: Couldn't decrypt value for {parameter}: {e}");
+                        rejection(
+                            ResponseType::BadRequest,
+                            ErrorReason::InvalidRequest,
+                            "Couldn't decrypt value",
+                        )
+                    })
+            }
+        }
+    }
+
+    // Needs a token allowing writes to "_params". Responds 2.01 with the new parameter's path.
+    fn create_parameter(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling POST /params");
+        self.check_not_in_maintenance()?;
+
+        let payload: CreateParamPayload = parse_payload(request, "POST /params")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        if !jwt_data.claims.params_write.iter().any(|p| p == "_params") {
+            println!("Validation error: Token does not have permission to create parameters");
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission to create parameters",
+            ));
+        }
+
+        let name = payload.name;
+        // Names that would clash with the device's other routes
+        if name.is_empty()
+            || name.contains('/')
+            || name.starts_with('_')
+            || name == "params"
+            || name == "validateToken"
+            || name == "batchGet"
+            || name == "batchSet"
+        {
+            return Err(rejection(
+                ResponseType::BadRequest,
+                ErrorReason::InvalidRequest,
+                format!("Invalid parameter name {name}"),
+            ));
+        }
+        let pattern = payload
+            .config
+            .pattern
+            .as_ref()
+            .map(|pattern| compile_pattern(pattern))
+            .transpose()
+            .map_err(|e| {
+                rejection(
+                    ResponseType::BadRequest,
+                    ErrorReason::InvalidRequest,
+                    format!("Invalid pattern: {e}"),
+                )
+            })?;
+
+        let mut parameters = self.parameters.write().unwrap();
+        if parameters.configs.contains_key(&name) {
+            return Err(rejection(
+                ResponseType::Conflict,
+                ErrorReason::Conflict,
+                format!("Parameter {name} already exists"),
+            ));
+        }
+        // The initial value has to meet the new parameter's own constraints like any write
+        let mut values = self.values.lock().unwrap();
+        let value = payload.value.or(payload.config.initial_value.clone());
+        if let Some(ref value) = value {
+            check_constraints(
+                Some(&payload.config),
+                pattern.as_ref(),
+                &values,
+                &name,
+                value,
+            )?;
+        }
+        self.use_token(&jwt_data.claims, TokenUse::Write)?;
+
+        println!("Creating parameter {name}");
+        if let Some(value) = value {
+            values.insert(name.clone(), value);
+        }
+        drop(values);
+        parameters.configs.insert(name.clone(), payload.config);
+        if let Some(pattern) = pattern {
+            parameters.patterns.insert(name.clone(), pattern);
+        }
+        if let Some(ref mut message) = request.response {
+            message.message.header.code = MessageClass::Response(ResponseType::Created);
+            message
+                .message
+                .add_option(CoapOption::LocationPath, name.into_bytes());
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    // Needs a token allowing writes to "_reload". Replaces the definitions of the parameters in
+    // the config file with the file's current ones; parameters only created with POST /params are
+    // left alone. Nothing changes unless the whole file is valid.
+    fn reload_parameters(
+        &self,
+        request: &mut CoapRequest<SocketAddr>,
+    ) -> Result<(), HandlingError> {
+        println!("Handling POST /_reload");
+        self.check_not_in_maintenance()?;
+
+        let payload: GetParamPayload = parse_payload(request, "POST /_reload")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        if !jwt_data.claims.params_write.iter().any(|p| p == "_reload") {
+            println!("Validation error: Token does not have permission to reload parameters");
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission to reload parameters",
+            ));
+        }
+
+        let config = crate::config::load().map_err(|e| {
+            println!("Couldn't reload config: {e:#}");
+            rejection(
+                ResponseType::BadRequest,
+                ErrorReason::InvalidRequest,
+                format!("Couldn't reload config: {e:#}"),
+            )
+        })?;
+
+        let mut parameters = self.parameters.write().unwrap();
+        let mut configs = parameters.configs.clone();
+        configs.extend(config.parameters);
+        let reloaded = Parameters::new(configs)
+            .map_err(|e| rejection(ResponseType::BadRequest, ErrorReason::InvalidRequest, e))?;
+        self.use_token(&jwt_data.claims, TokenUse::Write)?;
+        *parameters = reloaded;
+        println!("Reloaded parameter definitions");
+        if let Some(ref mut message) = request.response {
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    // Needs a token allowing writes to "_maintenance". The value is "true" to enter maintenance
+    // mode or "false" to leave it.
+    fn set_maintenance(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling PUT /_maintenance");
+
+        let payload: SetParamPayload = parse_payload(request, "PUT /_maintenance")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        if !jwt_data
+            .claims
+            .params_write
+            .iter()
+            .any(|p| p == "_maintenance")
+        {
+            println!("Validation error: Token does not have permission to set maintenance mode");
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission to set maintenance mode",
+            ));
+        }
+
+        let Ok(maintenance) = self
+            .put_value(payload.value, payload.encrypted_value, "_maintenance")?
+            .parse::<bool>()
+        else {
+            return Err(rejection(
+                ResponseType::BadRequest,
+                ErrorReason::ConstraintViolation,
+                "Maintenance mode must be true or false",
+            ));
+        };
+
+        println!(
+            "{} maintenance mode",
+            if maintenance { "Entering" } else { "Leaving" }
+        );
+        self.use_token(&jwt_data.claims, TokenUse::Write)?;
+        self.maintenance.store(maintenance, Ordering::Relaxed);
+        if let Some(ref mut message) = request.response {
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    fn check_not_in_maintenance(&self) -> Result<(), HandlingError> {
+        if self.maintenance.load(Ordering::Relaxed) {
+            println!("Refusing write, device is in maintenance mode");
+            return Err(rejection(
+                ResponseType::ServiceUnavailable,
+                ErrorReason::Unavailable,
+                "Device is in maintenance mode",
+            ));
+        }
+        Ok(())
+    }
+
+    // Needs a token allowing writes to "_profile". Sets the parameters the profile has values
+    // for, all or nothing as PUT /_group does, and leaves the others as they are.
+    fn switch_profile(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling PUT /_profile");
+        self.check_not_in_maintenance()?;
+
+        let payload: SetParamPayload = parse_payload(request, "PUT /_profile")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Write)?;
+
+        if !jwt_data.claims.params_write.iter().any(|p| p == "_profile") {
+            println!("Validation error: Token does not have permission to switch profiles");
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission to switch profiles",
+            ));
+        }
+
+        let name = self.put_value(payload.value, payload.encrypted_value, "_profile")?;
+        let Some(profile) = self.profiles.get(&name) else {
+            return Err(rejection(
+                ResponseType::NotFound,
+                ErrorReason::NotFound,
+                format!("No profile named {name}"),
+            ));
+        };
+
+        println!("Switching to profile {name}");
+        self.apply_values(&jwt_data.claims, profile.clone())?;
+        if let Some(ref mut message) = request.response {
+            message.message.payload.clear();
+        }
+        Ok(())
+    }
+
+    fn list_parameters(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling GET /params");
+
+        let parameters = self.parameters.read().unwrap();
+        let params: Vec<_> = parameters
+            .configs
+            .iter()
+            .map(|(name, config)| ParamDescription {
+                name,
+                description: config.description.as_deref(),
+                ty: config.ty,
+            })
+            .collect();
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload = serde_json::to_vec(&params).unwrap();
+        }
+        Ok(())
+    }
+
+    fn get_parameter_meta(
+        &self,
+        request: &mut CoapRequest<SocketAddr>,
+        parameter: &str,
+    ) -> Result<(), HandlingError> {
+        println!("Handling GET /{}/meta", parameter);
+
+        let payload: GetParamPayload = parse_payload(request, "GET /meta")?;
+        let jwt_data = self.decode_token(&payload.token, TokenUse::Read)?;
+
+        if !jwt_data.claims.params_read.iter().any(|p| p == parameter) {
+            println!(
+                "Validation error: Token does not have permission to access parameter {parameter}"
+            );
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::Forbidden,
+                "No permission for parameter",
+            ));
+        }
+
+        if !self.values.lock().unwrap().contains_key(parameter) {
+            return Err(self.missing_value(parameter));
+        }
+
+        let meta = self
+            .param_meta
+            .lock()
+            .unwrap()
+            .get(parameter)
+            .cloned()
+            .ok_or_else(|| {
+                rejection(
+                    ResponseType::NotFound,
+                    ErrorReason::NotFound,
+                    "Parameter has not been written since startup",
+                )
+            })?;
+        self.use_token(&jwt_data.claims, TokenUse::Read)?;
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload = serde_json::to_vec(&meta).unwrap();
+        }
+        Ok(())
+    }
+
+    // Runs the same checks as GET/PUT would for the given parameters, but only reports the
+    // outcome instead of touching any parameter values.
+    fn validate_token(&self, request: &mut CoapRequest<SocketAddr>) -> Result<(), HandlingError> {
+        println!("Handling POST /validateToken");
+
+        let payload: ValidateTokenPayload = parse_payload(request, "POST /validateToken")?;
+
+        let result = match self.decode_token(&payload.token, TokenUse::Check) {
+            Ok(jwt_data) => {
+                let (allowed_read, forbidden_read) = payload
+                    .params_read
+                    .into_iter()
+                    .partition(|param| jwt_data.claims.params_read.contains(param));
+                let (allowed_write, forbidden_write) = payload
+                    .params_write
+                    .into_iter()
+                    .partition(|param| jwt_data.claims.params_write.contains(param));
+
+                ValidateTokenResponse {
+                    valid: true,
+                    error: None,
+                    allowed_read,
+                    forbidden_read,
+                    allowed_write,
+                    forbidden_write,
+                }
+            }
+            Err(e) => ValidateTokenResponse {
+                valid: false,
+                error: Some(e.message),
+                forbidden_read: payload.params_read,
+                forbidden_write: payload.params_write,
+                ..Default::default()
+            },
+        };
+
+        if let Some(ref mut message) = request.response {
+            message.message.payload = serde_json::to_vec(&result).unwrap();
+        }
+        Ok(())
+    }
+
+    fn audit(&self, event: AuditEvent) {
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.record(event);
+        }
+    }
+
+    fn decode_token(
+        &self,
+        token: &str,
+        token_use: TokenUse,
+    ) -> Result<TokenData<JwtClaims>, HandlingError> {
+        let _span = info_span!("validate_token").entered();
+        // Bound the base64 and JSON work an oversized token can cause
+        if token.len() > self.max_token_length {
+            println!(
+                "Validation error: Token is {} bytes, maximum is {}",
+                token.len(),
+                self.max_token_length
+            );
+            return Err(rejection(
+                ResponseType::BadRequest,
+                ErrorReason::InvalidToken,
+                "Token too long",
+            ));
+        }
+
+        let jwt_data = decode_jwt(
+            token,
+            &self.jwt_decoder,
+            &self.audience,
+            self.issuer.as_deref(),
+            self.require_token_exp,
+            self.max_token_age_secs,
+        )
+        .map_err(|e| {
+            println!("Error decoding control token: {e}");
+            match e
+                .downcast_ref::<jsonwebtoken::errors::Error>()
+                .map(jsonwebtoken::errors::Error::kind)
+            {
+                // 4.01 tells the controller to get a fresh token and try again
+                Some(ErrorKind::ExpiredSignature) => rejection(
+                    ResponseType::Unauthorized,
+                    ErrorReason::TokenExpired,
+                    format!("Couldn't decode JWT: {e}"),
+                ),
+                Some(ErrorKind::InvalidIssuer) => rejection(
+                    ResponseType::BadRequest,
+                    ErrorReason::InvalidToken,
+                    format!(
+                        "Token was not issued by arbiter {}",
+                        self.issuer.as_deref().unwrap_or_default()
+                    ),
+                ),
+                _ => rejection(
+                    ResponseType::BadRequest,
+                    ErrorReason::InvalidToken,
+                    format!("Couldn't decode JWT: {e}"),
+                ),
+            }
+        })?;
+
+        println!(
+            "Received token: {}",
+            serde_json::to_string_pretty(&jwt_data.claims).unwrap()
+        );
+
+        // Belt and braces on top of the audience validation in decode_jwt(): a token scoped to
+        // another device must never be accepted here, whatever parameters it lists. In strict
+        // mode, neither is a token that also names other devices.
+        if !jwt_data
+            .claims
+            .aud
+            .allows(&self.audience, self.strict_audience)
+        {
+            println!(
+                "Validation error: Token audience {} is not this device",
+                jwt_data.claims.aud
+            );
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::InvalidToken,
+                "Token is not scoped to this device",
+            ));
+        }
+
+        if self
+            .revoked_tokens
+            .read()
+            .unwrap()
+            .contains(&jwt_data.claims.jti)
+        {
+            println!(
+                "Validation error: Token {} was revoked",
+                jwt_data.claims.jti
+            );
+            return Err(rejection(
+                ResponseType::Forbidden,
+                ErrorReason::TokenRevoked,
+                "Token has been revoked",
+            ));
+        }
+
+        if let Some(ref allowed_controllers) = self.allowed_controllers {
+            if !allowed_controllers.contains(&jwt_data.claims.sub) {
+                println!(
+                    "Validation error: Controller {} is not in the allowlist",
+                    jwt_data.claims.sub
+                );
+                return Err(rejection(
+                    ResponseType::Forbidden,
+                    ErrorReason::InvalidToken,
+                    "Controller not allowed",
+                ));
+            }
+        }
+
+        // Only checked here, so that a token is refused before any work is done with it. It is
+        // used up by use_token() once the request has gone through.
+        if let Some(ref seen_tokens) = self.seen_tokens {
+            if self.is_single_use(token_use)
+                && seen_tokens.lock().unwrap().contains(&jwt_data.claims.jti)
+            {
+                return Err(token_replayed(&jwt_data.claims.jti));
+            }
+        }
+
+        Ok(jwt_data)
+    }
+
+    fn is_single_use(&self, token_use: TokenUse) -> bool {
+        match token_use {
+            TokenUse::Read => self.replay_protect_reads,
+            TokenUse::Write => true,
+            TokenUse::Check => false,
+        }
+    }
+
+    // Marks a single-use token as used, just before the request it came with takes effect. Fails
+    // if another request used it meanwhile.
+    fn use_token(&self, claims: &JwtClaims, token_use: TokenUse) -> Result<(), HandlingError> {
+        let Some(ref seen_tokens) = self.seen_tokens else {
+            return Ok(());
+        };
+        if self.is_single_use(token_use) && !seen_tokens.lock().unwrap().insert(claims.jti) {
+            return Err(token_replayed(&claims.jti));
+        }
+        Ok(())
+    }
+}
+
+impl coap::server::RequestHandler for RequestHandler {
+    fn handle_request<'life0, 'async_trait>(
+        &'life0 self,
+        mut request: Box<CoapRequest<SocketAddr>>,
+    ) -> core::pin::Pin<
+        Box<
+            dyn core::future::Future<Output = Box<CoapRequest<SocketAddr>>>
+                + core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        // Groups the traces of handling this request, so they can be told apart from other requests
+        let span = info_span!(
+            "request",
+            method = ?request.get_method(),
+            path = %request.get_path(),
+            peer = request.source.map(display),
+        );
+        let handling = async {
+            if self.deduplicate(&mut request) {
+                return request;
+            }
+
+            let delay = self.simulated_delay();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            let path = request.get_path();
+            let result = match (*request.get_method(), path.as_str()) {
+                (Method::Post, "validateToken") => self.validate_token(&mut request),
+                (Method::Get, "params") => self.list_parameters(&mut request),
+                (Method::Post, "params") => self.create_parameter(&mut request),
+                (Method::Post, "batchGet") => self.batch_get(&mut request),
+                (Method::Post, "batchSet") => self.batch_set(&mut request),
+                (Method::Post, "_reload") => self.reload_parameters(&mut request),
+                (Method::Put, "_maintenance") => self.set_maintenance(&mut request),
+                (Method::Put, "_profile") => self.switch_profile(&mut request),
+                (Method::Put, "_group") => self.set_group(&mut request),
+                (Method::Get, _) if path.ends_with("/meta") => {
+                    let parameter = path.trim_end_matches("/meta").to_string();
+                    self.get_parameter_meta(&mut request, &parameter)
+                }
+                (Method::Get, _) => self.get_parameter(&mut request, path),
+                (Method::Put, _) => self.set_parameter(&mut request, path),
+                (method, _) => {
+                    println!("Received unhandled method {:?}", method);
+                    Ok(())
+                }
+            };
+
+            if let Err(e) = result {
+                request.apply_from_error(e);
+                if let Some(ref mut response) = request.response {
+                    response
+                        .message
+                        .set_content_format(ContentFormat::ApplicationJSON);
+                }
+            }
+
+            if self.dedup_window.is_some() {
+                self.remember_response(&request);
+            }
+            request
+        };
+        Box::pin(handling.instrument(span))
+    }
+}
+
+// The parameters' initial values, overridden by the default profile's
+fn initial_values(config: &Config) -> HashMap<String, String> {
+    let mut values: HashMap<_, _> = config
+        .parameters
+        .iter()
+        .filter_map(|(name, config)| Some((name.clone(), config.initial_value.clone()?)))
+        .collect();
+    if let Some(profile) = config
+        .default_profile
+        .as_ref()
+        .and_then(|profile| config.profiles.get(profile))
+    {
+        values.extend(profile.clone());
+    }
+    values
+}
+
+fn accepts_json(request: &CoapRequest<SocketAddr>) -> bool {
+    let json = usize::from(ContentFormat::ApplicationJSON) as u16;
+    matches!(
+        request
+            .message
+            .get_first_option_as::<OptionValueU16>(CoapOption::Accept),
+        Some(Ok(OptionValueU16(format))) if format == json
+    )
+}
+
+// Values that don't parse as their declared type are returned as strings
+fn typed_value(value: &str, ty: ParameterType) -> serde_json::Value {
+    let parsed = match ty {
+        ParameterType::Int => value.parse::<i64>().ok().map(Into::into),
+        ParameterType::Float => value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Into::into),
+        ParameterType::Bool => value.parse::<bool>().ok().map(Into::into),
+        ParameterType::String => None,
+    };
+    parsed.unwrap_or_else(|| value.into())
+}
+
+// Parameter definitions along with their compiled patterns
+struct Parameters {
+    configs: BTreeMap<String, ParameterConfig>,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Parameters {
+    fn new(configs: BTreeMap<String, ParameterConfig>) -> Result<Self, String> {
+        let patterns = configs
+            .iter()
+            .filter_map(|(name, config)| {
+                let pattern = config.pattern.as_ref()?;
+                Some(
+                    compile_pattern(pattern)
+                        .map(|regex| (name.clone(), regex))
+                        .map_err(|e| format!("Invalid pattern for parameter {name}: {e}")),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { configs, patterns })
+    }
+
+    fn check_value(
+        &self,
+        values: &HashMap<String, String>,
+        parameter: &str,
+        value: &str,
+    ) -> Result<(), HandlingError> {
+        check_constraints(
+            self.configs.get(parameter),
+            self.patterns.get(parameter),
+            values,
+            parameter,
+            value,
+        )
+    }
+}
+
+// Checks a value against the parameter's pattern, and its requirements against the given values
+fn check_constraints(
+    config: Option<&ParameterConfig>,
+    pattern: Option<&Regex>,
+    values: &HashMap<String, String>,
+    parameter: &str,
+    value: &str,
+) -> Result<(), HandlingError> {
+    if let Some(pattern) = pattern {
+        if !pattern.is_match(value) {
+            println!("Validation error: Value {value} doesn't match the pattern for {parameter}");
+            return Err(rejection(
+                ResponseType::BadRequest,
+                ErrorReason::ConstraintViolation,
+                format!("Value doesn't match the pattern for parameter {parameter}"),
+            ));
+        }
+    }
+
+    if let Some(config) = config {
+        for (prerequisite, required) in &config.requires {
+            if values.get(prerequisite) != Some(required) {
+                println!("Validation error: {parameter} requires {prerequisite} to be {required}");
+                return Err(rejection(
+                    ResponseType::BadRequest,
+                    ErrorReason::ConstraintViolation,
+                    format!(
+                        "Parameter {parameter} can only be set while {prerequisite} is {required}"
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn token_replayed(jti: &Uuid) -> HandlingError {
+    println!("Validation error: Token {jti} has already been used");
+    rejection(
+        ResponseType::Forbidden,
+        ErrorReason::TokenReplayed,
+        "Token has already been used",
+    )
+}
+
+fn check_write_permission(claims: &JwtClaims, parameter: &str) -> Result<(), HandlingError> {
+    if !claims.params_write.iter().any(|p| p == parameter) {
+        println!("Validation error: Token does not have permission to write parameter {parameter}");
+        return Err(rejection(
+            ResponseType::Forbidden,
+            ErrorReason::Forbidden,
+            format!("No permission for parameter {parameter}"),
+        ));
+    }
+    Ok(())
+}
+
+// Patterns must match the whole value
+fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})$"))
+}
+
+fn parse_payload<T: DeserializeOwned>(
+    request: &CoapRequest<SocketAddr>,
+    description: &str,
+) -> Result<T, HandlingError> {
+    serde_json::from_slice(&request.message.payload).map_err(|e| {
+        rejection(
+            ResponseType::BadRequest,
+            ErrorReason::InvalidRequest,
+            format!("Couldn't parse payload of {description}: {e}"),
+        )
+    })
+}
+
+fn decode_jwt(
+    token: &str,
+    decoder: &DecodingKey,
+    audience: &str,
+    issuer: Option<&str>,
+    require_exp: bool,
+    max_age_secs: Option<u64>,
+) -> anyhow::Result<TokenData<JwtClaims>> {
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.set_audience(&[audience]);
+    // jsonwebtoken skips the audience and issuer checks entirely if the claims are absent, so
+    // require them
+    validation.required_spec_claims = HashSet::from(["aud".to_string()]);
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_string());
+    }
+    if require_exp {
+        validation.required_spec_claims.insert("exp".to_string());
+    }
+
+    let jwt_data = jsonwebtoken::decode::<JwtClaims>(token, decoder, &validation)?;
+
+    // jsonwebtoken doesn't validate iat, so check the token's age ourselves
+    if let Some(max_age_secs) = max_age_secs {
+        let Some(iat) = jwt_data.claims.iat else {
+            anyhow::bail!("Token has no iat claim but a maximum token age is configured");
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let age = now.saturating_sub(iat);
+        if age > max_age_secs {
+            anyhow::bail!("Token was issued {age}s ago, maximum age is {max_age_secs}s");
+        }
+    }
+
+    Ok(jwt_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use coap::server::RequestHandler as _;
+    use coap_lite::CoapResponse;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const DEVICE_CID: &str = "00000000-0000-0000-0000-0000000000d1";
+    const ARBITER_CID: &str = "00000000-0000-0000-0000-0000000000a1";
+
+    fn keys() -> (EncodingKey, DecodingKey) {
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        (
+            EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap(),
+            DecodingKey::from_ec_pem(key_pair.public_key_pem().as_bytes()).unwrap(),
+        )
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // The claims of a token the arbiter would issue to this device
+    fn claims(read: &[&str], write: &[&str]) -> serde_json::Value {
+        json!({
+            "iss": ARBITER_CID,
+            "sub": "controller",
+            "aud": DEVICE_CID,
+            "exp": now() + 60,
+            "iat": now(),
+            "jti": Uuid::new_v4(),
+            "params_read": read,
+            "params_write": write,
+        })
+    }
+
+    fn sign(key: &EncodingKey, claims: &serde_json::Value) -> String {
+        jsonwebtoken::encode(&Header::new(Algorithm::ES256), claims, key).unwrap()
+    }
+
+    // A device with the given config fields on top of the required ones, and the key its tokens
+    // are signed with
+    struct TestDevice {
+        handler: RequestHandler,
+        key: EncodingKey,
+        revoked_tokens: Arc<RwLock<HashSet<Uuid>>>,
+        // What controllers encrypt values to, if valueEncryption is set
+        encryption_key: Option<String>,
+    }
+
+    impl TestDevice {
+        fn new(config: serde_json::Value) -> Self {
+            let mut fields = json!({
+                "cid": DEVICE_CID,
+                "label": "test",
+                "manufacturer": "ACME",
+                "model": "Test",
+                "arbiterCid": ARBITER_CID,
+            });
+            fields
+                .as_object_mut()
+                .unwrap()
+                .extend(config.as_object().unwrap().clone());
+            let config: Config = serde_json::from_value(fields).unwrap();
+
+            let (key, decoding_key) = keys();
+            let revoked_tokens = Arc::new(RwLock::new(HashSet::new()));
+            let audit_log = config
+                .audit_file
+                .as_ref()
+                .map(|file| AuditLog::open(file).unwrap());
+            let value_key = config.value_encryption.then(ValueKey::generate);
+            let encryption_key = value_key.as_ref().map(ValueKey::public_key);
+            TestDevice {
+                handler: RequestHandler::new(
+                    decoding_key,
+                    audit_log,
+                    value_key,
+                    revoked_tokens.clone(),
+                    &config,
+                )
+                .unwrap(),
+                key,
+                revoked_tokens,
+                encryption_key,
+            }
+        }
+
+        fn token(&self, read: &[&str], write: &[&str]) -> String {
+            sign(&self.key, &claims(read, write))
+        }
+
+        // A value for `parameter` encrypted the way the controller does it
+        fn encrypt(&self, parameter: &str, value: &str) -> serde_json::Value {
+            let encrypted = crate::controller_encryption::encrypt_value(
+                self.encryption_key.as_ref().unwrap(),
+                &DEVICE_CID.parse().unwrap(),
+                parameter,
+                value,
+            )
+            .unwrap();
+            serde_json::to_value(encrypted).unwrap()
+        }
+
+        // The body of a GET with a token allowing it
+        async fn get(&self, parameter: &str) -> serde_json::Value {
+            let token = self.token(&[parameter], &[]);
+            self.send(Method::Get, parameter, json!({ "token": token }))
+                .await
+                .1
+        }
+
+        async fn send(
+            &self,
+            method: Method,
+            path: &str,
+            payload: serde_json::Value,
+        ) -> (ResponseType, serde_json::Value) {
+            let mut request = CoapRequest::new();
+            request.set_method(method);
+            request.set_path(path);
+            request.message.payload = serde_json::to_vec(&payload).unwrap();
+            self.send_request(request).await
+        }
+
+        async fn send_request(
+            &self,
+            mut request: CoapRequest<SocketAddr>,
+        ) -> (ResponseType, serde_json::Value) {
+            request.response = CoapResponse::new(&request.message);
+            request.source = Some(SocketAddr::from(([127, 0, 0, 1], 5683)));
+
+            let response = self
+                .handler
+                .handle_request(Box::new(request))
+                .await
+                .response
+                .unwrap();
+            let MessageClass::Response(code) = response.message.header.code else {
+                panic!("Not a response: {}", response.message.header.code);
+            };
+            let payload = &response.message.payload;
+            let body = serde_json::from_slice(payload)
+                .unwrap_or_else(|_| String::from_utf8_lossy(payload).into());
+            (code, body)
+        }
+    }
+
+    #[test]
+    fn token_without_exp_is_rejected_when_required() {
+        let (encoding_key, decoding_key) = keys();
+        let mut claims = claims(&["temp"], &[]);
+        claims.as_object_mut().unwrap().remove("exp");
+        let token = sign(&encoding_key, &claims);
+
+        let e = decode_jwt(
+            &token,
+            &decoding_key,
+            DEVICE_CID,
+            Some(ARBITER_CID),
+            true,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            e.downcast_ref::<jsonwebtoken::errors::Error>()
+                .map(jsonwebtoken::errors::Error::kind),
+            Some(ErrorKind::MissingRequiredClaim(claim)) if claim == "exp"
+        ));
+        assert!(decode_jwt(
+            &token,
+            &decoding_key,
+            DEVICE_CID,
+            Some(ARBITER_CID),
+            false,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let (encoding_key, decoding_key) = keys();
+        let mut claims = claims(&["temp"], &[]);
+        claims["exp"] = json!(now() - 3600);
+        let token = sign(&encoding_key, &claims);
+
+        let e = decode_jwt(
+            &token,
+            &decoding_key,
+            DEVICE_CID,
+            Some(ARBITER_CID),
+            false,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            e.downcast_ref::<jsonwebtoken::errors::Error>()
+                .map(jsonwebtoken::errors::Error::kind),
+            Some(ErrorKind::ExpiredSignature)
+        ));
+    }
+
+    #[tokio::test]
+    async fn revoked_token_is_rejected() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+        let claims = claims(&["temp"], &[]);
+        let token = sign(&device.key, &claims);
+
+        let (code, _) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Content);
+
+        let jti = claims["jti"].as_str().unwrap().parse().unwrap();
+        device.revoked_tokens.write().unwrap().insert(jti);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(body["reason"], "token_revoked");
+    }
+
+    #[tokio::test]
+    async fn token_from_another_issuer_is_rejected() {
+        let mut claims = claims(&["temp"], &[]);
+        claims["iss"] = json!("00000000-0000-0000-0000-0000000000a2");
+        let config = json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        });
+
+        let device = TestDevice::new(config.clone());
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "invalid_token");
+        assert_eq!(
+            body["message"],
+            format!("Token was not issued by arbiter {ARBITER_CID}")
+        );
+
+        // Without an arbiterCid any issuer goes
+        let mut config = config;
+        config["arbiterCid"] = serde_json::Value::Null;
+        let device = TestDevice::new(config);
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(body, json!(20));
+    }
+
+    #[tokio::test]
+    async fn profile_is_validated_and_merged_into_the_current_values() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "mode": { "pattern": "auto|manual", "initialValue": "auto" },
+                "temp": {
+                    "pattern": "[0-9]+",
+                    "initialValue": "20",
+                    "requires": { "mode": "manual" },
+                },
+                "name": { "initialValue": "lobby" },
+            },
+            "profiles": {
+                "manual": { "mode": "manual", "temp": "18" },
+                "broken": { "temp": "hot" },
+                "needsManual": { "mode": "auto", "temp": "25" },
+            },
+        }));
+        let switch =
+            |profile: &str| json!({ "token": device.token(&[], &["_profile"]), "value": profile });
+
+        let (code, _) = device.send(Method::Put, "_profile", switch("manual")).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(device.get("mode").await, "manual");
+        assert_eq!(device.get("temp").await, json!(18));
+        assert_eq!(device.get("name").await, "lobby");
+        let token = device.token(&["temp"], &[]);
+        let (_, meta) = device
+            .send(Method::Get, "temp/meta", json!({ "token": token }))
+            .await;
+        assert_eq!(meta["modifiedBy"], "controller");
+
+        for profile in ["broken", "needsManual"] {
+            let (code, body) = device.send(Method::Put, "_profile", switch(profile)).await;
+            assert_eq!(code, ResponseType::BadRequest);
+            assert_eq!(body["reason"], "constraint_violation");
+            assert_eq!(device.get("mode").await, "manual");
+            assert_eq!(device.get("temp").await, json!(18));
+        }
+    }
+
+    #[tokio::test]
+    async fn token_is_used_up_by_the_first_write_that_goes_through() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "temp": { "pattern": "[0-9]+", "initialValue": "20" },
+                "mode": {},
+            },
+            "replayCacheSize": 16,
+        }));
+        let token = device.token(&["temp"], &["temp", "mode"]);
+
+        // A refused write leaves the token usable
+        let (code, body) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "hot" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "constraint_violation");
+
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "21" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+
+        let (code, body) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "22" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(body["reason"], "token_replayed");
+        assert_eq!(device.get("temp").await, json!(21));
+
+        // Reads aren't protected unless replayProtectReads is set
+        let (code, _) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Content);
+    }
+
+    #[tokio::test]
+    async fn batch_uses_its_token_once_for_all_its_writes() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "pattern": "[0-9]+" }, "mode": {} },
+            "replayCacheSize": 16,
+        }));
+        let payload = json!({
+            "token": device.token(&[], &["temp", "mode"]),
+            "values": { "mode": "manual", "temp": "18" },
+        });
+
+        let (code, body) = device.send(Method::Post, "batchSet", payload.clone()).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(
+            body,
+            json!({ "mode": { "value": "manual" }, "temp": { "value": "18" } })
+        );
+
+        let (code, body) = device.send(Method::Post, "batchSet", payload).await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(body["reason"], "token_replayed");
+    }
+
+    #[tokio::test]
+    async fn created_parameter_needs_a_value_matching_its_pattern() {
+        let device = TestDevice::new(json!({}));
+        let create = |value: serde_json::Value| {
+            json!({
+                "token": device.token(&[], &["_params"]),
+                "name": "speed",
+                "pattern": "[0-9]+",
+                "initialValue": value,
+            })
+        };
+
+        let (code, body) = device
+            .send(Method::Post, "params", create(json!("fast")))
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "constraint_violation");
+        let (_, params) = device.send(Method::Get, "params", json!({})).await;
+        assert_eq!(params, json!([]));
+
+        let (code, _) = device
+            .send(Method::Post, "params", create(json!("3")))
+            .await;
+        assert_eq!(code, ResponseType::Created);
+        assert_eq!(device.get("speed").await, json!(3));
+    }
+
+    #[tokio::test]
+    async fn writes_and_reloads_are_refused_in_maintenance_mode() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+        let token = device.token(&[], &["_maintenance"]);
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "_maintenance",
+                json!({ "token": token, "value": "true" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+
+        let token = device.token(&[], &["temp", "_reload"]);
+        for (method, path, payload) in [
+            (
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "21" }),
+            ),
+            (Method::Post, "_reload", json!({ "token": token })),
+        ] {
+            let (code, body) = device.send(method, path, payload).await;
+            assert_eq!(code, ResponseType::ServiceUnavailable);
+            assert_eq!(body["reason"], "unavailable");
+        }
+        // Reads carry on
+        assert_eq!(device.get("temp").await, json!(20));
+    }
+
+    #[test]
+    fn invalid_parameter_pattern_fails_startup() {
+        let config: Config = serde_json::from_value(json!({
+            "cid": DEVICE_CID,
+            "label": "test",
+            "manufacturer": "ACME",
+            "model": "Test",
+            "parameters": { "temp": { "pattern": "[0-9" } },
+        }))
+        .unwrap();
+        let (_, decoding_key) = keys();
+        let revoked_tokens = Arc::new(RwLock::new(HashSet::new()));
+
+        let e = RequestHandler::new(decoding_key, None, None, revoked_tokens, &config)
+            .err()
+            .unwrap();
+        assert!(e
+            .to_string()
+            .starts_with("Invalid pattern for parameter temp"));
+    }
+
+    #[tokio::test]
+    async fn put_value_is_returned_by_the_next_get() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+        assert_eq!(device.get("temp").await, json!(20));
+
+        let token = device.token(&[], &["temp"]);
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "21" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(device.get("temp").await, json!(21));
+
+        let token = device.token(&["speed"], &[]);
+        let (code, _) = device
+            .send(Method::Get, "speed", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::NotFound);
+    }
+
+    #[tokio::test]
+    async fn group_with_one_bad_value_changes_nothing() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "mode": { "pattern": "auto|manual", "initialValue": "auto" },
+                "temp": { "pattern": "[0-9]+", "initialValue": "20" },
+            },
+        }));
+        let token = device.token(&[], &["mode", "temp"]);
+        let set_group = |values: serde_json::Value| json!({ "token": token, "values": values });
+
+        for values in [
+            json!({ "mode": "manual", "temp": "hot" }),
+            json!({ "mode": "manual", "speed": "3" }),
+        ] {
+            let (code, _) = device.send(Method::Put, "_group", set_group(values)).await;
+            assert_ne!(code, ResponseType::Content);
+            assert_eq!(device.get("mode").await, "auto");
+            assert_eq!(device.get("temp").await, json!(20));
+        }
+
+        // The token has to allow writing every parameter in the group
+        let token = device.token(&[], &["mode"]);
+        let values = json!({ "mode": "manual", "temp": "18" });
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "_group",
+                json!({ "token": token, "values": values }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(device.get("mode").await, "auto");
+
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "_group",
+                set_group(json!({ "mode": "manual", "temp": "18" })),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(device.get("mode").await, "manual");
+        assert_eq!(device.get("temp").await, json!(18));
+    }
+
+    #[tokio::test]
+    async fn group_accepts_encrypted_values() {
+        let device = TestDevice::new(json!({
+            "parameters": { "mode": {}, "temp": { "pattern": "[0-9]+" } },
+            "valueEncryption": true,
+            "requireEncryptedValues": true,
+        }));
+        let token = device.token(&[], &["mode", "temp"]);
+        let values = json!({
+            "mode": { "encryptedValue": device.encrypt("mode", "manual") },
+            "temp": { "encryptedValue": device.encrypt("temp", "18") },
+        });
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "_group",
+                json!({ "token": token, "values": values }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(device.get("mode").await, "manual");
+        assert_eq!(device.get("temp").await, json!(18));
+
+        // Plain values are still refused when encryption is required
+        let values = json!({ "mode": { "value": "auto" } });
+        let (code, body) = device
+            .send(
+                Method::Put,
+                "_group",
+                json!({ "token": token, "values": values }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "constraint_violation");
+    }
+
+    #[tokio::test]
+    async fn batch_accepts_encrypted_values() {
+        let device = TestDevice::new(json!({
+            "parameters": { "mode": {}, "temp": {} },
+            "valueEncryption": true,
+            "requireEncryptedValues": true,
+        }));
+        let values = json!({
+            "mode": { "encryptedValue": device.encrypt("mode", "manual") },
+            "temp": "18",
+        });
+        let payload = json!({
+            "token": device.token(&[], &["mode", "temp"]),
+            "values": values,
+        });
+
+        let (code, body) = device.send(Method::Post, "batchSet", payload).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(body["mode"], json!({ "value": "manual" }));
+        assert_eq!(body["temp"]["error"]["reason"], "constraint_violation");
+        assert_eq!(device.get("mode").await, "manual");
+    }
+
+    #[tokio::test]
+    async fn validate_token_reports_parameters_the_token_lacks() {
+        let device = TestDevice::new(json!({}));
+        let payload = json!({
+            "token": device.token(&["temp"], &["mode"]),
+            "paramsRead": ["temp", "mode"],
+            "paramsWrite": ["mode", "temp"],
+        });
+        let (code, body) = device.send(Method::Post, "validateToken", payload).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(
+            body,
+            json!({
+                "valid": true,
+                "allowedRead": ["temp"],
+                "forbiddenRead": ["mode"],
+                "allowedWrite": ["mode"],
+                "forbiddenWrite": ["temp"],
+            })
+        );
+
+        // Nothing is allowed by a token that doesn't validate
+        let payload = json!({ "token": "garbage", "paramsRead": ["temp"] });
+        let (_, body) = device.send(Method::Post, "validateToken", payload).await;
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["forbiddenRead"], json!(["temp"]));
+    }
+
+    #[tokio::test]
+    async fn token_for_another_device_is_rejected() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+        let mut claims = claims(&["temp"], &[]);
+        claims["aud"] = json!("00000000-0000-0000-0000-0000000000d2");
+        let token = sign(&device.key, &claims);
+
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn token_for_several_devices_is_only_accepted_without_strict_audience() {
+        let mut claims = claims(&["temp"], &[]);
+        claims["aud"] = json!([DEVICE_CID, "00000000-0000-0000-0000-0000000000d2"]);
+        let config = json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        });
+
+        let device = TestDevice::new(config.clone());
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(body["reason"], "invalid_token");
+
+        let mut config = config;
+        config["strictAudience"] = json!(false);
+        let device = TestDevice::new(config);
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(body, json!(20));
+    }
+
+    #[tokio::test]
+    async fn only_allowed_controllers_are_accepted() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "allowedControllers": ["controller"],
+        }));
+        let (code, _) = device
+            .send(
+                Method::Get,
+                "temp",
+                json!({ "token": device.token(&["temp"], &[]) }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+
+        let mut claims = claims(&["temp"], &[]);
+        claims["sub"] = json!("intruder");
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+        assert_eq!(body["message"], "Controller not allowed");
+    }
+
+    #[tokio::test]
+    async fn oversized_token_is_rejected_before_decoding() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "maxTokenLength": 64,
+        }));
+        // Not a JWT at all, so the message shows it was never decoded
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": "x".repeat(65) }))
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "invalid_token");
+        assert_eq!(body["message"], "Token too long");
+    }
+
+    #[test]
+    fn token_older_than_the_maximum_age_is_rejected() {
+        let (encoding_key, decoding_key) = keys();
+        let decode = |iat: u64| {
+            let mut claims = claims(&["temp"], &[]);
+            claims["iat"] = json!(iat);
+            let token = sign(&encoding_key, &claims);
+            decode_jwt(
+                &token,
+                &decoding_key,
+                DEVICE_CID,
+                Some(ARBITER_CID),
+                true,
+                Some(300),
+            )
+        };
+
+        assert!(decode(now() - 10).is_ok());
+        let e = decode(now() - 600).err().unwrap();
+        assert!(e.to_string().starts_with("Token was issued"));
+    }
+
+    #[tokio::test]
+    async fn label_audience_token_is_only_accepted_by_the_device_with_that_label() {
+        let mut claims = claims(&["temp"], &[]);
+        claims["aud"] = json!("test");
+        let config = json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "labelAudience": true,
+        });
+
+        let device = TestDevice::new(config.clone());
+        let token = sign(&device.key, &claims);
+        let (code, _) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::Content);
+
+        let mut config = config;
+        config["label"] = json!("other");
+        let device = TestDevice::new(config);
+        let token = sign(&device.key, &claims);
+        let (code, body) = device
+            .send(Method::Get, "temp", json!({ "token": token }))
+            .await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(body["reason"], "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn meta_names_the_last_writer() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+        let read_meta = || async {
+            let token = device.token(&["temp"], &[]);
+            device
+                .send(Method::Get, "temp/meta", json!({ "token": token }))
+                .await
+        };
+        let (code, _) = read_meta().await;
+        assert_eq!(code, ResponseType::NotFound);
+
+        for writer in ["alice", "bob"] {
+            let mut claims = claims(&[], &["temp"]);
+            claims["sub"] = json!(writer);
+            let payload = json!({ "token": sign(&device.key, &claims), "value": "21" });
+            let (code, _) = device.send(Method::Put, "temp", payload).await;
+            assert_eq!(code, ResponseType::Content);
+        }
+        let (code, meta) = read_meta().await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(meta["modifiedBy"], "bob");
+        assert!(meta["modifiedAt"].as_u64().unwrap() >= now() - 5);
+    }
+
+    #[tokio::test]
+    async fn parameter_listing_includes_descriptions() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "temp": { "description": "Target temperature in °C", "type": "int" },
+                "mode": {},
+            },
+        }));
+        let (code, params) = device.send(Method::Get, "params", json!({})).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(
+            params,
+            json!([
+                { "name": "mode" },
+                { "name": "temp", "description": "Target temperature in °C", "type": "int" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn only_authorized_puts_are_audited() {
+        let file = std::env::temp_dir().join(format!("device-audit-{}.log", Uuid::new_v4()));
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "auditFile": file,
+        }));
+
+        let token = device.token(&[], &["temp"]);
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "21" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        let token = device.token(&[], &[]);
+        let (code, _) = device
+            .send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": "22" }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Forbidden);
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["operation"], "put");
+        assert_eq!(records[0]["parameter"], "temp");
+        assert_eq!(records[0]["controller"], "controller");
+        assert_eq!(records[0]["oldValue"], "20");
+        assert_eq!(records[0]["newValue"], "21");
+    }
+
+    #[tokio::test]
+    async fn put_value_must_match_the_parameter_pattern() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "mac": {
+                    "pattern": "([0-9a-f]{2}:){5}[0-9a-f]{2}",
+                    "initialValue": "00:00:00:00:00:00",
+                },
+            },
+        }));
+        let put = |value: &str| {
+            let token = device.token(&[], &["mac"]);
+            device.send(
+                Method::Put,
+                "mac",
+                json!({ "token": token, "value": value }),
+            )
+        };
+
+        assert_eq!(put("02:42:ac:11:00:02").await.0, ResponseType::Content);
+        // The whole value has to match, not just part of it
+        assert_eq!(
+            put("02:42:ac:11:00:02:ff").await.0,
+            ResponseType::BadRequest
+        );
+        assert_eq!(put("not a mac").await.0, ResponseType::BadRequest);
+        assert_eq!(device.get("mac").await, json!("02:42:ac:11:00:02"));
+    }
+
+    #[tokio::test]
+    async fn parameter_can_only_be_written_once_its_prerequisite_is_set() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "mode": { "initialValue": "auto" },
+                "setpoint": { "initialValue": "20", "requires": { "mode": "manual" } },
+            },
+        }));
+        let put = |parameter: &'static str, value: &str| {
+            let token = device.token(&[], &[parameter]);
+            device.send(
+                Method::Put,
+                parameter,
+                json!({ "token": token, "value": value }),
+            )
+        };
+
+        let (code, body) = put("setpoint", "22").await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(
+            body["message"],
+            "Parameter setpoint can only be set while mode is manual"
+        );
+        assert_eq!(device.get("setpoint").await, json!(20));
+
+        assert_eq!(put("mode", "manual").await.0, ResponseType::Content);
+        assert_eq!(put("setpoint", "22").await.0, ResponseType::Content);
+        assert_eq!(device.get("setpoint").await, json!(22));
+    }
+
+    #[tokio::test]
+    async fn response_delays_fall_within_the_jitter_range() {
+        let config = json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "responseDelayMs": 5,
+            "responseJitterMs": 10,
+            "jitterSeed": 7,
+        });
+        let device = TestDevice::new(config.clone());
+        let delays: Vec<_> = (0..1000)
+            .map(|_| device.handler.simulated_delay())
+            .collect();
+        assert!(delays
+            .iter()
+            .all(|delay| (Duration::from_millis(5)..=Duration::from_millis(15)).contains(delay)));
+        assert_eq!(delays.iter().min(), Some(&Duration::from_millis(5)));
+        assert_eq!(delays.iter().max(), Some(&Duration::from_millis(15)));
+
+        // The same seed gives the same delays
+        let again = TestDevice::new(config);
+        assert!(delays
+            .iter()
+            .all(|delay| *delay == again.handler.simulated_delay()));
+
+        let start = Instant::now();
+        device.get("temp").await;
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn typed_value_is_returned_to_requests_that_accept_json() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "speed": { "type": "int", "initialValue": "42" },
+                "serial": { "type": "string", "initialValue": "42" },
+            },
+        }));
+        let get_json = |parameter: &str| {
+            let mut request = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path(parameter);
+            request.message.payload =
+                serde_json::to_vec(&json!({ "token": device.token(&[parameter], &[]) })).unwrap();
+            request.message.add_option(
+                CoapOption::Accept,
+                OptionValueU16(usize::from(ContentFormat::ApplicationJSON) as u16).into(),
+            );
+            device.send_request(request)
+        };
+
+        assert_eq!(
+            get_json("speed").await.1,
+            json!({ "value": 42, "type": "int" })
+        );
+        assert_eq!(
+            get_json("serial").await.1,
+            json!({ "value": "42", "type": "string" })
+        );
+        // Other requests get the bare value
+        assert_eq!(device.get("speed").await, json!(42));
+    }
+
+    #[tokio::test]
+    async fn retransmitted_message_is_only_handled_once() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+            "messageDedupWindowMs": 60_000,
+        }));
+        let put = |message_id: u16, value: &str| {
+            let mut request = CoapRequest::new();
+            request.set_method(Method::Put);
+            request.set_path("temp");
+            request.message.header.message_id = message_id;
+            request.message.payload = serde_json::to_vec(
+                &json!({ "token": device.token(&[], &["temp"]), "value": value }),
+            )
+            .unwrap();
+            device.send_request(request)
+        };
+        let get = |message_id: u16| {
+            let mut request = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path("temp");
+            request.message.header.message_id = message_id;
+            request.message.payload =
+                serde_json::to_vec(&json!({ "token": device.token(&["temp"], &[]) })).unwrap();
+            device.send_request(request)
+        };
+
+        let first = put(7, "21").await;
+        assert_eq!(first.0, ResponseType::Content);
+        // Had it been handled again, this would have set the value to 22
+        assert_eq!(put(7, "22").await, first);
+        assert_eq!(get(8).await.1, json!(21));
+
+        assert_eq!(put(9, "22").await.0, ResponseType::Content);
+        assert_eq!(get(10).await.1, json!(22));
+    }
+
+    #[tokio::test]
+    async fn encrypted_put_value_is_stored_decrypted() {
+        let device = TestDevice::new(json!({
+            "parameters": { "setpoint": { "initialValue": "20" } },
+            "valueEncryption": true,
+        }));
+        let token = device.token(&[], &["setpoint"]);
+        let put = |encrypted_value: serde_json::Value| {
+            device.send(
+                Method::Put,
+                "setpoint",
+                json!({ "token": token, "encryptedValue": encrypted_value }),
+            )
+        };
+
+        let (code, _) = put(device.encrypt("setpoint", "22")).await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(device.get("setpoint").await, json!(22));
+
+        // Encrypted for another parameter, so it can't be replayed here
+        let (code, _) = put(device.encrypt("mode", "23")).await;
+        assert_eq!(code, ResponseType::BadRequest);
+        assert_eq!(device.get("setpoint").await, json!(22));
+    }
+
+    #[tokio::test]
+    async fn error_bodies_say_why_the_request_failed() {
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "pattern": "[0-9]+", "initialValue": "20" } },
+        }));
+        let put = |token: String, value: &str| {
+            device.send(
+                Method::Put,
+                "temp",
+                json!({ "token": token, "value": value }),
+            )
+        };
+        let reason = |(code, body): (ResponseType, serde_json::Value)| {
+            assert!(body["message"].is_string());
+            (code, body["reason"].as_str().unwrap().to_string())
+        };
+
+        // Read-only token
+        let response = put(device.token(&["temp"], &[]), "21").await;
+        assert_eq!(
+            reason(response),
+            (ResponseType::Forbidden, "forbidden".to_string())
+        );
+        let response = put(device.token(&[], &["temp"]), "warm").await;
+        assert_eq!(
+            reason(response),
+            (ResponseType::BadRequest, "constraint_violation".to_string())
+        );
+        let response = put("not.a.token".to_string(), "21").await;
+        assert_eq!(
+            reason(response),
+            (ResponseType::BadRequest, "invalid_token".to_string())
+        );
+        let token = device.token(&["speed"], &[]);
+        let response = device
+            .send(Method::Get, "speed", json!({ "token": token }))
+            .await;
+        assert_eq!(
+            reason(response),
+            (ResponseType::NotFound, "unknown_parameter".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_reports_forbidden_parameters_without_failing_the_rest() {
+        let device = TestDevice::new(json!({
+            "parameters": {
+                "temp": { "initialValue": "20" },
+                "mode": { "initialValue": "auto" },
+            },
+        }));
+
+        let token = device.token(&["temp"], &[]);
+        let (code, body) = device
+            .send(
+                Method::Post,
+                "batchGet",
+                json!({ "token": token, "params": ["temp", "mode"] }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(body["temp"], json!({ "value": "20" }));
+        assert_eq!(body["mode"]["error"]["reason"], "forbidden");
+
+        let token = device.token(&[], &["mode"]);
+        let (code, body) = device
+            .send(
+                Method::Post,
+                "batchSet",
+                json!({ "token": token, "values": { "temp": "25", "mode": "manual" } }),
+            )
+            .await;
+        assert_eq!(code, ResponseType::Content);
+        assert_eq!(body["mode"], json!({ "value": "manual" }));
+        assert_eq!(body["temp"]["error"]["reason"], "forbidden");
+        assert_eq!(device.get("temp").await, json!(20));
+        assert_eq!(device.get("mode").await, "manual");
+    }
+
+    // Records the name and fields of each span created while it is the default subscriber
+    #[derive(Default)]
+    struct SpanRecorder(Mutex<Vec<(&'static str, HashMap<&'static str, String>)>>);
+
+    struct SpanFields<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl tracing::field::Visit for SpanFields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            span.record(&mut SpanFields(&mut fields));
+            let mut spans = self.0.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn request_is_handled_in_a_span_with_its_method_path_and_peer() {
+        let recorder = Arc::new(SpanRecorder::default());
+        let _subscriber = tracing::subscriber::set_default(recorder.clone());
+        let device = TestDevice::new(json!({
+            "parameters": { "temp": { "initialValue": "20" } },
+        }));
+
+        assert_eq!(device.get("temp").await, json!(20));
+        let spans = recorder.0.lock().unwrap();
+        let expected = HashMap::from([
+            ("method", "Get".to_string()),
+            ("path", "temp".to_string()),
+            ("peer", "127.0.0.1:5683".to_string()),
+        ]);
+        assert_eq!(
+            *spans,
+            [("request", expected), ("validate_token", HashMap::new())]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+// Overrides top-level config fields from environment variables named after the field with the
+// given prefix, e.g. `{prefix}LOG_LEVEL` for `logLevel`. Values are parsed as JSON where
+// possible and taken as plain strings otherwise, so a string that looks like a number needs
+// quoting.
+pub fn apply_env_overrides(config: &mut serde_json::Value, prefix: &str) {
+    apply_overrides(config, prefix, std::env::vars());
+}
+
+fn apply_overrides(
+    config: &mut serde_json::Value,
+    prefix: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) {
+    let Some(fields) = config.as_object_mut() else {
+        return;
+    };
+    for (name, value) in vars {
+        let Some(field) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+        fields.insert(env_name_to_field(field), value);
+    }
+}
+
+fn env_name_to_field(name: &str) -> String {
+    let mut field = String::new();
+    for (index, word) in name.split('_').filter(|word| !word.is_empty()).enumerate() {
+        let word = word.to_lowercase();
+        if index == 0 {
+            field.push_str(&word);
+        } else {
+            let mut chars = word.chars();
+            if let Some(first) = chars.next() {
+                field.extend(first.to_uppercase());
+                field.push_str(chars.as_str());
+            }
+        }
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn env_names_become_camel_case_fields() {
+        assert_eq!(env_name_to_field("LOG_LEVEL"), "logLevel");
+        assert_eq!(env_name_to_field("CID"), "cid");
+        assert_eq!(env_name_to_field("MAX_TOKEN_AGE_SECS"), "maxTokenAgeSecs");
+        assert_eq!(env_name_to_field("_LISTEN__PORT_"), "listenPort");
+    }
+
+    #[test]
+    fn values_are_parsed_as_json_or_taken_as_strings() {
+        let mut config = json!({ "label": "dev1", "listenPort": 0 });
+        let vars = [
+            ("DEVICE_LISTEN_PORT", "47111"),
+            ("DEVICE_LABEL", "lobby"),
+            ("DEVICE_TAGS", r#"["hvac"]"#),
+            ("DEVICE_MODEL", r#""1234""#),
+            ("ARBITER_CID", "ignored"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        apply_overrides(&mut config, "DEVICE_", vars);
+        assert_eq!(
+            config,
+            json!({
+                "label": "lobby",
+                "listenPort": 47111,
+                "tags": ["hvac"],
+                "model": "1234",
+            })
+        );
+    }
+}